@@ -8,6 +8,10 @@ pub const BOOT_SA1ROM: Mapper = Mapper::Sa1rom([0, 1, 2, 3]);
 pub const BOOT_SDDROM: Mapper = Mapper::Sddrom([0, 1, 2, 3]);
 
 impl Mapper {
+    /// Where region `n`'s banks start.
+    /// SA-1 and SDD-1 ROMs are split into 1 MiB segments, and each of the
+    /// mapper's four slots says which segment is in one of these regions.
+    /// For the SDD-1 the slots are the bank registers at $4804-$4807.
     pub fn region_base(&self, n: u8) -> u32 {
         assert!(n < 4, "called region_base with a too-high region number");
         match *self {
@@ -15,6 +19,32 @@ impl Mapper {
             _ => panic!("called region_base on a mapper without regions"),
         }
     }
+
+    /// How much ROM is in one bank, as code usually sees it.
+    /// Data that's read with 16-bit pointers can't cross one of these.
+    /// The SA-1 and SDD-1 also have 64 KiB banks at C0-FF,
+    /// but not for everything, so the smaller ones are the ones that count.
+    pub fn bank_size(&self) -> usize {
+        match *self {
+            Hirom | Exhirom => 0x1_0000,
+            Lorom | Exlorom | Sfxrom | Sa1rom(_) | Sddrom(_) => 0x8000,
+        }
+    }
+}
+
+// The SA-1 also shows each slot's segment LoROM-style, 32 banks at a time.
+// The first two slots are in banks 00-3F and the last two in 80-BF.
+const SA1_LO_BANKS: [u32; 4] = [0x00, 0x20, 0x80, 0xa0];
+
+/// Which slot an SA-1 bank shows, if it shows ROM at all.
+fn sa1_slot(ofs: usize) -> Option<usize> {
+    let bank = ofs >> 16;
+    match bank {
+        0xc0 ..= 0xff => Some((bank - 0xc0) >> 4),
+        0x40 ..= 0x7f => None,
+        _ if ofs & 0x00_8000 == 0 => None,
+        _ => Some((bank & 0x80) >> 6 | (bank & 0x20) >> 5),
+    }
 }
 
 use address::Mapper::*;
@@ -38,31 +68,56 @@ impl Address {
     }
 
     pub fn new_from_snes(ofs: usize, map: Mapper) -> Option<Address> {
-        if ofs >= 0x7e_0000 && ofs < 0x80_0000 {
+        if (0x7e_0000 .. 0x80_0000).contains(&ofs) {
             return None;
         };
         let in_range = match map {
-            Lorom | Exlorom => ofs & 0x00_8000 != 0 && !(ofs >= 0x70_0000 && ofs < 0x80_0000),
+            Lorom => ofs & 0x00_8000 != 0 && !(0x70_0000 .. 0x80_0000).contains(&ofs),
+            // the SRAM in 70-7D is only in the lower halves
+            Exlorom => ofs & 0x00_8000 != 0,
             Hirom | Exhirom => ofs & 0x40_0000 != 0 || ofs & 0x00_8000 != 0,
-            Sfxrom => unimplemented!(),
-            Sa1rom(_) => unimplemented!(),
-                //ofs >= 0xc0_0000 || ofs & 0x00_8000 != 0 && !(ofs >= 0x40_0000 && ofs < 0x80_0000),
-            Sddrom(_) => unimplemented!(),
+            // banks 70-71 are the GSU's RAM, and the rest of 60-7F isn't anything
+            Sfxrom => match ofs >> 16 {
+                0x40 ..= 0x5f | 0xc0 ..= 0xdf => true,
+                0x00 ..= 0x3f | 0x80 ..= 0xbf => ofs & 0x00_8000 != 0,
+                _ => false,
+            },
+            Sa1rom(_) => ofs < 0x100_0000 && sa1_slot(ofs).is_some(),
+            Sddrom(_) => match ofs >> 16 {
+                0xc0 ..= 0xff => true,
+                0x00 ..= 0x3f | 0x80 ..= 0xbf => ofs & 0x00_8000 != 0,
+                _ => false,
+            },
         };
         if !in_range {
             return None;
         };
-        Some(
-            Address {
-                map,
-                pc: match map {
-                    Lorom => (ofs & 0x7f_0000) >> 1 | ofs & 0x00_7fff,
-                    Exlorom => (ofs & 0xff_0000) >> 1 | ofs & 0x00_7fff,
-                    Hirom => ofs & 0x3f_ffff,
-                    Exhirom => (ofs & 0x80_0000) >> 1 | ofs & 0x3f_ffff,
-                    _ => unimplemented!(),
-                }
-            }
+        // a slot can name a segment past the end of the ROM
+        Address::new_from_pc(
+            match map {
+                Lorom => (ofs & 0x7f_0000) >> 1 | ofs & 0x00_7fff,
+                // the first 4 MiB are in 80-FF, and the rest in 00-7D
+                Exlorom => (!ofs & 0x80_0000) >> 1 | (ofs & 0x7f_0000) >> 1 | ofs & 0x00_7fff,
+                Hirom => ofs & 0x3f_ffff,
+                // the first 4 MiB are in C0-FF, and the rest in 40-7D
+                Exhirom => (!ofs & 0x80_0000) >> 1 | ofs & 0x3f_ffff,
+                Sfxrom if ofs & 0x40_0000 != 0 => ofs & 0x1f_ffff,
+                Sfxrom => (ofs & 0x3f_0000) >> 1 | ofs & 0x00_7fff,
+                Sa1rom(slots) => {
+                    let segment = slots[sa1_slot(ofs).unwrap()] as usize * 0x10_0000;
+                    if ofs >= 0xc0_0000 {
+                        segment | ofs & 0x0f_ffff
+                    } else {
+                        segment | (ofs & 0x1f_0000) >> 1 | ofs & 0x00_7fff
+                    }
+                },
+                // only the banks at C0-FF can be switched; the LoROM banks are always the first 2 MiB
+                Sddrom(slots) if ofs >= 0xc0_0000 => {
+                    (slots[(ofs - 0xc0_0000) >> 20] as usize * 0x10_0000) | ofs & 0x0f_ffff
+                },
+                Sddrom(_) => (ofs & 0x3f_0000) >> 1 | ofs & 0x00_7fff,
+            },
+            map,
         )
     }
     
@@ -81,15 +136,37 @@ impl Address {
         let pc32 = self.pc as u32;
         match self.map {
             Lorom => Some((pc32 & 0x3f_8000) << 1 | pc32 & 0x00_7fff | 0x80_8000),
-            Exlorom => Some((pc32 & 0x7f_8000) << 1 | pc32 & 0x00_7fff | 0x00_8000),
-            Hirom => Some(pc32 | 0x80_0000),
-            Exhirom => Some((pc32 & 0x40_0000) << 1 | (pc32 & 0x3f_ffff)),
-            _ => unimplemented!(),
+            // the last 64 KiB would be in 7E, which is RAM
+            Exlorom if pc32 >= 0x7f_0000 => None,
+            Exlorom => Some((!pc32 & 0x40_0000) << 1 | (pc32 & 0x3f_8000) << 1 | pc32 & 0x00_7fff | 0x00_8000),
+            Hirom => Some(pc32 | 0xc0_0000),
+            // the last 128 KiB would be in 7E-7F, so only their top halves can be seen, in 3E-3F
+            Exhirom if pc32 >= 0x7e_0000 && pc32 & 0x8000 == 0 => None,
+            Exhirom if pc32 >= 0x7e_0000 => Some(pc32 & 0x3f_ffff),
+            Exhirom => Some((!pc32 & 0x40_0000) << 1 | 0x40_0000 | pc32 & 0x3f_ffff),
+            // the GSU can't run at FastROM speed, so these stay in 00-3F
+            Sfxrom => Some((pc32 & 0x1f_8000) << 1 | pc32 & 0x00_7fff | 0x00_8000),
+            // the LoROM-style banks, since that's where code expects ROM to be
+            Sa1rom(_) => self.snes_seg_ofs().map(|(slot, _)| {
+                let within = pc32 & 0x0f_ffff;
+                ((SA1_LO_BANKS[slot as usize] << 16) + ((within & 0x0f_8000) << 1)) | 0x8000 | (within & 0x7fff)
+            }),
+            Sddrom(_) if pc32 < 0x20_0000 => Some((pc32 & 0x1f_8000) << 1 | pc32 & 0x00_7fff | 0x80_8000),
+            Sddrom(_) => self.snes_seg_ofs().map(|(_, ofs)| ofs),
         }
     }
 
-    pub fn snes_seg_ofs(&self) -> (u8, u32) {
-        unimplemented!()
+    /// Which of the mapper's slots this address's segment is in,
+    /// and where it is in that slot's region.
+    /// `None` if no slot has its segment, or the mapper doesn't have slots.
+    pub fn snes_seg_ofs(&self) -> Option<(u8, u32)> {
+        let slots = match self.map {
+            Sa1rom(slots) | Sddrom(slots) => slots,
+            _ => return None,
+        };
+        let segment = self.segment().unwrap();
+        let slot = slots.iter().position(|&s| s == segment)? as u8;
+        Some((slot, self.map.region_base(slot) | (self.pc as u32 & 0x0f_ffff)))
     }
 
     pub fn segment(&self) -> Option<u8> {
@@ -99,8 +176,334 @@ impl Address {
         }
     }
 
+    pub fn mapper(&self) -> Mapper {
+        self.map
+    }
+
     pub fn pc_addr(&self) -> usize {
         self.pc
     }
+
+    /// The address `n` bytes after this one, if it's still in the ROM.
+    pub fn checked_add(&self, n: usize) -> Option<Address> {
+        self.pc.checked_add(n).and_then(|pc| Address::new_from_pc(pc, self.map))
+    }
+
+    /// The address `n` bytes before this one, if it's still in the ROM.
+    pub fn checked_sub(&self, n: usize) -> Option<Address> {
+        self.pc.checked_sub(n).and_then(|pc| Address::new_from_pc(pc, self.map))
+    }
+
+    /// How many bytes after `base` this address is, if it isn't before it.
+    pub fn offset_from(&self, base: Address) -> Option<usize> {
+        if self.map == base.map { self.pc.checked_sub(base.pc) } else { None }
+    }
+
+    /// Whether `len` bytes starting here run into the next bank.
+    pub fn crosses_bank(&self, len: usize) -> bool {
+        let bank = self.map.bank_size();
+        len > 0 && self.pc / bank != (self.pc + len - 1) / bank
+    }
+
+    /// The `len` bytes starting here, if they're all in the ROM.
+    pub fn range(&self, len: usize) -> Option<AddressRange> {
+        if len == 0 || self.checked_add(len - 1).is_some() {
+            Some(AddressRange { start: *self, len })
+        } else {
+            None
+        }
+    }
+
+    /// Reads an address the way it'd be written on the command line:
+    /// a SNES address as `$05:E000`, `$05E000` or `05E000`,
+    /// or a PC offset as `0x2E000`.
+    pub fn parse(text: &str, map: Mapper) -> Option<Address> {
+        if text.starts_with("0x") || text.starts_with("0X") {
+            let pc = usize::from_str_radix(&text[2 ..], 16).ok()?;
+            return Address::new_from_pc(pc, map);
+        }
+        let text = text.trim_start_matches('$');
+        let ofs = match text.find(':') {
+            Some(i) if i <= 2 && text.len() - i - 1 <= 4 => {
+                let bank = u8::from_str_radix(&text[.. i], 16).ok()? as usize;
+                let addr = u16::from_str_radix(&text[i + 1 ..], 16).ok()? as usize;
+                bank << 16 | addr
+            },
+            Some(_) => return None,
+            None if text.len() <= 6 => usize::from_str_radix(text, 16).ok()?,
+            None => return None,
+        };
+        Address::new_from_snes(ofs, map)
+    }
+}
+
+/// Shows the address as `$05:e000`, or with `{:#}`, as the PC offset `0x02e000`.
+/// An address with nowhere to be seen from, like a segment that isn't in
+/// any of the SA-1's slots, is always shown as a PC offset.
+impl ::std::fmt::Display for Address {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.snes_ofs() {
+            Some(ofs) if !f.alternate() => write!(f, "${:02x}:{:04x}", ofs >> 16, ofs & 0xffff),
+            _ => write!(f, "0x{:06x}", self.pc),
+        }
+    }
+}
+
+/// A run of bytes in the ROM, which goes through their addresses in ROM order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AddressRange { start: Address, len: usize }
+
+impl AddressRange {
+    pub fn start(&self) -> Address {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, a: Address) -> bool {
+        a.map == self.start.map && a.pc >= self.start.pc && a.pc - self.start.pc < self.len
+    }
+
+    pub fn crosses_bank(&self) -> bool {
+        self.start.crosses_bank(self.len)
+    }
+}
+
+impl Iterator for AddressRange {
+    type Item = Address;
+
+    fn next(&mut self) -> Option<Address> {
+        if self.len == 0 {
+            return None;
+        }
+        let a = self.start;
+        self.len -= 1;
+        // the range never runs past the ROM, so this is only `None` after the last byte
+        if let Some(next) = a.checked_add(1) {
+            self.start = next;
+        }
+        Some(a)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // booted, switched past the first 4 MiB, backwards, and with a segment in two slots
+    const SA1_SLOTS: [[u8; 4]; 4] = [[0, 1, 2, 3], [4, 5, 6, 7], [3, 2, 1, 0], [0, 0, 7, 5]];
+
+    fn pc_at(ofs: usize, map: Mapper) -> Option<usize> {
+        Address::new_from_snes(ofs, map).map(|a| a.pc_ofs())
+    }
+
+    #[test]
+    fn sa1_known_addresses() {
+        assert_eq!(pc_at(0x00_8000, BOOT_SA1ROM), Some(0x00_0000));
+        assert_eq!(pc_at(0x1f_ffff, BOOT_SA1ROM), Some(0x0f_ffff));
+        assert_eq!(pc_at(0x20_8000, BOOT_SA1ROM), Some(0x10_0000));
+        assert_eq!(pc_at(0x80_8000, BOOT_SA1ROM), Some(0x20_0000));
+        assert_eq!(pc_at(0xbf_ffff, BOOT_SA1ROM), Some(0x3f_ffff));
+        assert_eq!(pc_at(0xc0_0000, BOOT_SA1ROM), Some(0x00_0000));
+        assert_eq!(pc_at(0xff_ffff, BOOT_SA1ROM), Some(0x3f_ffff));
+        assert_eq!(pc_at(0x00_8000, Sa1rom([4, 5, 6, 7])), Some(0x40_0000));
+        assert_eq!(pc_at(0xe1_2345, Sa1rom([4, 5, 6, 7])), Some(0x61_2345));
+
+        // RAM, I/O and BW-RAM
+        assert_eq!(pc_at(0x00_7fff, BOOT_SA1ROM), None);
+        assert_eq!(pc_at(0x40_8000, BOOT_SA1ROM), None);
+        assert_eq!(pc_at(0x7e_8000, BOOT_SA1ROM), None);
+        assert_eq!(pc_at(0x80_0000, BOOT_SA1ROM), None);
+
+        let a = Address::new_from_pc(0x20_0000, BOOT_SA1ROM).unwrap();
+        assert_eq!(a.snes_ofs(), Some(0x80_8000));
+        assert_eq!(a.snes_seg_ofs(), Some((2, 0xe0_0000)));
+        let a = Address::new_from_pc(0x40_0000, BOOT_SA1ROM).unwrap();
+        assert_eq!(a.snes_ofs(), None);
+        assert_eq!(a.snes_seg_ofs(), None);
+    }
+
+    #[test]
+    fn sa1_every_pc_round_trips() {
+        for &slots in &SA1_SLOTS {
+            let map = Sa1rom(slots);
+            for pc in 0 .. 0x80_0000 {
+                let a = Address::new_from_pc(pc, map).unwrap();
+                let mapped = slots.contains(&((pc >> 20) as u8));
+                match (a.snes_ofs(), a.snes_seg_ofs()) {
+                    (Some(lo), Some((slot, hi))) => {
+                        assert!(mapped);
+                        assert_eq!(slots[slot as usize], (pc >> 20) as u8);
+                        assert_eq!(pc_at(lo as usize, map), Some(pc), "{:?} pc {:06x}", slots, pc);
+                        assert_eq!(pc_at(hi as usize, map), Some(pc), "{:?} pc {:06x}", slots, pc);
+                    },
+                    (None, None) => assert!(!mapped, "{:?} pc {:06x}", slots, pc),
+                    _ => panic!("{:?} pc {:06x} is only half mapped", slots, pc),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sa1_every_snes_address_round_trips() {
+        for &slots in &SA1_SLOTS {
+            let map = Sa1rom(slots);
+            for ofs in 0 .. 0x100_0000 {
+                let bank = ofs >> 16;
+                let is_rom = bank >= 0xc0 || (bank & 0x40 == 0 && ofs & 0x8000 != 0);
+                match Address::new_from_snes(ofs, map) {
+                    // it might come back as another address, but it has to be for the same byte
+                    Some(a) => {
+                        assert!(is_rom, "{:?} ${:06x}", slots, ofs);
+                        let back = a.snes_ofs().unwrap() as usize;
+                        assert_eq!(pc_at(back, map), Some(a.pc_ofs()), "{:?} ${:06x}", slots, ofs);
+                    },
+                    None => assert!(!is_rom, "{:?} ${:06x}", slots, ofs),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sfx_and_sdd_known_addresses() {
+        assert_eq!(pc_at(0x00_8000, Sfxrom), Some(0x00_0000));
+        assert_eq!(pc_at(0x3f_ffff, Sfxrom), Some(0x1f_ffff));
+        assert_eq!(pc_at(0x40_0000, Sfxrom), Some(0x00_0000));
+        assert_eq!(pc_at(0xdf_ffff, Sfxrom), Some(0x1f_ffff));
+        assert_eq!(pc_at(0x70_0000, Sfxrom), None);
+        assert_eq!(pc_at(0x60_0000, Sfxrom), None);
+        assert_eq!(pc_at(0xe0_0000, Sfxrom), None);
+        assert_eq!(Address::new_from_pc(0x1f_ffff, Sfxrom).unwrap().snes_ofs(), Some(0x3f_ffff));
+
+        let switched = Sddrom([4, 5, 6, 7]);
+        assert_eq!(pc_at(0x80_8000, switched), Some(0x00_0000));
+        assert_eq!(pc_at(0xbf_ffff, switched), Some(0x1f_ffff));
+        assert_eq!(pc_at(0xc0_0000, BOOT_SDDROM), Some(0x00_0000));
+        assert_eq!(pc_at(0xc0_0000, switched), Some(0x40_0000));
+        assert_eq!(pc_at(0xff_ffff, switched), Some(0x7f_ffff));
+        assert_eq!(pc_at(0x40_8000, switched), None);
+        assert_eq!(Address::new_from_pc(0x1f_ffff, switched).unwrap().snes_ofs(), Some(0xbf_ffff));
+        assert_eq!(Address::new_from_pc(0x61_2345, switched).unwrap().snes_ofs(), Some(0xe1_2345));
+        assert_eq!(Address::new_from_pc(0x21_2345, switched).unwrap().snes_ofs(), None);
+    }
+
+    #[test]
+    fn sfx_and_sdd_every_snes_address_round_trips() {
+        for &map in &[Sfxrom, BOOT_SDDROM, Sddrom([7, 2, 0, 5])] {
+            for ofs in 0 .. 0x100_0000 {
+                if let Some(a) = Address::new_from_snes(ofs, map) {
+                    let back = a.snes_ofs().unwrap() as usize;
+                    assert_eq!(pc_at(back, map), Some(a.pc_ofs()), "{:?} ${:06x}", map, ofs);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ex_known_addresses() {
+        assert_eq!(pc_at(0x80_8000, Exlorom), Some(0x00_0000));
+        assert_eq!(pc_at(0x00_8000, Exlorom), Some(0x40_0000));
+        assert_eq!(pc_at(0x7d_ffff, Exlorom), Some(0x7e_ffff));
+        assert_eq!(pc_at(0x7e_8000, Exlorom), None);
+        assert_eq!(pc_at(0xc0_0000, Exhirom), Some(0x00_0000));
+        assert_eq!(pc_at(0x40_0000, Exhirom), Some(0x40_0000));
+        assert_eq!(pc_at(0x00_ffc0, Exhirom), Some(0x40_ffc0));
+        assert_eq!(pc_at(0x80_ffc0, Exhirom), Some(0x00_ffc0));
+        assert_eq!(Address::new_from_pc(0x00_0000, Exlorom).unwrap().snes_ofs(), Some(0x80_8000));
+        assert_eq!(Address::new_from_pc(0x7f_0000, Exlorom).unwrap().snes_ofs(), None);
+        assert_eq!(Address::new_from_pc(0x00_1234, Exhirom).unwrap().snes_ofs(), Some(0xc0_1234));
+        assert_eq!(Address::new_from_pc(0x7e_1234, Exhirom).unwrap().snes_ofs(), None);
+        assert_eq!(Address::new_from_pc(0x7f_9234, Exhirom).unwrap().snes_ofs(), Some(0x3f_9234));
+        assert_eq!(Address::new_from_pc(0x00_1234, Hirom).unwrap().snes_ofs(), Some(0xc0_1234));
+    }
+
+    #[test]
+    fn lo_and_hi_every_pc_round_trips() {
+        for &map in &[Lorom, Hirom, Exlorom, Exhirom, Sfxrom] {
+            let mut pc = 0;
+            while let Some(a) = Address::new_from_pc(pc, map) {
+                if let Some(snes) = a.snes_ofs() {
+                    assert_eq!(pc_at(snes as usize, map), Some(pc), "{:?} pc {:06x}", map, pc);
+                }
+                pc += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn lo_and_hi_every_snes_address_round_trips() {
+        for &map in &[Lorom, Hirom, Exlorom, Exhirom] {
+            for ofs in 0 .. 0x100_0000 {
+                if let Some(a) = Address::new_from_snes(ofs, map) {
+                    let back = a.snes_ofs().unwrap() as usize;
+                    assert_eq!(pc_at(back, map), Some(a.pc_ofs()), "{:?} ${:06x}", map, ofs);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = Address::new_from_snes(0x05_e000, Lorom).unwrap();
+        assert_eq!(a.checked_add(0x2000).unwrap().snes_ofs(), Some(0x86_8000));
+        assert_eq!(a.checked_sub(0x2e001), None);
+        assert_eq!(a.checked_sub(0x2e000).map(|a| a.pc_ofs()), Some(0));
+        assert_eq!(Address::new_from_pc(0x3f_ffff, Lorom).unwrap().checked_add(1), None);
+        assert_eq!(a.checked_add(0x2003).unwrap().offset_from(a), Some(0x2003));
+        assert_eq!(a.checked_sub(1).unwrap().offset_from(a), None);
+        assert_eq!(Address::new_from_pc(0x2_e000, Hirom).unwrap().offset_from(a), None);
+
+        assert!(!a.crosses_bank(0x2000));
+        assert!(a.crosses_bank(0x2001));
+        assert!(!a.crosses_bank(0));
+        let h = Address::new_from_pc(0x01_8000, Hirom).unwrap();
+        assert!(!h.crosses_bank(0x8000));
+        assert!(h.crosses_bank(0x8001));
+    }
+
+    #[test]
+    fn ranges() {
+        let a = Address::new_from_pc(0x3f_fffe, Lorom).unwrap();
+        let r = a.range(2).unwrap();
+        assert_eq!(r.map(|a| a.pc_ofs()).collect::<Vec<_>>(), vec![0x3f_fffe, 0x3f_ffff]);
+        assert_eq!(a.range(3), None);
+        assert_eq!(a.range(0).unwrap().count(), 0);
+
+        let b = Address::new_from_snes(0x80_fffe, Lorom).unwrap();
+        let snes = b.range(4).unwrap().map(|a| a.snes_ofs().unwrap()).collect::<Vec<_>>();
+        assert_eq!(snes, vec![0x80_fffe, 0x80_ffff, 0x81_8000, 0x81_8001]);
+        assert!(b.range(4).unwrap().crosses_bank());
+        assert!(b.range(4).unwrap().contains(b.checked_add(3).unwrap()));
+        assert!(!b.range(4).unwrap().contains(b.checked_add(4).unwrap()));
+    }
+
+    #[test]
+    fn parse_and_format() {
+        let a = Address::new_from_snes(0x05_e000, Lorom).unwrap();
+        for text in &["$05:E000", "$05E000", "05E000", "5:e000", "0x2e000", "0X02E000"] {
+            let b = Address::parse(text, Lorom);
+            assert_eq!(b.map(|b| b.pc_ofs()), Some(0x2_e000), "{}", text);
+        }
+        for text in &["", "$", "05:", "0x", "$05:E0000", "105:E000", "1005E000", "$05:7000", "0x400000", "E000x"] {
+            assert_eq!(Address::parse(text, Lorom), None, "{}", text);
+        }
+        assert_eq!(a.to_string(), "$85:e000");
+        assert_eq!(format!("{:#}", a), "0x02e000");
+        assert_eq!(Address::parse(&a.to_string(), Lorom), Some(a));
+        assert_eq!(Address::parse(&format!("{:#}", a), Lorom), Some(a));
+
+        let hidden = Address::new_from_pc(0x40_0000, BOOT_SA1ROM).unwrap();
+        assert_eq!(hidden.to_string(), "0x400000");
+    }
+}
@@ -0,0 +1,84 @@
+// Since format 0.2.0, every level has a table of CRCs right after its header,
+// one for each section, so that when something else writes over a level
+// we can tell which part of it was hit.
+//
+// The 2 bytes at the end of the pointer hole can also hold a CRC of everything
+// from the end of the hole to the end of the header, for the game to check
+// when it loads the level. 0000 there means the level has no such CRC,
+// so a CRC that comes out as 0000 is stored as FFFF instead.
+
+use crc::crc16;
+
+pub const SECTIONS: [&str; 8] = [
+    "pointer hole", "screens", "screendex", "sprites",
+    "palette", "entrances", "exits", "header",
+];
+
+/// The CRC of `body` to put in the pointer hole, which is never 0000.
+pub fn loader_crc(body: &[u8]) -> u16 {
+    match crc16(body) {
+        0 => 0xffff,
+        c => c,
+    }
+}
+
+pub const TABLE_LEN: u32 = SECTIONS.len() as u32 * 2;
+
+/// The pointer hole: the sprites and header pointers, then the loader CRC.
+pub const HOLE_LEN: u32 = 8;
+pub const HEADER_LEN: u32 = 18;
+pub const PAL_LEN: u32 = 0x202;
+
+/// Where the sections of a level start, as offsets from its pointer hole.
+pub struct Layout {
+    pub dex: u32,
+    pub sprites: u32,
+    pub pal: Option<u32>,
+    pub entrances: u32,
+    pub exits: u32,
+    pub header: u32,
+}
+
+impl Layout {
+    /// The start and end of each section, in the order of `SECTIONS`.
+    pub fn ranges(&self) -> [(u32, u32); 8] {
+        let sprites_end = self.pal.unwrap_or(self.entrances);
+        let pal = match self.pal {
+            Some(p) => (p, p + PAL_LEN),
+            None => (self.entrances, self.entrances),
+        };
+        [
+            (0, HOLE_LEN - 2),
+            (HOLE_LEN, self.dex),
+            (self.dex, self.sprites),
+            (self.sprites, sprites_end),
+            pal,
+            (self.entrances, self.exits),
+            (self.exits, self.header),
+            (self.header, self.header + HEADER_LEN),
+        ]
+    }
+
+    /// The part of the level covered by the CRC in the pointer hole.
+    pub fn body(&self) -> (u32, u32) {
+        (HOLE_LEN, self.header + HEADER_LEN)
+    }
+
+    pub fn table(&self) -> u32 {
+        self.header + HEADER_LEN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loader_crc_is_never_zero() {
+        // a CRC-16/CCITT-FALSE of data followed by its own CRC is 0
+        let body = b"123456789\x29\xb1";
+        assert_eq!(crc16(body), 0);
+        assert_eq!(loader_crc(body), 0xffff);
+        assert_eq!(loader_crc(b"123456789"), 0x29b1);
+    }
+}
@@ -0,0 +1,644 @@
+use std::io;
+use address::{Address, Mapper};
+use spr::SpritePlacement;
+
+use super::read::{read_long, sprite_table_len, unpack_entrance, unpack_sprite};
+use super::checksum::{loader_crc, Layout, HEADER_LEN, HOLE_LEN, PAL_LEN, SECTIONS, TABLE_LEN};
+use crc::crc16;
+use level_table::MARKER;
+use rats::scan::{self, MARKER_LEN, TAG_LEN};
+
+// The dump is for looking at levels that are already broken,
+// so nothing in here trusts the ROM: every read is bounds-checked,
+// and anything that doesn't agree with the rest of the block is
+// printed with a "!!" marker instead of stopping the dump.
+
+const SCREEN_BYTES: usize = 0x200;
+
+struct Dumper<'r, W: io::Write + 'r> {
+    rom: &'r [u8],
+    map: Mapper,
+    out: &'r mut W,
+    problems: u32,
+}
+
+impl<'r, W: io::Write> Dumper<'r, W> {
+    fn byte(&self, at: Address) -> Option<u8> {
+        self.rom.get(at.pc_ofs()).cloned()
+    }
+
+    fn bytes(&self, at: Address, len: usize) -> Option<&'r [u8]> {
+        let r = at.range(len)?;
+        let rom = self.rom;
+        rom.get(r.start().pc_ofs() .. r.start().pc_ofs() + r.len())
+    }
+
+    fn long(&self, at: Address) -> Option<u32> {
+        self.bytes(at, 3).map(read_long)
+    }
+
+    /// The bytes from offset `from` to offset `to` of the level at `start`.
+    fn span(&self, start: Address, from: u32, to: u32) -> Option<&'r [u8]> {
+        if to < from {
+            return None;
+        }
+        self.bytes(start.checked_add(from as usize)?, (to - from) as usize)
+    }
+
+    /// Where the `what` pointer `ptr` points, if it's in the ROM at all.
+    fn to(&mut self, what: &str, ptr: u32) -> io::Result<Option<Address>> {
+        let at = Address::new_from_snes(ptr as usize, self.map);
+        if at.is_none() {
+            self.problem(&format!("{} pointer {} is not in the ROM", what, snes(ptr)))?;
+        }
+        Ok(at)
+    }
+
+    fn problem(&mut self, msg: &str) -> io::Result<()> {
+        self.problems += 1;
+        writeln!(self.out, "  !! {}", msg)
+    }
+
+    /// Checks a section against where the one before it ended, if it's known.
+    fn expect_at(&mut self, what: &str, found: Address, expected: Option<Address>) -> io::Result<()> {
+        match expected {
+            Some(e) if e != found => self.problem(&format!(
+                "{} pointer is {}, but the previous section ends at {}",
+                what, found, e
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Shows a pointer read out of the ROM the same way as an `Address`.
+fn snes(ptr: u32) -> String {
+    format!("${:02x}:{:04x}", ptr >> 16, ptr & 0xffff)
+}
+
+/// Writes an annotated listing of the level block starting at `start`,
+/// which was written in format `version`.
+///
+/// Returns the number of inconsistencies that were marked in the listing.
+pub fn dump_level<W: io::Write>(
+    out: &mut W,
+    rom: &[u8],
+    start: Address,
+    map: Mapper,
+    version: (u8, u8, u8),
+) -> io::Result<u32> {
+    let mut d = Dumper { rom, map, out, problems: 0 };
+
+    dump_rats(&mut d, start)?;
+
+    writeln!(d.out, "pointer hole @ {}", start)?;
+    let (sprites, header) = match (d.long(start), start.checked_add(3).and_then(|a| d.long(a))) {
+        (Some(s), Some(h)) => (s, h),
+        _ => {
+            d.problem("pointer hole runs past the end of the ROM")?;
+            return Ok(d.problems);
+        },
+    };
+    writeln!(d.out, "  +0  sprites   -> {}", snes(sprites))?;
+    writeln!(d.out, "  +3  header    -> {}", snes(header))?;
+    if let Some(rest) = start.checked_add(6).and_then(|a| d.bytes(a, 2)) {
+        writeln!(d.out, "  +6  checksum  {:02x} {:02x}", rest[0], rest[1])?;
+    }
+    let (sprites, header) = match (d.to("sprites", sprites)?, d.to("header", header)?) {
+        (Some(s), Some(h)) => (s, h),
+        _ => return Ok(d.problems),
+    };
+
+    let hed = match dump_header(&mut d, header)? {
+        Some(h) => h,
+        None => return Ok(d.problems),
+    };
+
+    let (dex_expected, screen_count) = match start.checked_add(HOLE_LEN as usize) {
+        Some(at) => dump_screens(&mut d, at)?,
+        None => {
+            d.problem("screen data runs past the end of the ROM")?;
+            (None, 0)
+        },
+    };
+    d.expect_at("dex", hed.dex, dex_expected)?;
+
+    let dex_len = dump_dex(&mut d, &hed, screen_count)?;
+    d.expect_at("sprites", sprites, hed.dex.checked_add(dex_len))?;
+
+    let sprites_end = dump_sprites(&mut d, sprites, screen_count)?;
+    let pal_expected = sprites_end.and_then(|e| e.checked_add(e.pc_ofs() & 1));
+
+    let entrances_expected = if let Some(pal) = hed.pal {
+        d.expect_at("palette", pal, pal_expected)?;
+        dump_pal(&mut d, pal)?;
+        pal.checked_add(PAL_LEN as usize)
+    } else {
+        pal_expected
+    };
+    d.expect_at("entrances", hed.entrances, entrances_expected)?;
+
+    dump_entrances(&mut d, hed.entrances, hed.exits)?;
+
+    let header_expected = dump_exits(&mut d, hed.exits, screen_count)?;
+    d.expect_at("header", header, header_expected)?;
+
+    let mut len = HEADER_LEN;
+    if version >= (0, 2, 0) {
+        match layout(start, sprites, header, &hed) {
+            Some(layout) => dump_checksums(&mut d, start, &layout)?,
+            None => d.problem("can't check the checksums; a section starts before the pointer hole")?,
+        }
+        len += TABLE_LEN;
+    }
+
+    match header.checked_add(len as usize) {
+        Some(end) => match end.offset_from(start) {
+            Some(n) => writeln!(d.out, "end @ {} ({} bytes)", end, n)?,
+            None => {
+                writeln!(d.out, "end @ {}", end)?;
+                d.problem("the level ends before it starts; the header pointer is below the level")?;
+            },
+        },
+        None => d.problem("the level runs past the end of the ROM")?,
+    }
+    Ok(d.problems)
+}
+
+/// Where each section is, as an offset from the pointer hole at `start`.
+fn layout(start: Address, sprites: Address, header: Address, hed: &RawHeader) -> Option<Layout> {
+    let ofs = |at: Address| at.offset_from(start).map(|o| o as u32);
+    Some(Layout {
+        dex: ofs(hed.dex)?,
+        sprites: ofs(sprites)?,
+        pal: match hed.pal {
+            Some(p) => Some(ofs(p)?),
+            None => None,
+        },
+        entrances: ofs(hed.entrances)?,
+        exits: ofs(hed.exits)?,
+        header: ofs(header)?,
+    })
+}
+
+fn dump_rats<W: io::Write>(d: &mut Dumper<W>, start: Address) -> io::Result<()> {
+    let tag_start = match start.checked_sub(TAG_LEN + MARKER_LEN) {
+        Some(a) => a,
+        None => return d.problem("level starts too close to the start of the ROM to have a RATS tag"),
+    };
+    let rom = d.rom;
+    if start.pc_ofs() > rom.len() {
+        return d.problem("level starts past the end of the ROM");
+    }
+    writeln!(d.out, "RATS tag @ PC {:#}", tag_start)?;
+    let t = match scan::read_tag(&rom[tag_start.pc_ofs() ..], tag_start.pc_ofs()) {
+        Some(t) => t,
+        None => return d.problem("no STAR tag in front of the level"),
+    };
+    writeln!(d.out, "  STAR  protects 0x{:04x} bytes", t.len)?;
+    if !t.valid {
+        d.problem("RATS length and complement disagree")?;
+    }
+    let end = scan::block_end(rom, &t);
+    if end != t.protected_end() {
+        writeln!(d.out, "  (written by an older exlev; the block really ends at PC {:x}, 0x{:04x} bytes in)",
+            end, end - t.data_start())?;
+    }
+    if t.has_marker(MARKER) {
+        writeln!(d.out, "  CLNP")?;
+        Ok(())
+    } else {
+        d.problem("no CLNP marker after the RATS tag")
+    }
+}
+
+struct RawHeader {
+    dex: Address,
+    exits: Address,
+    width: u8,
+    height: u8,
+    pal: Option<Address>,
+    entrances: Address,
+    wide_dex: bool,
+}
+
+fn dump_header<W: io::Write>(d: &mut Dumper<W>, at: Address) -> io::Result<Option<RawHeader>> {
+    writeln!(d.out, "header @ {}", at)?;
+    let b = match d.bytes(at, HEADER_LEN as usize) {
+        Some(b) => b,
+        None => {
+            d.problem("header runs past the end of the ROM")?;
+            return Ok(None);
+        },
+    };
+
+    let dex = read_long(&b[0 ..]);
+    let exits = read_long(&b[3 ..]);
+    let (width, height) = (b[6], b[7]);
+    writeln!(d.out, "  +0  dex       -> {}", snes(dex))?;
+    writeln!(d.out, "  +3  exits     -> {}", snes(exits))?;
+    writeln!(d.out, "  +6  width     {}", width)?;
+    writeln!(d.out, "  +7  height    {}", height)?;
+    if width != 32 || height != 32 {
+        d.problem("only 32x32 levels are supported")?;
+    }
+    writeln!(d.out, "  +8  mode      {:02x}: level mode {:02x}, layer 3 image {}, wide screendex {}",
+        b[8], b[8] & 0x1f, b[8] >> 5 & 3, b[8] >> 7)?;
+
+    let pal = if b[9] & 1 == 1 {
+        let p = read_long(&b[9 ..]) & !1;
+        writeln!(d.out, "  +9  palette   custom -> {}", snes(p))?;
+        Some(p)
+    } else {
+        writeln!(d.out, "  +9  palette   shared: fg {}, bg {}, sprite {}, sky {}",
+            b[11] >> 3, b[11] & 7, b[10] & 7, b[10] >> 3)?;
+        if b[9] != 0 {
+            d.problem("shared palette has a nonzero first byte")?;
+        }
+        None
+    };
+
+    writeln!(d.out, "  +c  audio     {:02x}", b[12])?;
+    writeln!(d.out, "  +d  tilesets  {:02x}: fg {:x}, sprite {:x}", b[13], b[13] & 0xf, b[13] >> 4)?;
+    writeln!(d.out, "  +e  settings  {:02x}: time {}, layer 3 priority {}, scroll {}",
+        b[14], b[14] >> 4, b[14] >> 3 & 1, b[14] & 7)?;
+    let entrances = read_long(&b[15 ..]);
+    writeln!(d.out, "  +f  entrances -> {}", snes(entrances))?;
+
+    let (dex, exits, entrances) = match (d.to("dex", dex)?, d.to("exits", exits)?, d.to("entrances", entrances)?) {
+        (Some(dex), Some(exits), Some(entrances)) => (dex, exits, entrances),
+        _ => return Ok(None),
+    };
+    let pal = match pal {
+        Some(p) => match d.to("palette", p)? {
+            Some(p) => Some(p),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+
+    let wide_dex = b[8] & 0x80 != 0;
+    Ok(Some(RawHeader {dex, exits, width, height, pal, entrances, wide_dex}))
+}
+
+/// Returns the address just past the screen data, if it's in the ROM, and the number of screens.
+fn dump_screens<W: io::Write>(d: &mut Dumper<W>, at: Address) -> io::Result<(Option<Address>, usize)> {
+    writeln!(d.out, "screens @ {}", at)?;
+    let mut p = Some(at);
+    let mut decoded = 0;
+    let mut terminated = false;
+    while let Some((here, l)) = p.and_then(|a| d.bytes(a, 2).map(|l| (a, l))) {
+        let len = l[0] as usize | (l[1] as usize) << 8;
+        if len == 0 {
+            writeln!(d.out, "  {}  end", here)?;
+            p = here.checked_add(2);
+            terminated = true;
+            break;
+        }
+        let val = match here.checked_add(2).and_then(|a| d.byte(a)) {
+            Some(v) => v,
+            None => break,
+        };
+        writeln!(d.out, "  {}  {:5} x {:02x}  (screen {:02x} +{:03x})",
+            here, len, val, decoded / SCREEN_BYTES, decoded % SCREEN_BYTES)?;
+        decoded += len;
+        p = here.checked_add(3);
+    }
+    if !terminated {
+        d.problem("screen data runs past the end of the ROM")?;
+        p = None;
+    }
+
+    writeln!(d.out, "  {} bytes decoded, {} screens", decoded, decoded / SCREEN_BYTES)?;
+    if decoded % SCREEN_BYTES != 0 {
+        d.problem(&format!("decoded screen data is not a whole number of screens ({} left over)",
+            decoded % SCREEN_BYTES))?;
+    }
+    Ok((p, decoded / SCREEN_BYTES))
+}
+
+/// Returns the length of the screendex.
+fn dump_dex<W: io::Write>(d: &mut Dumper<W>, hed: &RawHeader, screens: usize) -> io::Result<usize> {
+    let (w, h) = (hed.width as usize, hed.height as usize);
+    let entry = if hed.wide_dex { 2 } else { 1 };
+    let len = w * h * 2 * entry;
+    if hed.wide_dex {
+        writeln!(d.out, "screendex @ {}  (16-bit entries, * = scroll filter bit 0x8000)", hed.dex)?;
+    } else {
+        writeln!(d.out, "screendex @ {}  (* = scroll filter bit 0x80)", hed.dex)?;
+    }
+    let bytes = match d.bytes(hed.dex, len) {
+        Some(b) => b,
+        None => {
+            d.problem("screendex runs past the end of the ROM")?;
+            return Ok(len);
+        },
+    };
+
+    let mut bad = Vec::new();
+    let entries: Vec<(u16, bool)> = if hed.wide_dex {
+        bytes.chunks(2).map(|e| {
+            let e = e[0] as u16 | (e[1] as u16) << 8;
+            (e & 0x7fff, e & 0x8000 != 0)
+        }).collect()
+    } else {
+        bytes.iter().map(|&e| ((e & 0x7f) as u16, e & 0x80 != 0)).collect()
+    };
+    for (layer, chunk) in entries.chunks(w * h).enumerate() {
+        writeln!(d.out, "  {}", if layer == 0 { "FG" } else { "BG" })?;
+        for (y, row) in chunk.chunks(w).enumerate() {
+            let mut line = format!("  {:02x}:", y);
+            for (x, &(e, s)) in row.iter().enumerate() {
+                if hed.wide_dex {
+                    line.push_str(&format!(" {:03x}{}", e, if s { '*' } else { ' ' }));
+                } else {
+                    line.push_str(&format!(" {:02x}{}", e, if s { '*' } else { ' ' }));
+                }
+                if e as usize >= screens {
+                    bad.push((layer, x, y, e));
+                }
+            }
+            writeln!(d.out, "{}", line)?;
+        }
+    }
+    for (layer, x, y, e) in bad {
+        d.problem(&format!("{} screen {:02x},{:02x} uses screen {:02x}, but there are only {} screens",
+            if layer == 0 { "FG" } else { "BG" }, x, y, e, screens))?;
+    }
+    Ok(len)
+}
+
+/// Returns the address just past the sprite data, if it's in the ROM.
+fn dump_sprites<W: io::Write>(d: &mut Dumper<W>, at: Address, screens: usize) -> io::Result<Option<Address>> {
+    writeln!(d.out, "sprites @ {}", at)?;
+    match d.byte(at) {
+        Some(0) => writeln!(d.out, "  {}  00 (leading byte)", at)?,
+        Some(b) => d.problem(&format!("leading sprite byte is {:02x}, should be 00", b))?,
+        None => {
+            d.problem("sprite data runs past the end of the ROM")?;
+            return Ok(None);
+        },
+    }
+
+    let table_len = sprite_table_len(screens) as usize;
+    let (table_at, table, records_at) = match at.checked_add(1).and_then(|t| {
+        Some((t, d.bytes(t, table_len)?, t.checked_add(table_len)?))
+    }) {
+        Some(t) => t,
+        None => {
+            d.problem("sprite offset table runs past the end of the ROM")?;
+            return Ok(None);
+        },
+    };
+
+    writeln!(d.out, "  offset table @ {}", table_at)?;
+    let mut users = Vec::new();
+    for (i, e) in table.chunks(2).enumerate() {
+        if i < screens {
+            if e == [0, 0] {
+                writeln!(d.out, "    screen {:02x}: none", i)?;
+            } else {
+                let from = records_at.checked_add((e[0] as usize >> 1) * 4)
+                    .map_or("past the end of the ROM".to_string(), |a| a.to_string());
+                writeln!(d.out, "    screen {:02x}: {:02x} {:02x} (from {}, sprite #{})", i, e[0], e[1], from, e[1])?;
+                users.push(i);
+            }
+        } else if e != [0, 0] {
+            d.problem(&format!("offset table has an entry for screen {:02x}, which does not exist", i))?;
+        }
+    }
+
+    writeln!(d.out, "  records @ {}", records_at)?;
+    if d.bytes(records_at, 4) != Some(&SpritePlacement::TERMINATOR_BYTES[..]) {
+        d.problem("sprite records don't start with a terminator")?;
+    }
+    let mut p = records_at.checked_add(4);
+
+    for scr in users {
+        writeln!(d.out, "    screen {:02x}", scr)?;
+        loop {
+            let (here, r) = match p.and_then(|a| d.bytes(a, 4).map(|r| (a, r))) {
+                Some(r) => r,
+                None => {
+                    d.problem("sprite records run past the end of the ROM")?;
+                    return Ok(None);
+                },
+            };
+            p = here.checked_add(4);
+            if r[0] & 0x80 != 0 {
+                writeln!(d.out, "    {}  {:02x} {:02x} {:02x} {:02x}  end", here, r[0], r[1], r[2], r[3])?;
+                break;
+            }
+            let long = r[0] & 2 != 0;
+            let spr = unpack_sprite(r);
+            writeln!(d.out, "    {}  {:02x} {:02x} {:02x} {:02x}  sprite {:03x} at {:x},{:x}, extra bit {}, xb1 {:02x}",
+                here, r[0], r[1], r[2], r[3], spr.id, spr.pos_x, spr.pos_y, spr.xbit as u8, spr.xbytes[0])?;
+            if long {
+                match p.and_then(|a| d.bytes(a, 4).map(|x| (a, x))) {
+                    Some((here, x)) => {
+                        writeln!(d.out, "    {}  {:02x} {:02x} {:02x} {:02x}  xb2 {:02x}, xb3 {:02x}, xb4 {:02x}",
+                            here, x[0], x[1], x[2], x[3], x[0], x[1], x[2])?;
+                        if x[3] != 0 {
+                            d.problem("padding byte of a long sprite is not 00")?;
+                        }
+                        p = here.checked_add(4);
+                    },
+                    None => {
+                        d.problem("sprite records run past the end of the ROM")?;
+                        return Ok(None);
+                    },
+                }
+            }
+        }
+    }
+
+    Ok(p)
+}
+
+fn dump_pal<W: io::Write>(d: &mut Dumper<W>, at: Address) -> io::Result<()> {
+    writeln!(d.out, "palette @ {}", at)?;
+    let bytes = match d.bytes(at, PAL_LEN as usize) {
+        Some(b) => b,
+        None => return d.problem("palette runs past the end of the ROM"),
+    };
+    let (bg, rest) = bytes.split_at(2);
+    writeln!(d.out, "  back  {:04x}", bg[0] as u16 | (bg[1] as u16) << 8)?;
+    for (row, colors) in rest.chunks(0x20).enumerate() {
+        let mut line = format!("  {:x}:  ", row);
+        for c in colors.chunks(2) {
+            line.push_str(&format!(" {:04x}", c[0] as u16 | (c[1] as u16) << 8));
+        }
+        writeln!(d.out, "{}", line)?;
+    }
+    if at.pc_ofs() & 1 == 1 {
+        d.problem("palette is not word-aligned")?;
+    }
+    Ok(())
+}
+
+fn dump_entrances<W: io::Write>(d: &mut Dumper<W>, at: Address, end: Address) -> io::Result<()> {
+    writeln!(d.out, "entrances @ {}", at)?;
+    let primaries = match d.byte(at) {
+        Some(n) => n,
+        None => return d.problem("entrances run past the end of the ROM"),
+    };
+    writeln!(d.out, "  {}  {:02x} (primary count)", at, primaries)?;
+    if primaries > 2 {
+        d.problem("more than 2 primary entrances")?;
+    }
+
+    // There is no stored count of secondary entrances:
+    // the exit table comes right after the last one.
+    let count = match end.offset_from(at) {
+        Some(len) if len >= 1 && (len - 1).is_multiple_of(6) => (len - 1) / 6,
+        _ => return d.problem("entrance records don't end where the exits begin"),
+    };
+
+    for i in 0 .. count {
+        let (p, e) = match at.checked_add(1 + i * 6).and_then(|p| d.bytes(p, 6).map(|e| (p, e))) {
+            Some(e) => e,
+            None => return d.problem("entrances run past the end of the ROM"),
+        };
+        let en = unpack_entrance(e);
+        let (kind, sub) = if i < primaries as usize { ('m', i) } else { ('s', i - primaries as usize) };
+        writeln!(d.out, "  {}  {:02x} {:02x} {:02x} {:02x} {:02x} {:02x}  {}{:02x}",
+            p, e[0], e[1], e[2], e[3], e[4], e[5], kind, sub)?;
+        writeln!(d.out, "      level {:03x}, animation {}, x {:03x}, y {:03x}, bg offset {:03x}",
+            en.levelnum, en.anim, en.x, en.y, en.bgofs)?;
+        writeln!(d.out, "      intro {}, constant {}, scroll {:x}, water {}, slippery {}",
+            en.intro as u8, en.three, en.scroll, en.water as u8, en.slippery as u8)?;
+        if en.three != 3 {
+            d.problem(&format!("entrance {}{:02x} has {} in its constant field, should be 3", kind, sub, en.three))?;
+        }
+    }
+
+    if count < primaries as usize {
+        d.problem(&format!("primary count is {}, but there are only {} entrances", primaries, count))?;
+    } else if count - primaries as usize > 32 {
+        d.problem("more than 32 secondary entrances")?;
+    }
+    Ok(())
+}
+
+fn dump_checksums<W: io::Write>(d: &mut Dumper<W>, start: Address, layout: &Layout) -> io::Result<()> {
+    let (at, table) = match start.checked_add(layout.table() as usize)
+        .and_then(|at| d.bytes(at, TABLE_LEN as usize).map(|t| (at, t))) {
+        Some(t) => t,
+        None => return d.problem("checksum table runs past the end of the ROM"),
+    };
+    writeln!(d.out, "checksums @ {}", at)?;
+
+    for (i, (&(from, to), name)) in layout.ranges().iter().zip(SECTIONS.iter()).enumerate() {
+        let stored = table[i * 2] as u16 | (table[i * 2 + 1] as u16) << 8;
+        writeln!(d.out, "  +{:02x}  {:04x}  {}", i * 2, stored, name)?;
+        match d.span(start, from, to).map(crc16) {
+            Some(c) if c == stored => (),
+            Some(c) => d.problem(&format!("{} has changed: its checksum is now {:04x}", name, c))?,
+            None => d.problem(&format!("can't check {}; it doesn't fit in the ROM", name))?,
+        }
+    }
+
+    let (from, to) = layout.body();
+    match d.span(start, HOLE_LEN - 2, HOLE_LEN) {
+        Some(&[0, 0]) => writeln!(d.out, "  no checksum for the game to check")?,
+        Some(h) => {
+            let stored = h[0] as u16 | (h[1] as u16) << 8;
+            writeln!(d.out, "  {:04x}  checksum for the game to check (in the pointer hole)", stored)?;
+            if to < from {
+                d.problem("can't check the level's checksum; its body ends before it starts")?;
+            } else if d.span(start, from, to).map(loader_crc) != Some(stored) {
+                d.problem("the game will reject this level, since its checksum doesn't match")?;
+            }
+        },
+        None => (),
+    }
+    Ok(())
+}
+
+/// Returns the address just past the exits, if it's in the ROM.
+fn dump_exits<W: io::Write>(d: &mut Dumper<W>, at: Address, screens: usize) -> io::Result<Option<Address>> {
+    writeln!(d.out, "exits @ {}", at)?;
+    for i in 0 .. screens {
+        match at.checked_add(i * 3).and_then(|p| d.bytes(p, 3).map(|e| (p, e))) {
+            Some((p, e)) => {
+                let lvl = e[0] as u16 | (e[1] as u16) << 8;
+                writeln!(d.out, "  {}  {:02x} {:02x} {:02x}  screen {:02x} -> {:03x}#{}{:02x}",
+                    p, e[0], e[1], e[2], i, lvl,
+                    if e[2] & 0x80 != 0 { 's' } else { 'm' }, e[2] & 0x7f)?;
+                if lvl >= 0x200 {
+                    d.problem(&format!("exit for screen {:02x} goes to a level past 1ff", i))?;
+                }
+            },
+            None => {
+                d.problem("exits run past the end of the ROM")?;
+                return Ok(None);
+            },
+        }
+    }
+    Ok(at.checked_add(screens * 3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use level::{Level, LevelHeader, PScrGrid};
+    use level_table::MARKER;
+    use rats;
+    use super::super::{write_level_body, FORMAT_VERSION};
+
+    /// An empty level, inserted at PC 0x80000 the way `--insert-tmx` does it.
+    fn inserted_level() -> (Vec<u8>, Address) {
+        let level = Level::from_parts(
+            PScrGrid::new(32, 32), PScrGrid::new(32, 32), vec![false; 32 * 32], Vec::new(), LevelHeader::default()
+        );
+        let space = Address::new_from_pc(0x8_0000, Mapper::Lorom).unwrap();
+        let start = Address::new_from_pc(0x8_000c, Mapper::Lorom).unwrap();
+        let mut data = MARKER.to_vec();
+        data.extend(write_level_body(&level).unwrap().relocate(start.snes_ofs().unwrap()));
+        let mut rom = vec![0; 0x10_0000];
+        rats::insert_at(&mut rom, space, &data);
+        (rom, start)
+    }
+
+    fn dump(rom: &[u8], start: Address) -> (u32, String) {
+        let mut out = Vec::new();
+        let problems = dump_level(&mut out, rom, start, Mapper::Lorom, FORMAT_VERSION).unwrap();
+        (problems, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn inserted_level_has_no_problems() {
+        let (rom, start) = inserted_level();
+        let (problems, out) = dump(&rom, start);
+        assert_eq!(problems, 0, "{}", out);
+        assert!(out.contains("header @ "), "{}", out);
+    }
+
+    #[test]
+    fn header_below_the_level() {
+        let (mut rom, start) = inserted_level();
+        // move the header far enough below the level that it ends before it too
+        let hole = start.pc_ofs() + 3;
+        let header = Address::new_from_snes(read_long(&rom[hole ..]) as usize, Mapper::Lorom).unwrap().pc_ofs();
+        rom.copy_within(header .. header + HEADER_LEN as usize, 0x7_ff00);
+        let below = Address::new_from_pc(0x7_ff00, Mapper::Lorom).unwrap().snes_ofs().unwrap();
+        rom[hole .. hole + 3].copy_from_slice(&[below as u8, (below >> 8) as u8, (below >> 16) as u8]);
+        let (problems, out) = dump(&rom, start);
+        assert!(problems > 0);
+        assert!(out.contains("the level ends before it starts"), "{}", out);
+    }
+
+    #[test]
+    fn bad_tag_and_pointers() {
+        let (mut rom, start) = inserted_level();
+        rom[0x8_0006] ^= 1;
+        // the screendex pointer, at the start of the header
+        let header = read_long(&rom[start.pc_ofs() + 3 ..]);
+        let pc = Address::new_from_snes(header as usize, Mapper::Lorom).unwrap().pc_ofs();
+        rom[pc] ^= 2;
+        let (problems, out) = dump(&rom, start);
+        assert!(out.contains("RATS length and complement disagree"), "{}", out);
+        assert!(out.contains("dex pointer is"), "{}", out);
+        assert!(problems >= 2);
+    }
+}
@@ -1,12 +1,34 @@
 use std::error;
 use std::fmt;
 
-#[derive(Debug, Clone, Copy)]
-pub enum DecodeError {}
+use level::TooManyScreens;
+
+#[derive(Debug, Clone)]
+pub enum DecodeError {
+    /// The level was stamped with a format version this exlev doesn't know.
+    UnknownVersion(u8, u8, u8),
+    /// A section of the level runs past the end of the ROM
+    /// (or points somewhere that isn't ROM).
+    Truncated(&'static str),
+    /// A section of the level can't be made sense of.
+    Malformed(&'static str, String),
+    /// A section of the level doesn't match its checksum,
+    /// so something else has probably written over it.
+    Checksum(&'static str),
+}
 
 impl fmt::Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "error decoding binary level")
+        match *self {
+            DecodeError::UnknownVersion(a, b, c) =>
+                write!(f, "error decoding binary level: unknown format version {}.{}.{}", a, b, c),
+            DecodeError::Truncated(section) =>
+                write!(f, "error decoding binary level: {} runs out of ROM", section),
+            DecodeError::Malformed(section, ref msg) =>
+                write!(f, "error decoding binary level: bad {}: {}", section, msg),
+            DecodeError::Checksum(section) =>
+                write!(f, "error decoding binary level: the {} section has changed since it was inserted", section),
+        }
     }
 }
 
@@ -19,12 +41,30 @@ impl error::Error for DecodeError {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum EncodeError {}
+#[derive(Debug, Clone)]
+pub enum EncodeError {
+    /// The level has more distinct screens than the screendex can refer to.
+    TooManyScreens(TooManyScreens),
+    /// The sprites of this distinct screen and the ones after it are further
+    /// into the sprite data than the sprite offset table can point.
+    TooManySprites(usize),
+}
+
+impl From<TooManyScreens> for EncodeError {
+    fn from(e: TooManyScreens) -> EncodeError {
+        EncodeError::TooManyScreens(e)
+    }
+}
 
 impl fmt::Display for EncodeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "error encoding binary level")
+        match *self {
+            EncodeError::TooManyScreens(ref e) =>
+                write!(f, "error encoding binary level: {}", e),
+            EncodeError::TooManySprites(scr) =>
+                write!(f, "error encoding binary level: too many sprites; the ones from screen {:02x} on \
+                    are past what the sprite offset table can point to", scr),
+        }
     }
 }
 
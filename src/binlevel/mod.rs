@@ -4,9 +4,17 @@ mod errors;
 mod read;
 mod write;
 mod rle;
+mod dump;
+mod checksum;
 
 pub use self::errors::DecodeError;
 pub use self::errors::EncodeError;
 
 pub use self::read::read_level;
-pub use self::write::write_level_body;
+pub use self::write::{write_level_body, LevelBlob};
+pub use self::dump::dump_level;
+
+/// The version of the binary level format that `write_level_body` writes.
+/// This gets stamped into the version table for each inserted level,
+/// so that `read_level` knows how to read it back.
+pub const FORMAT_VERSION: (u8, u8, u8) = (0, 3, 0);
@@ -1,9 +1,514 @@
-use std::io;
-use level::Level;
+use address::{Address, Mapper};
+use level::{self, Level, LevelHeader, Palette, PScrGrid, SharedPal};
+use snes_color::SnesPal;
+use spr::{SprSet, SpritePlacement};
+use entrance::{EntranceId, EntrancePlacement};
 
 use super::DecodeError;
+use super::checksum::{loader_crc, Layout, HEADER_LEN, HOLE_LEN, PAL_LEN, SECTIONS, TABLE_LEN};
+use crc::crc16;
 
-pub fn read_level<R: io::Read>(source: &mut R) -> Result<Level, DecodeError> {
-    let _s = source;
-    unimplemented!();
+/// Reads a level that was written in format `version`,
+/// with its pointer hole at `start`.
+///
+/// `(0, 0, 0)` is what the version table says about levels that were
+/// inserted before it existed; those are read as 0.1.0, which is
+/// the format they were actually written in.
+/// 0.2.0 is laid out the same as 0.1.0, with checksums added after the header.
+/// 0.3.0 adds the wide screendex flag, and a sprite offset table
+/// that grows past 128 screens.
+pub fn read_level(rom: &[u8], start: Address, map: Mapper, version: (u8, u8, u8))
+-> Result<Level, DecodeError> {
+    let src = Source { rom, map };
+    match version {
+        (0, 0, 0) | (0, 1, 0) => read_level_v0_1(&src, start),
+        (0, 2, 0) | (0, 3, 0) => {
+            verify_checksums(&src, start)?;
+            read_level_v0_1(&src, start)
+        },
+        (a, b, c) => Err(DecodeError::UnknownVersion(a, b, c)),
+    }
+}
+
+fn verify_checksums(src: &Source, start: Address) -> Result<(), DecodeError> {
+    let sprites = src.ptr(start, "pointer hole")?;
+    let header = src.ptr(src.after(start, 3, "pointer hole")?, "pointer hole")?;
+    let (_, ptrs) = read_header(src, header)?;
+    let ofs = |at: Address, name: &'static str| at.offset_from(start).map(|o| o as u32)
+        .ok_or_else(|| DecodeError::Malformed(name, "it's before the pointer hole".into()));
+    let layout = Layout {
+        dex: ofs(ptrs.dex, "screendex")?,
+        sprites: ofs(sprites, "sprites")?,
+        pal: match ptrs.pal {
+            Some(p) => Some(ofs(p, "palette")?),
+            None => None,
+        },
+        entrances: ofs(ptrs.entrances, "entrances")?,
+        exits: ofs(ptrs.exits, "exits")?,
+        header: ofs(header, "header")?,
+    };
+
+    let table = src.span(start, layout.table(), layout.table() + TABLE_LEN, "checksum table")?;
+    for (i, (&(from, to), &name)) in layout.ranges().iter().zip(SECTIONS.iter()).enumerate() {
+        let stored = table[i * 2] as u16 | (table[i * 2 + 1] as u16) << 8;
+        if crc16(src.span(start, from, to, name)?) != stored {
+            return Err(DecodeError::Checksum(name));
+        }
+    }
+
+    let hole = src.span(start, HOLE_LEN - 2, HOLE_LEN, "pointer hole")?;
+    let stored = hole[0] as u16 | (hole[1] as u16) << 8;
+    let (from, to) = layout.body();
+    if stored != 0 && loader_crc(src.span(start, from, to, "level")?) != stored {
+        return Err(DecodeError::Checksum("pointer hole"));
+    }
+    Ok(())
+}
+
+struct Source<'r> {
+    rom: &'r [u8],
+    map: Mapper,
+}
+
+impl<'r> Source<'r> {
+    fn bytes(&self, at: Address, len: usize, section: &'static str) -> Result<&'r [u8], DecodeError> {
+        let rom = self.rom;
+        at.range(len)
+            .and_then(|r| rom.get(r.start().pc_ofs() .. r.start().pc_ofs() + r.len()))
+            .ok_or(DecodeError::Truncated(section))
+    }
+
+    /// The bytes from offset `from` to offset `to` of the level at `start`.
+    fn span(&self, start: Address, from: u32, to: u32, section: &'static str)
+    -> Result<&'r [u8], DecodeError> {
+        if to < from {
+            return Err(DecodeError::Malformed(section, "it ends before it starts".into()));
+        }
+        self.bytes(self.after(start, from as usize, section)?, (to - from) as usize, section)
+    }
+
+    fn after(&self, at: Address, n: usize, section: &'static str) -> Result<Address, DecodeError> {
+        at.checked_add(n).ok_or(DecodeError::Truncated(section))
+    }
+
+    /// Where `snes` is in the ROM.
+    fn at(&self, snes: u32, section: &'static str) -> Result<Address, DecodeError> {
+        Address::new_from_snes(snes as usize, self.map).ok_or(DecodeError::Truncated(section))
+    }
+
+    /// Where the pointer at `at` points.
+    fn ptr(&self, at: Address, section: &'static str) -> Result<Address, DecodeError> {
+        self.at(read_long(self.bytes(at, 3, section)?), section)
+    }
+}
+
+fn read_level_v0_1(src: &Source, start: Address) -> Result<Level, DecodeError> {
+    let sprites = src.ptr(start, "pointer hole")?;
+    let header = src.ptr(src.after(start, 3, "pointer hole")?, "pointer hole")?;
+
+    let (hed, ptrs) = read_header(src, header)?;
+    if ptrs.width != 32 || ptrs.height != 32 {
+        return Err(DecodeError::Malformed(
+            "header", format!("level is {}x{} screens, only 32x32 is supported", ptrs.width, ptrs.height)
+        ));
+    }
+
+    let screens = read_screens(src, src.after(start, HOLE_LEN as usize, "screen data")?)?;
+    let dex = read_dex(src, &ptrs)?;
+    if let Some(&(e, _)) = dex.iter().find(|&&(e, _)| e as usize >= screens.len()) {
+        return Err(DecodeError::Malformed(
+            "screendex", format!("uses screen {:02x}, but there are only {} screens", e, screens.len())
+        ));
+    }
+    let scr_sprites = read_sprites(src, sprites, screens.len())?;
+    let entrances = read_entrances(src, ptrs.entrances, ptrs.exits)?;
+    let exits = read_exits(src, ptrs.exits, screens.len())?;
+
+    let (fg_dex, bg_dex) = dex.split_at(32 * 32);
+    let mut fg = PScrGrid::new(32, 32);
+    let mut bg = PScrGrid::new(32, 32);
+    let mut sprs = SprSet::new();
+    for (i, (&(f, _), &(b, _))) in fg_dex.iter().zip(bg_dex.iter()).enumerate() {
+        let (x, y) = (i % 32, i / 32);
+        let (f, b) = (f as usize, b as usize);
+
+        let scr = fg.screen_at_mut(x, y);
+        scr.tiles.copy_from_slice(&screens[f]);
+        scr.exit = exits[f];
+        for spr in &scr_sprites[f] {
+            let mut spr = *spr;
+            spr.pos_x += x as u16 * 16;
+            spr.pos_y += y as u16 * 16;
+            sprs.insert(spr);
+        }
+
+        bg.screen_at_mut(x, y).tiles.copy_from_slice(&screens[b]);
+    }
+    level::place_sprites(&mut fg, &sprs);
+
+    let sf = fg_dex.iter().map(|&(_, s)| s).collect();
+
+    check_entrance_counts(&entrances)?;
+    Ok(Level::from_parts(fg, bg, sf, entrances, hed))
+}
+
+/// `Level::from_parts` panics on more entrances than a level can have,
+/// so a ROM that says there are more has to be caught first.
+fn check_entrance_counts(entrances: &[EntrancePlacement]) -> Result<(), DecodeError> {
+    let primaries = entrances.iter().filter(|en| !en.id.secondary).count();
+    let secondaries = entrances.len() - primaries;
+    if primaries > 2 || secondaries > 32 {
+        return Err(DecodeError::Malformed(
+            "entrances", format!("{} primary and {} secondary entrances (max is 2 and 32)", primaries, secondaries)
+        ));
+    }
+    Ok(())
+}
+
+struct HeaderPtrs {
+    dex: Address,
+    exits: Address,
+    entrances: Address,
+    pal: Option<Address>,
+    width: u8,
+    height: u8,
+    wide_dex: bool,
+}
+
+fn read_header(src: &Source, at: Address) -> Result<(LevelHeader, HeaderPtrs), DecodeError> {
+    let b = src.bytes(at, HEADER_LEN as usize, "header")?;
+
+    let pal = if b[9] & 1 == 1 { Some(src.at(read_long(&b[9 ..]) & !1, "palette")?) } else { None };
+    let palette = if let Some(p) = pal {
+        let pal_bytes = src.bytes(p, PAL_LEN as usize, "palette")?;
+        Palette::Custom(SnesPal::from_binary_snes(pal_bytes).unwrap())
+    } else {
+        Palette::Shared(SharedPal {
+            fg: b[11] >> 3,
+            bg: b[11] & 7,
+            sp: b[10] & 7,
+            sky: b[10] >> 3,
+        })
+    };
+
+    let hed = LevelHeader {
+        palette,
+        mode: b[8] & 0x1f,
+        l3_img: b[8] >> 5 & 3,
+        audio_track: b[12],
+        tileset_fg: b[13] & 0xf,
+        tileset_sp: b[13] >> 4,
+        time: b[14] >> 4,
+        l3_prio: b[14] & 8 != 0,
+        scroll: b[14] & 7,
+    };
+
+    let ptrs = HeaderPtrs {
+        dex: src.at(read_long(&b[0 ..]), "screendex")?,
+        exits: src.at(read_long(&b[3 ..]), "exits")?,
+        width: b[6],
+        height: b[7],
+        wide_dex: b[8] & 0x80 != 0,
+        entrances: src.at(read_long(&b[15 ..]), "entrances")?,
+        pal,
+    };
+
+    Ok((hed, ptrs))
+}
+
+/// Reads the FG and BG screendex, as (screen, scroll filter) pairs.
+fn read_dex(src: &Source, ptrs: &HeaderPtrs) -> Result<Vec<(u16, bool)>, DecodeError> {
+    let count = ptrs.width as usize * ptrs.height as usize * 2;
+    if ptrs.wide_dex {
+        let bytes = src.bytes(ptrs.dex, count * 2, "screendex")?;
+        Ok(bytes.chunks(2).map(|e| {
+            let e = e[0] as u16 | (e[1] as u16) << 8;
+            (e & 0x7fff, e & 0x8000 != 0)
+        }).collect())
+    } else {
+        let bytes = src.bytes(ptrs.dex, count, "screendex")?;
+        Ok(bytes.iter().map(|&e| ((e & 0x7f) as u16, e & 0x80 != 0)).collect())
+    }
+}
+
+/// How long the sprite offset table is for a level with `screens` screens.
+pub(super) fn sprite_table_len(screens: usize) -> u32 {
+    ::std::cmp::max(0x100, screens as u32 * 2)
+}
+
+/// The most screens a level can use: one for every FG and BG spot on a 32x32 level.
+const MAX_SCREENS: usize = 32 * 32 * 2;
+
+fn read_screens(src: &Source, at: Address) -> Result<Vec<[u16; 256]>, DecodeError> {
+    let mut bytes = Vec::new();
+    let mut p = at;
+    loop {
+        let l = src.bytes(p, 2, "screen data")?;
+        let len = l[0] as usize | (l[1] as usize) << 8;
+        if len == 0 {
+            break;
+        }
+        let val = src.bytes(src.after(p, 2, "screen data")?, 1, "screen data")?[0];
+        bytes.extend(::std::iter::repeat_n(val, len));
+        if bytes.len() > MAX_SCREENS * 0x200 {
+            return Err(DecodeError::Malformed(
+                "screen data", format!("it's more than {} screens", MAX_SCREENS)
+            ));
+        }
+        p = src.after(p, 3, "screen data")?;
+    }
+
+    if bytes.len() % 0x200 != 0 {
+        return Err(DecodeError::Malformed(
+            "screen data", format!("{} bytes is not a whole number of screens", bytes.len())
+        ));
+    }
+
+    Ok(bytes.chunks(0x200).map(|scr| {
+        let mut tiles = [0; 256];
+        for (i, t) in tiles.iter_mut().enumerate() {
+            *t = scr[i] as u16 | (scr[i + 256] as u16) << 8;
+        }
+        tiles
+    }).collect())
+}
+
+/// Reads the sprites of each screen, with positions local to the screen.
+fn read_sprites(src: &Source, at: Address, screens: usize) -> Result<Vec<Vec<SpritePlacement>>, DecodeError> {
+    let table_len = sprite_table_len(screens) as usize;
+    let table = src.bytes(src.after(at, 1, "sprite offset table")?, table_len, "sprite offset table")?;
+    let mut p = src.after(at, 1 + table_len + 4, "sprites")?;
+
+    // The offsets in the table are truncated when long sprites are involved,
+    // so the records are read in order instead of by offset.
+    let mut sprs = vec![Vec::new(); screens];
+    for (scr, e) in sprs.iter_mut().zip(table.chunks(2)) {
+        if e == [0, 0] {
+            continue;
+        }
+        loop {
+            let r = src.bytes(p, 4, "sprites")?;
+            p = src.after(p, 4, "sprites")?;
+            if r[0] & 0x80 != 0 {
+                break;
+            }
+            let mut spr = unpack_sprite(r);
+            if r[0] & 2 != 0 {
+                spr.xbytes[1 ..].copy_from_slice(src.bytes(p, 3, "sprites")?);
+                p = src.after(p, 4, "sprites")?;
+            }
+            scr.push(spr);
+        }
+    }
+    Ok(sprs)
+}
+
+fn read_entrances(src: &Source, at: Address, end: Address) -> Result<Vec<EntrancePlacement>, DecodeError> {
+    let primaries = src.bytes(at, 1, "entrances")?[0] as usize;
+    let len = match end.offset_from(at) {
+        Some(len) if len >= 1 && (len - 1).is_multiple_of(6) => len,
+        _ => return Err(DecodeError::Malformed("entrances", "records don't end where the exits begin".into())),
+    };
+    let count = (len - 1) / 6;
+    if count < primaries {
+        return Err(DecodeError::Malformed(
+            "entrances", format!("{} primary and {} total entrances", primaries, count)
+        ));
+    }
+
+    let recs = src.bytes(src.after(at, 1, "entrances")?, count * 6, "entrances")?;
+    recs.chunks(6).enumerate().map(|(i, e)| {
+        let en = unpack_entrance(e);
+        // Entrances are stored in order, so their index is their ID.
+        let id = if i < primaries {
+            EntranceId::from_parts(en.levelnum, i as u8, false)
+        } else {
+            EntranceId::from_parts(en.levelnum, (i - primaries) as u8, true)
+        }.ok_or_else(|| DecodeError::Malformed("entrances", format!("entrance {} has no valid ID", i)))?;
+        Ok(EntrancePlacement::new(id, en.x, en.y, en.anim, en.slippery, en.water))
+    }).collect()
+}
+
+fn read_exits(src: &Source, at: Address, screens: usize) -> Result<Vec<EntranceId>, DecodeError> {
+    let bytes = src.bytes(at, screens * 3, "exits")?;
+    bytes.chunks(3).map(|e| {
+        let levelnum = e[0] as u16 | (e[1] as u16) << 8;
+        EntranceId::from_parts(levelnum, e[2] & 0x7f, e[2] & 0x80 != 0).ok_or_else(|| {
+            DecodeError::Malformed("exits", format!("{:02x} {:02x} {:02x} is not an entrance", e[0], e[1], e[2]))
+        })
+    }).collect()
+}
+
+pub(super) struct EntranceFields {
+    pub levelnum: u16,
+    pub anim: u8,
+    pub x: u16,
+    pub y: u16,
+    pub bgofs: u16,
+    pub intro: bool,
+    pub three: u8,
+    pub scroll: u8,
+    pub water: bool,
+    pub slippery: bool,
+}
+
+/// Unpacks a 6-byte entrance record; the inverse of `write::write_entrance`.
+pub(super) fn unpack_entrance(e: &[u8]) -> EntranceFields {
+    EntranceFields {
+        levelnum: e[0] as u16 | (e[1] as u16 & 1) << 8,
+        anim: e[1] >> 1 & 7,
+        x: (e[1] >> 4) as u16 | (e[2] as u16 & 0x1f) << 4,
+        bgofs: (e[2] >> 5) as u16 | (e[3] as u16 & 0xf) << 3 | (e[5] as u16 & 0x30) << 3,
+        y: (e[3] >> 4) as u16 | (e[4] as u16 & 0x1f) << 4,
+        intro: e[4] & 0x20 != 0,
+        three: e[4] >> 6,
+        scroll: e[5] & 0xf,
+        water: e[5] & 0x40 != 0,
+        slippery: e[5] & 0x80 != 0,
+    }
+}
+
+/// Unpacks the first 4 bytes of a sprite record, with its position local to its screen.
+/// The other extra bytes of long sprites are in the 4 bytes after this.
+pub(super) fn unpack_sprite(r: &[u8]) -> SpritePlacement {
+    SpritePlacement::new(
+        (r[0] as u16 & 0x18) << 5 | r[1] as u16,
+        (r[3] >> 4) as u16,
+        (r[2] >> 4) as u16,
+        r[0] & 4 != 0,
+        [(r[2] & 0xf) | (r[3] & 0xf) << 4, 0, 0, 0],
+    )
+}
+
+pub(super) fn read_long(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use level::LevelHeader;
+    use super::super::write_level_body;
+
+    /// A level with a couple of different screens, a sprite, an entrance
+    /// and a header that isn't the default one.
+    fn some_level() -> Level {
+        let mut fg = PScrGrid::new(32, 32);
+        fg.screen_at_mut(1, 0).tiles[0x21] = 0x130;
+        fg.screen_at_mut(1, 0).sprites.insert(SpritePlacement::new(0x10, 17, 3, true, [0x5a, 0, 0, 0]));
+        let mut bg = PScrGrid::new(32, 32);
+        bg.screen_at_mut(0, 1).tiles[0xff] = 0x25;
+        let id = EntranceId::from_parts(0x105, 0, false).unwrap();
+        let entrances = vec![EntrancePlacement::new(id, 0x18, 0x1a0, 2, true, false)];
+        let header = LevelHeader {
+            audio_track: 5,
+            time: 4,
+            scroll: 1,
+            palette: Palette::Shared(SharedPal {fg: 3, bg: 4, sp: 5, sky: 6}),
+            ..LevelHeader::default()
+        };
+        Level::from_parts(fg, bg, vec![false; 32 * 32], entrances, header)
+    }
+
+    /// Writes `level` into an otherwise empty LoROM ROM, returning the ROM and where it starts.
+    fn written(level: &Level) -> (Vec<u8>, Address) {
+        let start = Address::new_from_pc(0x8_000c, Mapper::Lorom).unwrap();
+        let bytes = write_level_body(level).unwrap().relocate(start.snes_ofs().unwrap());
+        let mut rom = vec![0; 0x10_0000];
+        rom[start.pc_ofs() .. start.pc_ofs() + bytes.len()].copy_from_slice(&bytes);
+        (rom, start)
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let (rom, start) = written(&some_level());
+        let lvl = read_level(&rom, start, Mapper::Lorom, (0, 1, 0)).unwrap();
+        assert!(lvl.entrances == some_level().entrances);
+        assert_eq!(lvl.header.audio_track, 5);
+        // writing what was read gives back the same bytes, sprites and all
+        let (again, _) = written(&lvl);
+        assert!(again == rom);
+        // and levels from before the version table are read the same way
+        assert!(read_level(&rom, start, Mapper::Lorom, (0, 0, 0)).is_ok());
+    }
+
+    #[test]
+    fn unknown_versions() {
+        let (rom, start) = written(&some_level());
+        match read_level(&rom, start, Mapper::Lorom, (1, 0, 0)) {
+            Err(DecodeError::UnknownVersion(1, 0, 0)) => (),
+            _ => panic!("read a level in a format from the future"),
+        }
+    }
+
+    #[test]
+    fn more_primary_entrances_than_entrances() {
+        let (mut rom, start) = written(&some_level());
+        let header = read_long(&rom[start.pc_ofs() + 3 ..]) as usize;
+        let header = Address::new_from_snes(header, Mapper::Lorom).unwrap().pc_ofs();
+        let entrances = read_long(&rom[header + 15 ..]) as usize;
+        rom[Address::new_from_snes(entrances, Mapper::Lorom).unwrap().pc_ofs()] = 3;
+        match read_level(&rom, start, Mapper::Lorom, (0, 1, 0)) {
+            Err(DecodeError::Malformed("entrances", _)) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("read 3 primary entrances out of 1"),
+        }
+    }
+
+    #[test]
+    fn screen_data_that_never_ends() {
+        let (mut rom, start) = written(&some_level());
+        let at = start.pc_ofs() + 8;
+        for run in rom[at .. at + 3 * 20].chunks_mut(3) {
+            run.copy_from_slice(&[0xff, 0xff, 0]);
+        }
+        match read_level(&rom, start, Mapper::Lorom, (0, 1, 0)) {
+            Err(DecodeError::Malformed("screen data", _)) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("read more screens than a level can have"),
+        }
+    }
+
+    #[test]
+    fn checksums_catch_overwritten_sections() {
+        let (mut rom, start) = written(&some_level());
+        assert!(read_level(&rom, start, Mapper::Lorom, (0, 2, 0)).is_ok());
+        rom[start.pc_ofs() + 8] ^= 1;
+        match read_level(&rom, start, Mapper::Lorom, (0, 2, 0)) {
+            Err(DecodeError::Checksum("screens")) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("read a level with overwritten screens"),
+        }
+    }
+
+    #[test]
+    fn loader_checksum() {
+        let start = Address::new_from_pc(0x8_000c, Mapper::Lorom).unwrap();
+        let mut blob = write_level_body(&some_level()).unwrap();
+        blob.loader_crc = true;
+        let bytes = blob.relocate(start.snes_ofs().unwrap());
+        assert!(bytes[6 .. 8] != [0, 0]);
+        let mut rom = vec![0; 0x10_0000];
+        rom[start.pc_ofs() .. start.pc_ofs() + bytes.len()].copy_from_slice(&bytes);
+        assert!(read_level(&rom, start, Mapper::Lorom, (0, 2, 0)).is_ok());
+
+        rom[start.pc_ofs() + 6] ^= 1;
+        match read_level(&rom, start, Mapper::Lorom, (0, 2, 0)) {
+            Err(DecodeError::Checksum("pointer hole")) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("read a level whose loader checksum doesn't match"),
+        }
+    }
+
+    #[test]
+    fn wide_screendex_reads_back() {
+        let mut fg = PScrGrid::new(32, 32);
+        for i in 0 .. 300 {
+            fg.screen_at_mut(i % 32, i / 32).tiles[i % 256] = i as u16;
+        }
+        let lvl = Level::from_parts(fg, PScrGrid::new(32, 32), vec![false; 32 * 32], Vec::new(), LevelHeader::default());
+        let (rom, start) = written(&lvl);
+        let back = read_level(&rom, start, Mapper::Lorom, (0, 3, 0)).unwrap();
+        assert!(back.tile_bytes() == lvl.tile_bytes());
+    }
 }
@@ -1,88 +1,180 @@
 #![allow(dead_code, unused_variables)]
-use std::io::{self, SeekFrom};
 use level::{Level, ScreenDex, Palette};
+use entrance::EntrancePlacement;
 
 use super::EncodeError;
 use super::rle;
+use super::read::{read_long, sprite_table_len};
+use super::checksum::{loader_crc, Layout, TABLE_LEN};
+use crc::crc16;
 
 // This module is not optimized.
 
-pub fn write_level_body<W: io::Write + io::Seek>(
-    dest: &mut W,
-    level: &Level,
-    base_addr: u32,
-) -> Result<u32, EncodeError> {
-    let ref screendex = ScreenDex::from_level(level);
-    // we need to skip 8 bytes as a "hole" for pointers
-    let ptr_loc = dest.seek(SeekFrom::Current(0)).unwrap();
-    dest.seek(SeekFrom::Current(8)).unwrap();
-    
-    let screens = base_addr + 8;
-    let dex = screens + write_screens(dest, screendex)?;
-    let sprites = dex + write_dex(dest, screendex)?;
-    let pal = {
-        // add a padding byte if the palette is unaligned
-        let end_of_sprites = sprites + write_sprites(dest, screendex)?;
-        if end_of_sprites & 1 == 1 {
-            dest.write_all(&[0]).unwrap();
-            end_of_sprites + 1
-        } else {
-            end_of_sprites
+/// A level encoded as though it starts at SNES address 0.
+///
+/// Every pointer inside the level is relative to its start, so it has to be
+/// relocated with `relocate` once the level's real address is known.
+/// Since nothing gets added to the bank byte separately, the level has to be
+/// put somewhere it doesn't cross a bank boundary, and at an even address
+/// (the low bit of the palette pointer is a flag).
+pub struct LevelBlob {
+    bytes: Vec<u8>,
+    relocs: Vec<usize>,
+    layout: Layout,
+    /// Whether to put a CRC of the level in the pointer hole,
+    /// for the game to check when it loads the level.
+    pub loader_crc: bool,
+}
+
+impl LevelBlob {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn relocate(&self, base: u32) -> Vec<u8> {
+        assert!(base & 1 == 0, "tried to relocate a level to an odd address");
+        let mut v = self.bytes.clone();
+        for &ofs in &self.relocs {
+            let ptr = read_long(&v[ofs ..]) + base;
+            v[ofs .. ofs + 3].copy_from_slice(&[ptr as u8, (ptr >> 8) as u8, (ptr >> 16) as u8]);
+        }
+
+        // The checksums have to be done last, since they cover the pointers.
+        let table = self.layout.table() as usize;
+        for (i, &(start, end)) in self.layout.ranges().iter().enumerate() {
+            let crc = crc16(&v[start as usize .. end as usize]);
+            v[table + i * 2] = crc as u8;
+            v[table + i * 2 + 1] = (crc >> 8) as u8;
         }
+        if self.loader_crc {
+            let (start, end) = self.layout.body();
+            let crc = loader_crc(&v[start as usize .. end as usize]);
+            v[6] = crc as u8;
+            v[7] = (crc >> 8) as u8;
+        }
+        v
+    }
+}
+
+/// Where a level is being written to, and which parts of it are pointers.
+struct Body {
+    bytes: Vec<u8>,
+    relocs: Vec<usize>,
+}
+
+impl Body {
+    fn pos(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    fn write_all(&mut self, b: &[u8]) {
+        self.bytes.extend_from_slice(b);
+    }
+
+    fn write_ptr(&mut self, v: u32) {
+        let ofs = self.bytes.len();
+        self.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8]);
+        self.relocs.push(ofs);
+    }
+
+    fn patch_ptr(&mut self, ofs: usize, v: u32) {
+        self.bytes[ofs .. ofs + 3].copy_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8]);
+        self.relocs.push(ofs);
+    }
+}
+
+pub fn write_level_body(level: &Level) -> Result<LevelBlob, EncodeError> {
+    let screendex = &ScreenDex::from_level(level)?;
+    let mut body = Body { bytes: Vec::with_capacity(0x2000), relocs: Vec::new() };
+    let dest = &mut body;
+
+    // we need to skip 8 bytes as a "hole" for pointers
+    dest.write_all(&[0; 8]);
+
+    // the screens come right after the hole
+    write_screens(dest, screendex)?;
+    let dex = dest.pos();
+    write_dex(dest, screendex)?;
+    let sprites = dest.pos();
+    write_sprites(dest, screendex)?;
+    // add a padding byte if the palette is unaligned
+    if dest.pos() & 1 == 1 {
+        dest.write_all(&[0]);
+    }
+    let pal = dest.pos();
+    write_pal(dest, level)?;
+    let entrances = dest.pos();
+    write_entrances(dest, level)?;
+    let exits = dest.pos();
+    write_exits(dest, screendex)?;
+    let header = dest.pos();
+    write_header(dest, level, screendex.is_wide(), dex, pal, entrances, exits)?;
+    // the checksum table is filled in by `LevelBlob::relocate`
+    dest.write_all(&[0; TABLE_LEN as usize]);
+
+    dest.patch_ptr(0, sprites);
+    dest.patch_ptr(3, header);
+
+    let layout = Layout {
+        dex,
+        sprites,
+        pal: match level.header.palette {
+            Palette::Custom(_) => Some(pal),
+            Palette::Shared(_) => None,
+        },
+        entrances,
+        exits,
+        header,
     };
-    let entrances = pal + write_pal(dest, level)?;
-    let exits = entrances + write_entrances(dest, level)?;
-    let header = exits + write_exits(dest, screendex)?;
-    let end = header + write_header(dest, level, dex, pal, entrances, exits)?;
-    
-    print!("start: {:06x}\nscreens: {:06x}\ndex: {:06x}\nsprites: {:06x}\npal: {:06x}\nentrances: {:06x}\nexits: {:06x}\nheader: {:06x}\nend: {:06x}\n",
-        base_addr, screens, dex, sprites, pal, entrances, exits, header, end
-    );
-    
-    dest.seek(SeekFrom::Start(ptr_loc)).unwrap();
-    write_long(dest, sprites)?;
-    write_long(dest, header)?;
-    
-    Ok(end - base_addr)
+
+    Ok(LevelBlob { bytes: body.bytes, relocs: body.relocs, layout, loader_crc: false })
 }
 
-fn write_dex<W: io::Write>(dest: &mut W, dex: &ScreenDex) -> Result<u32, EncodeError> {
-    let bytes = dex.dex_bytes();
-    dest.write_all(&bytes).unwrap();
-    Ok(bytes.len() as u32)
+fn write_dex(dest: &mut Body, dex: &ScreenDex) -> Result<(), EncodeError> {
+    dest.write_all(&dex.dex_bytes());
+    Ok(())
 }
 
-fn write_screens<W: io::Write>(dest: &mut W, dex: &ScreenDex) -> Result<u32, EncodeError> {
+fn write_screens(dest: &mut Body, dex: &ScreenDex) -> Result<(), EncodeError> {
     let tile_bytes = dex.tile_bytes();
-    let mut buf: [u8; 3];
-    let mut length = 0;
     // this could plausibly be split off into a function,
     // but would require a lot of intermediate types for iterators.
     for run in rle::make_runs(tile_bytes.iter().map(|v| *v)) {
-        buf = [run.length as u8, (run.length >> 8) as u8, run.val];
-        dest.write_all(&buf).unwrap(); // this is not great
-        length += buf.len() as u32;
+        dest.write_all(&[run.length as u8, (run.length >> 8) as u8, run.val]);
     }
-    dest.write_all(&[0, 0]).unwrap();
-    length += 2;
-    Ok(length)
+    dest.write_all(&[0, 0]);
+    Ok(())
 }
 
-fn write_sprites<W: io::Write>(dest: &mut W, dex: &ScreenDex) -> Result<u32, EncodeError> {
+/// The most 4-byte sprite records that can come before a screen's,
+/// since the sprite offset table stores twice that count in a byte.
+const MAX_SPRITE_RECORDS: usize = 0x7f;
+/// The most sprites that can come before a screen's in the sprite offset table.
+const MAX_SPRITE_INDEX: usize = 0xff;
+
+fn write_sprites(dest: &mut Body, dex: &ScreenDex) -> Result<(), EncodeError> {
     let terminator = &::spr::SpritePlacement::TERMINATOR_BYTES;
-    // This size guess is precisely the maximum.
-    let mut spriteofs = Vec::with_capacity(0x100);
+    let table_len = sprite_table_len(dex.screens.len()) as usize;
+    let mut spriteofs = Vec::with_capacity(table_len);
     // This size guess is enough for exactly one sprite per screen.
     let mut spritebin = Vec::with_capacity(dex.screens.len() * 4);
     spritebin.extend_from_slice(terminator);
 
     let mut spriteind = 0;
-    for scr in dex.screens.iter() {
-        if scr.sprites.len() == 0 {
+    for (i, scr) in dex.screens.iter().enumerate() {
+        if scr.sprites.is_empty() {
             spriteofs.extend_from_slice(&[0, 0]);
         } else {
-            spriteofs.push(((spritebin.len() / 4) as u8) << 1);
-            spriteofs.push(spriteind);
+            let records = spritebin.len() / 4;
+            if records > MAX_SPRITE_RECORDS || spriteind > MAX_SPRITE_INDEX {
+                return Err(EncodeError::TooManySprites(i));
+            }
+            spriteofs.push((records as u8) << 1);
+            spriteofs.push(spriteind as u8);
             for spr in scr.sprites.iter() {
                 spritebin.extend(&spr.to_bytes());
                 spriteind += 1;
@@ -91,116 +183,219 @@ fn write_sprites<W: io::Write>(dest: &mut W, dex: &ScreenDex) -> Result<u32, Enc
         }
     }
 
-    spriteofs.resize(0x100, 0);
+    spriteofs.resize(table_len, 0);
 
-    dest.write_all(&[0]).unwrap();
-    dest.write_all(spriteofs.as_slice()).unwrap();
-    dest.write_all(spritebin.as_slice()).unwrap();
-    Ok(spriteofs.len() as u32 + spritebin.len() as u32 + 1)
+    dest.write_all(&[0]);
+    dest.write_all(spriteofs.as_slice());
+    dest.write_all(spritebin.as_slice());
+    Ok(())
 }
 
-fn write_pal<W: io::Write>(dest: &mut W, level: &Level) -> Result<u32, EncodeError> {
+fn write_pal(dest: &mut Body, level: &Level) -> Result<(), EncodeError> {
     if let Palette::Custom(ref pal) = level.header.palette {
         for color in pal.iter() {
             let w = color.to_snes();
-            let b = [w as u8, (w >> 8) as u8];
-            dest.write_all(&b).unwrap();
+            dest.write_all(&[w as u8, (w >> 8) as u8]);
         }
-        Ok(0x202)
-    } else {
-        Ok(0)
     }
+    Ok(())
 }
 
-fn write_entrances<W: io::Write>(dest: &mut W, level: &Level) -> Result<u32, EncodeError> {
+fn write_entrances(dest: &mut Body, level: &Level) -> Result<(), EncodeError> {
     let ens = {
         let mut ens = level.entrances.clone();
         ens.sort();
         ens
     };
     let primaries = ens.iter().filter(|en| !en.id.secondary).count();
-    dest.write_all(&[primaries as u8]).unwrap();
-    let mut len = 1;
+    dest.write_all(&[primaries as u8]);
     
-    for en in ens {
-        len += write_entrance(
-            dest,
-            en.id.levelnum,
-            en.pos_x, en.pos_y,
-            en.anim,
-            0, // bgofs
-            0, // scroll
-            3, // three
-            false, // intro
-            en.water, en.slippery
-        )?;
+    for en in &ens {
+        write_entrance(dest, en)?;
     }
     
-    Ok(len)
+    Ok(())
 }
 
-fn write_entrance<W: io::Write>(
-    dest: &mut W,
-    lvlnum: u16,
-    x: u16, y: u16,
-    anim: u8,
-    bgofs: u16,
-    scroll: u8,
-    three: u8,
-    intro: bool,
-    wet: bool, slip: bool,
-) -> Result<u32, EncodeError> {
+fn write_entrance(dest: &mut Body, en: &EntrancePlacement) -> Result<(), EncodeError> {
+    // TMX maps can't set these yet, so every entrance gets the same ones
+    let bgofs: u16 = 0;
+    let scroll: u8 = 0;
+    let three: u8 = 3;
+    let intro = false;
+
+    let (lvlnum, x, y, anim) = (en.id.levelnum, en.pos_x, en.pos_y, en.anim);
     dest.write_all(&[
         lvlnum as u8,
         ((lvlnum >> 8) as u8) | (anim << 1) | ((x << 4) as u8),
         ((x >> 4) as u8) | ((bgofs << 5) as u8),
         ((bgofs >> 3 & 0xf) as u8) | ((y << 4) as u8),
         ((y >> 4) as u8) | ((intro as u8) << 5) | (three << 6),
-        (scroll as u8) | ((bgofs >> 3 & 0x30) as u8) | ((wet as u8) << 6) | ((slip as u8) << 7),
-    ]).unwrap();
-    Ok(6)
+        scroll | ((bgofs >> 3 & 0x30) as u8) | ((en.water as u8) << 6) | ((en.slippery as u8) << 7),
+    ]);
+    Ok(())
 }
 
-fn write_exits<W: io::Write>(dest: &mut W, dex: &ScreenDex) -> Result<u32, EncodeError> {
-    for (i,scr) in dex.screens.iter().enumerate() {
-        println!("{:02x} -> {}", i, scr.exit);
-        dest.write_all(&scr.exit.to_bytes()).unwrap();
+fn write_exits(dest: &mut Body, dex: &ScreenDex) -> Result<(), EncodeError> {
+    for scr in dex.screens.iter() {
+        dest.write_all(&scr.exit.to_bytes());
     }
-    Ok((dex.screens.len() as u32) * 3)
+    Ok(())
 }
 
-fn write_header<W: io::Write>(
-    dest: &mut W,
+fn write_header(
+    dest: &mut Body,
     level: &Level,
+    wide_dex: bool,
     dex_addr: u32,
     pal_addr: u32,
     entrance_addr: u32,
     exit_addr: u32,
-) -> Result<u32, EncodeError> {
-    write_long(dest, dex_addr)?;
-    write_long(dest, exit_addr)?;
+) -> Result<(), EncodeError> {
+    dest.write_ptr(dex_addr);
+    dest.write_ptr(exit_addr);
     dest.write_all(&[
         32, // width
         32, // height
-        (level.header.l3_img << 5) | level.header.mode,
-    ]).unwrap();
+        // the top bit says the screendex has 16-bit entries
+        ((wide_dex as u8) << 7) | (level.header.l3_img << 5) | level.header.mode,
+    ]);
     if let Palette::Shared(p) = level.header.palette {
-        dest.write_all(&[0, p.sp | (p.sky << 3), p.bg | (p.fg << 3)]).unwrap()
+        dest.write_all(&[0, p.sp | (p.sky << 3), p.bg | (p.fg << 3)]);
     } else {
-        write_long(dest, pal_addr | 1)?;
+        dest.write_ptr(pal_addr | 1);
     }
     dest.write_all(&[
         level.header.audio_track,
         (level.header.tileset_sp << 4) | level.header.tileset_fg,
         (level.header.time << 4) | ((level.header.l3_prio as u8) << 3) | level.header.scroll as u8,
-    ]).unwrap();
-    write_long(dest, entrance_addr)?;
-    Ok(17)
+    ]);
+    dest.write_ptr(entrance_addr);
+    Ok(())
 }
 
-fn write_long<W: io::Write>(dest: &mut W, v: u32) -> Result<(), EncodeError> {
-    Ok(dest.write_all(
-        &[v as u8, (v >> 8) as u8, (v >> 16) as u8]
-    ).unwrap())
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address::{Address, Mapper};
+    use level::{LevelHeader, PScrGrid};
+    use entrance::EntranceId;
+    use level_table::MARKER;
+    use rats::{self, scan::{MARKER_LEN, TAG_LEN}};
+    use spr::SpritePlacement;
+    use super::super::read::unpack_entrance;
+    use super::super::{dump_level, read_level, DecodeError, FORMAT_VERSION};
+    use super::super::checksum::HOLE_LEN;
+
+    fn empty_level() -> Level {
+        Level::from_parts(
+            PScrGrid::new(32, 32), PScrGrid::new(32, 32), vec![false; 32 * 32], Vec::new(), LevelHeader::default()
+        )
+    }
+
+    #[test]
+    fn relocating_only_moves_pointers() {
+        let blob = write_level_body(&empty_level()).unwrap();
+        let at0 = blob.relocate(0);
+        let moved = blob.relocate(0x90_8000);
+        assert_eq!(at0.len(), blob.len());
+        assert_eq!(moved.len(), blob.len());
+        // the checksums at the end cover the pointers, so they move too
+        let table = blob.len() - TABLE_LEN as usize;
+        for i in 0 .. table {
+            if let Some(&ofs) = blob.relocs.iter().find(|&&ofs| (ofs .. ofs + 3).contains(&i)) {
+                if i == ofs {
+                    assert_eq!(read_long(&moved[ofs ..]), read_long(&at0[ofs ..]) + 0x90_8000);
+                }
+            } else {
+                assert_eq!(moved[i], at0[i], "byte {:x} moved", i);
+            }
+        }
+        // the header pointer in the hole points at the header, just before the checksums
+        assert_eq!(read_long(&at0[3 ..]) as usize + 18, table);
+    }
+
+    #[test]
+    #[should_panic]
+    fn relocating_to_an_odd_address() {
+        write_level_body(&empty_level()).unwrap().relocate(0x90_8001);
+    }
 
+    #[test]
+    fn entrance_records_unpack() {
+        let id = EntranceId::from_parts(0x1e3, 1, true).unwrap();
+        let en = EntrancePlacement::new(id, 0x1f5, 0x1a7, 5, true, false);
+        let mut dest = Body { bytes: Vec::new(), relocs: Vec::new() };
+        write_entrance(&mut dest, &en).unwrap();
+        let f = unpack_entrance(&dest.bytes);
+        assert_eq!((f.levelnum, f.x, f.y, f.anim), (0x1e3, 0x1f5, 0x1a7, 5));
+        assert_eq!((f.slippery, f.water, f.three, f.intro), (true, false, 3, false));
+    }
+
+    /// A level whose first `screens` FG screens are all different,
+    /// and each have a sprite.
+    fn level_with_sprites(screens: usize) -> Level {
+        let mut fg = PScrGrid::new(32, 32);
+        for i in 0 .. screens {
+            let (x, y) = (i % 32, i / 32);
+            let scr = fg.screen_at_mut(x, y);
+            scr.tiles[0] = i as u16;
+            scr.sprites.insert(SpritePlacement::new(1, x as u16 * 16 + 4, y as u16 * 16 + 4, false, [0; 4]));
+        }
+        let bg = PScrGrid::new(32, 32);
+        Level::from_parts(fg, bg, vec![false; 32 * 32], Vec::new(), LevelHeader::default())
+    }
+
+    #[test]
+    fn as_many_sprites_as_the_table_holds() {
+        // every screen's sprite takes a record, and so does its terminator
+        assert!(write_level_body(&level_with_sprites(64)).is_ok());
+        match write_level_body(&level_with_sprites(65)) {
+            Err(EncodeError::TooManySprites(64)) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("65 screens' sprites fit in the sprite offset table"),
+        }
+    }
+
+    #[test]
+    fn more_than_128_screens_with_sprites() {
+        match write_level_body(&level_with_sprites(300)) {
+            Err(EncodeError::TooManySprites(64)) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("300 screens' sprites fit in the sprite offset table"),
+        }
+    }
+
+    #[test]
+    fn reads_back_and_dumps_clean() {
+        let mut blob = write_level_body(&level_with_sprites(3)).unwrap();
+        blob.loader_crc = true;
+        let space = Address::new_from_pc(0x8_0000, Mapper::Lorom).unwrap();
+        let start = space.checked_add(TAG_LEN + MARKER_LEN).unwrap();
+        let bytes = blob.relocate(start.snes_ofs().unwrap());
+        let mut rom = vec![0; 0x10_0000];
+        let mut data = MARKER.to_vec();
+        data.extend(&bytes);
+        rats::insert_at(&mut rom, space, &data);
+
+        let lvl = read_level(&rom, start, Mapper::Lorom, FORMAT_VERSION).unwrap();
+        let mut again = write_level_body(&lvl).unwrap();
+        again.loader_crc = true;
+        assert!(again.relocate(start.snes_ofs().unwrap()) == bytes);
+
+        let mut out = Vec::new();
+        let problems = dump_level(&mut out, &rom, start, Mapper::Lorom, FORMAT_VERSION).unwrap();
+        assert_eq!(problems, 0, "{}", String::from_utf8_lossy(&out));
+
+        // the dump and the reader both notice when a section is overwritten
+        let pc = start.pc_ofs() + HOLE_LEN as usize;
+        rom[pc] ^= 1;
+        match read_level(&rom, start, Mapper::Lorom, FORMAT_VERSION) {
+            Err(DecodeError::Checksum("screens")) => (),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("read a level with a broken checksum"),
+        }
+        let problems = dump_level(&mut Vec::new(), &rom, start, Mapper::Lorom, FORMAT_VERSION).unwrap();
+        assert!(problems > 0);
+    }
+}
@@ -1,9 +1,39 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DcErr {
     LcLzInvalidCommandHeader,
     LcLzPrematureTermination,
     LcLzUndefinedLz2Command,
     LcLzOverlongOutput,
     LcLzOutOfRangeCopy,
+    InflateBadHeader,
+    InflateBadBlockType,
+    InflateBadLength,
+    InflateBadCode,
+    InflatePrematureTermination,
+    InflateOutOfRangeCopy,
+    InflateBadChecksum,
+}
+
+impl fmt::Display for DcErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let s = match *self {
+            DcErr::LcLzInvalidCommandHeader => "invalid LC_LZ command header",
+            DcErr::LcLzPrematureTermination => "LC_LZ data ends in the middle of a command",
+            DcErr::LcLzUndefinedLz2Command => "undefined LC_LZ2 command",
+            DcErr::LcLzOverlongOutput => "LC_LZ data decompresses to more than 64KiB",
+            DcErr::LcLzOutOfRangeCopy => "LC_LZ data copies from outside its output",
+            DcErr::InflateBadHeader => "bad zlib or gzip header",
+            DcErr::InflateBadBlockType => "invalid deflate block type",
+            DcErr::InflateBadLength => "deflate stored block has a mismatched length",
+            DcErr::InflateBadCode => "invalid deflate Huffman code",
+            DcErr::InflatePrematureTermination => "compressed data ends early",
+            DcErr::InflateOutOfRangeCopy => "deflate data copies from before its start",
+            DcErr::InflateBadChecksum => "decompressed data doesn't match its checksum",
+        };
+        write!(f, "{}", s)
+    }
 }
 
 pub enum CErr {
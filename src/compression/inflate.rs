@@ -0,0 +1,439 @@
+//! DEFLATE decompression (RFC 1951), and the zlib (RFC 1950) and gzip (RFC 1952)
+//! wrappers around it, which is what Tiled uses for compressed layers.
+//!
+//! This is a straightforward canonical-Huffman decoder in the style of zlib's
+//! `puff.c`: it's slow next to the real thing, but layer data is small.
+
+use super::{DcErr, DcResult};
+
+const MAX_BITS: usize = 15;
+
+// The base lengths and extra bits of length codes 257 ..= 285.
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// The base distances and extra bits of distance codes 0 ..= 29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+// The order code length code lengths are stored in, in dynamic blocks.
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decompresses a raw DEFLATE stream.
+pub fn inflate(buf: &[u8]) -> DcResult<Vec<u8>> {
+    let mut bits = Bits { buf, idx: 0, acc: 0, count: 0 };
+    let mut targ = Vec::with_capacity(buf.len() * 4);
+
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, &mut targ)?,
+            1 => {
+                let (lens, dists) = fixed_tables();
+                codes(&mut bits, &mut targ, &lens, &dists)?;
+            },
+            2 => {
+                let (lens, dists) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, &mut targ, &lens, &dists)?;
+            },
+            _ => return Err(DcErr::InflateBadBlockType),
+        }
+        if last {
+            break;
+        }
+    }
+
+    Ok(targ)
+}
+
+/// Decompresses a zlib stream, checking its Adler-32.
+pub fn zlib_decomp(buf: &[u8]) -> DcResult<Vec<u8>> {
+    if buf.len() < 6 {
+        return Err(DcErr::InflatePrematureTermination);
+    }
+    let (cmf, flg) = (buf[0], buf[1]);
+    // only method 8 (deflate) exists, and preset dictionaries aren't used by Tiled
+    if cmf & 0xf != 8 || cmf >> 4 > 7 || !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) || flg & 0x20 != 0 {
+        return Err(DcErr::InflateBadHeader);
+    }
+
+    let targ = inflate(&buf[2 .. buf.len() - 4])?;
+    let tail = &buf[buf.len() - 4 ..];
+    let stored = (tail[0] as u32) << 24 | (tail[1] as u32) << 16 | (tail[2] as u32) << 8 | tail[3] as u32;
+    if adler32(&targ) != stored {
+        return Err(DcErr::InflateBadChecksum);
+    }
+    Ok(targ)
+}
+
+/// Decompresses a gzip stream (of one member), checking its CRC-32 and length.
+pub fn gzip_decomp(buf: &[u8]) -> DcResult<Vec<u8>> {
+    if buf.len() < 18 {
+        return Err(DcErr::InflatePrematureTermination);
+    }
+    if buf[0] != 0x1f || buf[1] != 0x8b || buf[2] != 8 {
+        return Err(DcErr::InflateBadHeader);
+    }
+    let flags = buf[3];
+    let mut idx = 10;
+    // FEXTRA
+    if flags & 4 != 0 {
+        let xlen = *buf.get(idx).ok_or(DcErr::InflatePrematureTermination)? as usize
+            | (*buf.get(idx + 1).ok_or(DcErr::InflatePrematureTermination)? as usize) << 8;
+        idx += 2 + xlen;
+    }
+    // FNAME and FCOMMENT are zero-terminated
+    for &flag in &[8, 16] {
+        if flags & flag != 0 {
+            while *buf.get(idx).ok_or(DcErr::InflatePrematureTermination)? != 0 {
+                idx += 1;
+            }
+            idx += 1;
+        }
+    }
+    // FHCRC
+    if flags & 2 != 0 {
+        idx += 2;
+    }
+    if idx + 8 > buf.len() {
+        return Err(DcErr::InflatePrematureTermination);
+    }
+
+    let targ = inflate(&buf[idx .. buf.len() - 8])?;
+    let tail = &buf[buf.len() - 8 ..];
+    let le32 = |b: &[u8]| b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24;
+    if crc32(&targ) != le32(&tail[0 .. 4]) || targ.len() as u32 != le32(&tail[4 .. 8]) {
+        return Err(DcErr::InflateBadChecksum);
+    }
+    Ok(targ)
+}
+
+/// Reads a DEFLATE stream's bits, least significant first.
+struct Bits<'a> {
+    buf: &'a [u8],
+    idx: usize,
+    acc: u32,
+    count: u8,
+}
+
+impl<'a> Bits<'a> {
+    fn take(&mut self, n: u8) -> DcResult<u32> {
+        while self.count < n {
+            let b = *self.buf.get(self.idx).ok_or(DcErr::InflatePrematureTermination)?;
+            self.idx += 1;
+            self.acc |= (b as u32) << self.count;
+            self.count += 8;
+        }
+        let v = self.acc & ((1 << n) - 1);
+        self.acc >>= n;
+        self.count -= n;
+        Ok(v)
+    }
+
+    // Stored blocks start on a byte boundary.
+    fn align(&mut self) {
+        self.acc = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length
+/// and the symbols in order of their codes.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> DcResult<Huffman> {
+        let mut counts = [0; MAX_BITS + 1];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+
+        // check the code isn't over-subscribed; incomplete codes are allowed,
+        // since a distance code with a single symbol is one
+        let mut left = 1_i32;
+        for &c in &counts[1 ..] {
+            left <<= 1;
+            left -= c as i32;
+            if left < 0 {
+                return Err(DcErr::InflateBadCode);
+            }
+        }
+
+        let mut offs = [0; MAX_BITS + 1];
+        for len in 1 .. MAX_BITS {
+            offs[len + 1] = offs[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (sym, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offs[l as usize] as usize] = sym as u16;
+                offs[l as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> DcResult<u16> {
+        // the first code of the current length, and the index of its symbol
+        let (mut code, mut first, mut index) = (0_i32, 0_i32, 0_i32);
+        for &count in &self.counts[1 ..] {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(DcErr::InflateBadCode)
+    }
+}
+
+fn stored(bits: &mut Bits, targ: &mut Vec<u8>) -> DcResult<()> {
+    bits.align();
+    let buf = bits.buf;
+    let i = bits.idx;
+    if i + 4 > buf.len() {
+        return Err(DcErr::InflatePrematureTermination);
+    }
+    let len = buf[i] as usize | (buf[i + 1] as usize) << 8;
+    let nlen = buf[i + 2] as usize | (buf[i + 3] as usize) << 8;
+    if len != !nlen & 0xffff {
+        return Err(DcErr::InflateBadLength);
+    }
+    let data = buf.get(i + 4 .. i + 4 + len).ok_or(DcErr::InflatePrematureTermination)?;
+    targ.extend_from_slice(data);
+    bits.idx = i + 4 + len;
+    Ok(())
+}
+
+fn codes(bits: &mut Bits, targ: &mut Vec<u8>, lens: &Huffman, dists: &Huffman) -> DcResult<()> {
+    loop {
+        let sym = lens.decode(bits)? as usize;
+        if sym < 256 {
+            targ.push(sym as u8);
+        } else if sym == 256 {
+            return Ok(());
+        } else {
+            let sym = sym - 257;
+            if sym >= LEN_BASE.len() {
+                return Err(DcErr::InflateBadCode);
+            }
+            let len = LEN_BASE[sym] as usize + bits.take(LEN_EXTRA[sym])? as usize;
+
+            let dsym = dists.decode(bits)? as usize;
+            if dsym >= DIST_BASE.len() {
+                return Err(DcErr::InflateBadCode);
+            }
+            let dist = DIST_BASE[dsym] as usize + bits.take(DIST_EXTRA[dsym])? as usize;
+            if dist > targ.len() {
+                return Err(DcErr::InflateOutOfRangeCopy);
+            }
+
+            // copies can overlap what they're producing, so go a byte at a time
+            let start = targ.len() - dist;
+            for i in 0 .. len {
+                let b = targ[start + i];
+                targ.push(b);
+            }
+        }
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    for (sym, l) in lengths.iter_mut().enumerate() {
+        *l = match sym {
+            0 ..= 143 => 8,
+            144 ..= 255 => 9,
+            256 ..= 279 => 7,
+            _ => 8,
+        };
+    }
+    // these are known to be complete codes
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+fn dynamic_tables(bits: &mut Bits) -> DcResult<(Huffman, Huffman)> {
+    let nlen = bits.take(5)? as usize + 257;
+    let ndist = bits.take(5)? as usize + 1;
+    let ncode = bits.take(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(DcErr::InflateBadCode);
+    }
+
+    let mut clens = [0; 19];
+    for &i in &CLEN_ORDER[.. ncode] {
+        clens[i] = bits.take(3)? as u8;
+    }
+    let clen_code = Huffman::new(&clens)?;
+
+    let mut lengths = vec![0_u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let sym = clen_code.decode(bits)?;
+        let (val, rep) = match sym {
+            0 ..= 15 => (sym as u8, 1),
+            16 => {
+                let prev = *lengths[.. i].last().ok_or(DcErr::InflateBadCode)?;
+                (prev, 3 + bits.take(2)? as usize)
+            },
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if i + rep > nlen + ndist {
+            return Err(DcErr::InflateBadCode);
+        }
+        for l in &mut lengths[i .. i + rep] {
+            *l = val;
+        }
+        i += rep;
+    }
+
+    // a block with no end-of-block code can't be decoded
+    if lengths[256] == 0 {
+        return Err(DcErr::InflateBadCode);
+    }
+
+    Ok((Huffman::new(&lengths[.. nlen])?, Huffman::new(&lengths[nlen ..])?))
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &x in bytes {
+        a = (a + x as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &x in bytes {
+        crc ^= x as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These were made with Python's zlib and gzip modules.
+
+    #[test]
+    fn stored_block() {
+        let raw = [0x01, 0x05, 0x00, 0xfa, 0xff, b'e', b'x', b'l', b'e', b'v'];
+        assert_eq!(inflate(&raw), Ok(b"exlev".to_vec()));
+
+        let mut bad_len = raw;
+        bad_len[3] ^= 1;
+        assert_eq!(inflate(&bad_len), Err(DcErr::InflateBadLength));
+    }
+
+    #[test]
+    fn fixed_huffman_block() {
+        let raw = [0x4b, 0x4c, 0x4a, 0x4e, 0x44, 0x42, 0x0a, 0x19, 0xa9, 0x39, 0x39, 0xf9, 0x00];
+        assert_eq!(inflate(&raw), Ok(b"abcabcabcabcabc hello".to_vec()));
+    }
+
+    #[test]
+    fn dynamic_huffman_block() {
+        let raw = [
+            0xcd, 0xcb, 0xc1, 0x0d, 0x00, 0x20, 0x08, 0x04, 0xb0, 0x59, 0x2f, 0x82, 0x60, 0x2e, 0x82, 0xe0,
+            0xfe, 0x89, 0x6b, 0xd8, 0x7f, 0xe1, 0x4b, 0xa3, 0x72, 0x92, 0xc2, 0xed, 0x85, 0x5e, 0x19, 0x96,
+            0x87, 0x10, 0xec, 0x2e, 0xf6, 0x4d, 0x31, 0x3d, 0x40, 0x60, 0xb4, 0xd1, 0xaf, 0x4a, 0xe9, 0x04,
+            0x83, 0xc3, 0x0d, 0x1f, 0xdd, 0x07,
+        ];
+        let expected = (0 .. 200_u32).map(|i| ((i * i * 7 + i / 3) % 20 + 97) as u8).collect::<Vec<_>>();
+        assert_eq!(inflate(&raw), Ok(expected));
+    }
+
+    #[test]
+    fn bad_and_truncated_deflate() {
+        assert_eq!(inflate(&[0x07]), Err(DcErr::InflateBadBlockType));
+        assert_eq!(inflate(&[]), Err(DcErr::InflatePrematureTermination));
+        let raw = [0x4b, 0x4c, 0x4a, 0x4e, 0x44, 0x42, 0x0a, 0x19, 0xa9, 0x39, 0x39, 0xf9, 0x00];
+        assert_eq!(inflate(&raw[.. 8]), Err(DcErr::InflatePrematureTermination));
+        assert_eq!(inflate(&[0x01, 0x05, 0x00, 0xfa, 0xff, b'e', b'x']), Err(DcErr::InflatePrematureTermination));
+    }
+
+    const ZLIB: [u8; 24] = [
+        0x78, 0x9c, 0x0b, 0xc9, 0xcc, 0x49, 0x4d, 0x51, 0xc8, 0x49, 0xac, 0x4c, 0x2d, 0x52, 0x48, 0x49,
+        0x2c, 0x49, 0x04, 0x00, 0x32, 0x04, 0x05, 0xea,
+    ];
+
+    #[test]
+    fn zlib() {
+        assert_eq!(zlib_decomp(&ZLIB), Ok(b"Tiled layer data".to_vec()));
+
+        let mut bad_sum = ZLIB;
+        bad_sum[23] ^= 1;
+        assert_eq!(zlib_decomp(&bad_sum), Err(DcErr::InflateBadChecksum));
+
+        let mut bad_header = ZLIB;
+        bad_header[1] ^= 1;
+        assert_eq!(zlib_decomp(&bad_header), Err(DcErr::InflateBadHeader));
+
+        assert_eq!(zlib_decomp(&ZLIB[.. 4]), Err(DcErr::InflatePrematureTermination));
+        assert!(zlib_decomp(&ZLIB[.. 20]).is_err());
+    }
+
+    const GZIP: [u8; 36] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x0b, 0xc9, 0xcc, 0x49, 0x4d, 0x51,
+        0xc8, 0x49, 0xac, 0x4c, 0x2d, 0x52, 0x48, 0x49, 0x2c, 0x49, 0x04, 0x00, 0x82, 0x05, 0x6d, 0x3d,
+        0x10, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn gzip() {
+        assert_eq!(gzip_decomp(&GZIP), Ok(b"Tiled layer data".to_vec()));
+
+        let mut bad_crc = GZIP;
+        bad_crc[28] ^= 1;
+        assert_eq!(gzip_decomp(&bad_crc), Err(DcErr::InflateBadChecksum));
+
+        let mut bad_len = GZIP;
+        bad_len[32] += 1;
+        assert_eq!(gzip_decomp(&bad_len), Err(DcErr::InflateBadChecksum));
+
+        let mut bad_magic = GZIP;
+        bad_magic[1] = 0x8c;
+        assert_eq!(gzip_decomp(&bad_magic), Err(DcErr::InflateBadHeader));
+
+        assert_eq!(gzip_decomp(&GZIP[.. 12]), Err(DcErr::InflatePrematureTermination));
+    }
+
+    #[test]
+    fn gzip_with_file_name() {
+        let mut named = vec![0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff];
+        named.extend(b"lev.bin\0");
+        named.extend(&GZIP[10 ..]);
+        assert_eq!(gzip_decomp(&named), Ok(b"Tiled layer data".to_vec()));
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
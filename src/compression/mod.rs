@@ -10,3 +10,4 @@ type CResult<V> = Result<V, CErr>;
 
 pub mod lc_lz3;
 pub mod lc_lz2;
+pub mod inflate;
@@ -0,0 +1,30 @@
+//! CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xffff, no reflection).
+//! It's picked over CRC-32 because it's cheap to check on the SNES:
+//! one shift/xor loop per byte on 16-bit registers.
+
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0 .. 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // the standard check value for CRC-16/CCITT-FALSE
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(b""), 0xffff);
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct EntranceId {
     pub secondary: bool,
     pub levelnum: u16,
@@ -34,7 +34,7 @@ impl EntranceId {
         // "{kind: 'm' for main or 's' for secondary}{sub_id: hex u8}"
         // this function breaks it apart into a level number and fragment
         // and then calls from_num_and_fragment to do the rest
-        let mut pieces = name.splitn(1, '#');
+        let mut pieces = name.splitn(2, '#');
         let lvl_piece = if let Some(p) = pieces.next() { p } else { return None };
         let frag_piece = if let Some(p) = pieces.next() { p } else { return None };
         let lvlnum = if let Ok(n) = u16::from_str_radix(lvl_piece, 16) {
@@ -1,6 +1,8 @@
 #![allow(dead_code, unused_variables, unused_imports)]
-use std::collections::{BTreeSet, BTreeMap};
+use std::collections::{BTreeSet, BTreeMap, HashMap};
 use std::cmp;
+use std::fmt;
+use std::hash;
 use snes_color::{SnesColor, SnesPal};
 
 use spr::*;
@@ -49,14 +51,26 @@ impl PScreen {
     }
 }
 
-// P-Screens aren't Eq because a screen with a sprite cannot be equal
-// to any screen, including an otherwise identical screen
+// Sprites are stored relative to their screen, so two screens at different
+// places in the level are the same if their sprites are at the same
+// places within them.
 impl cmp::PartialEq for PScreen {
     fn eq(&self, other: &PScreen) -> bool {
-        if self.sprites.len() == 0 && other.sprites.len() == 0 {
-            self.tiles.iter().eq(other.tiles.iter()) && self.exit == other.exit
-        } else {
-            false
+        self.tiles.iter().eq(other.tiles.iter())
+        && self.exit == other.exit
+        && self.sprites.len() == other.sprites.len()
+        && self.sprites.iter().zip(other.sprites.iter()).all(|(a, b)| a.eq_local(b))
+    }
+}
+
+impl cmp::Eq for PScreen {}
+
+impl hash::Hash for PScreen {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.tiles[..].hash(state);
+        self.exit.hash(state);
+        for spr in &self.sprites {
+            spr.hash_local(state);
         }
     }
 }
@@ -101,15 +115,70 @@ impl PScrGrid {
     }
 }
 
+/// How many distinct screens a screendex with 8-bit entries can hold.
+pub const NARROW_DEX_MAX: usize = 0x80;
+/// How many distinct screens a screendex with 16-bit entries can hold.
+/// The entries could go up to 0x8000, but a level only has a 32x32 FG and BG.
+pub const WIDE_DEX_MAX: usize = 32 * 32 * 2;
+
 //#[derive(Debug)]
 pub struct ScreenDex {
     pub screens: Vec<PScreen>,
-    pub dex: Vec<u8>,
+    pub dex: Vec<u16>,
     pub filter: Vec<bool>,
     pub width: usize,
     pub height: usize,
 }
 
+/// A level has more distinct screens than a screendex can hold.
+#[derive(Debug, Clone)]
+pub struct TooManyScreens {
+    pub limit: usize,
+    /// The layer and position of each screen that didn't fit.
+    pub screens: Vec<(&'static str, usize, usize)>,
+}
+
+impl fmt::Display for TooManyScreens {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "level has more than {} distinct screens; these didn't fit:", self.limit)?;
+        for &(layer, x, y) in &self.screens {
+            write!(f, " {} ({}, {})", layer, x, y)?;
+        }
+        Ok(())
+    }
+}
+
+// Gives each distinct screen in `it` an index, in the order they first show up.
+// `pos` says where the nth screen of `it` is, for the error.
+fn index_screens<'a, I, P>(it: I, limit: usize, pos: P)
+    -> Result<(Vec<PScreen>, Vec<u16>), TooManyScreens>
+    where I: Iterator<Item = &'a PScreen>, P: Fn(usize) -> (&'static str, usize, usize)
+{
+    let mut seen: HashMap<&PScreen, u16> = HashMap::new();
+    let mut screens = Vec::new();
+    let mut dex = Vec::new();
+    let mut overflow = vec![];
+
+    for (i, scr) in it.enumerate() {
+        if let Some(&ix) = seen.get(scr) {
+            dex.push(ix);
+        } else if screens.len() < limit {
+            let here = screens.len() as u16;
+            seen.insert(scr, here);
+            screens.push(scr.clone());
+            dex.push(here);
+        } else {
+            overflow.push(pos(i));
+        }
+    }
+
+    if overflow.is_empty() {
+        Ok((screens, dex))
+    } else {
+        Err(TooManyScreens { limit, screens: overflow })
+    }
+}
+
 impl ScreenDex {
     pub fn empty(width: usize, height: usize) -> ScreenDex {
         assert!(width < 128, "tried to make screendex too wide");
@@ -127,53 +196,41 @@ impl ScreenDex {
         }
     }
 
-    pub fn from_scr_grid(mut grid: PScrGrid) -> ScreenDex {
-        let mut screens = Vec::with_capacity(128);
-        let mut dex = Vec::with_capacity(grid.width * grid.height);
-
-        for (i, scr) in grid.pscreens.drain(..).enumerate() {
-            if let Some(pos) = screens.iter().position(|s| *s == scr) {
-                dex.push(pos as u8);
-            } else {
-                let here = screens.len();
-                assert!(here < 128, "level has too many distinct screens");
-                screens.push(scr);
-                dex.push(here as u8);
-            }
-        }
+    pub fn from_scr_grid(grid: PScrGrid) -> Result<ScreenDex, TooManyScreens> {
+        let w = grid.width;
+        let (screens, dex) = index_screens(
+            grid.pscreens.iter(), WIDE_DEX_MAX, |i| ("grid", i % w, i / w)
+        )?;
 
-        ScreenDex {
+        Ok(ScreenDex {
             screens: screens,
-            // these are out of order because `dex: dex` moves dex
             filter: vec![false; dex.len()],
             dex: dex,
             width: grid.width,
-            height: grid.width,
-        }
+            height: grid.height,
+        })
     }
 
-    pub fn from_level(level: &Level) -> ScreenDex {
-        let mut screens = Vec::with_capacity(128);
-        let mut dex = Vec::with_capacity(level.fg.width * level.fg.height * 2);
+    pub fn from_level(level: &Level) -> Result<ScreenDex, TooManyScreens> {
+        let (w, n) = (level.fg.width, level.fg.pscreens.len());
         let (fg, bg) = (&level.fg.pscreens, &level.bg.pscreens);
-        for (i, scr) in fg.iter().chain(bg.iter()).enumerate() {
-            if let Some(pos) = screens.iter().position(|s| *s == *scr) {
-                dex.push(pos as u8);
-            } else {
-                let here = screens.len();
-                assert!(here < 128, "level has too many distinct screens");
-                screens.push(scr.clone());
-                dex.push(here as u8);
-            }
-        }
+        let (screens, dex) = index_screens(
+            fg.iter().chain(bg.iter()), WIDE_DEX_MAX,
+            |i| if i < n { ("FG", i % w, i / w) } else { ("BG", (i - n) % w, (i - n) / w) }
+        )?;
 
-        ScreenDex {
+        Ok(ScreenDex {
             screens: screens,
             filter: level.sf.clone(),
             dex: dex,
             width: level.fg.width,
             height: level.fg.height,
-        }
+        })
+    }
+
+    /// Whether the screendex needs 16-bit entries.
+    pub fn is_wide(&self) -> bool {
+        self.screens.len() > NARROW_DEX_MAX
     }
 
     pub fn screen_at(&self, x: usize, y: usize) -> &PScreen {
@@ -192,11 +249,19 @@ impl ScreenDex {
         tile_bytes(self.screens.iter())
     }
 
+    /// The screendex as it goes in ROM, with the scroll filter in the top bit
+    /// of each entry. Entries are 2 bytes each if `is_wide()`, else 1.
     pub fn dex_bytes(&self) -> Vec<u8> {
-        let mut v = self.dex.clone();
-        for (b, &s) in v.iter_mut().zip(self.filter.iter()) {
-            if s {
-                *b |= 0x80;
+        let wide = self.is_wide();
+        let filter = self.filter.iter().cloned().chain(::std::iter::repeat(false));
+        let mut v = Vec::with_capacity(self.dex.len() * 2);
+        for (&ix, s) in self.dex.iter().zip(filter) {
+            if wide {
+                let e = ix | if s { 0x8000 } else { 0 };
+                v.push(e as u8);
+                v.push((e >> 8) as u8);
+            } else {
+                v.push(ix as u8 | if s { 0x80 } else { 0 });
             }
         }
         v
@@ -355,3 +420,54 @@ pub fn place_exits(scrgrid: &mut PScrGrid, exits: &BTreeMap<(u8, u8), EntranceId
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spr::SpritePlacement;
+
+    fn level(fg: PScrGrid) -> Level {
+        Level::from_parts(fg, PScrGrid::new(32, 32), vec![false; 32 * 32], Vec::new(), LevelHeader::default())
+    }
+
+    #[test]
+    fn screens_with_the_same_sprites_share_an_entry() {
+        let mut fg = PScrGrid::new(32, 32);
+        for &(x, y) in &[(1, 0), (2, 0), (1, 3)] {
+            fg.screen_at_mut(x, y).tiles[0] = 0x130;
+            fg.screen_at_mut(x, y).sprites.insert(SpritePlacement::new(0x10, x as u16 * 16 + 5, y as u16 * 16 + 2, false, [0; 4]));
+        }
+        let dex = ScreenDex::from_level(&level(fg)).unwrap();
+        // the empty screen, and the one with the sprite
+        assert_eq!(dex.screens.len(), 2);
+        assert_eq!(dex.dex[1], dex.dex[2]);
+        assert_eq!(dex.dex[1], dex.dex[3 * 32 + 1]);
+    }
+
+    #[test]
+    fn sprites_elsewhere_on_the_screen_make_it_different() {
+        let mut fg = PScrGrid::new(32, 32);
+        fg.screen_at_mut(1, 0).sprites.insert(SpritePlacement::new(0x10, 16 + 5, 2, false, [0; 4]));
+        fg.screen_at_mut(2, 0).sprites.insert(SpritePlacement::new(0x10, 32 + 6, 2, false, [0; 4]));
+        fg.screen_at_mut(3, 0).sprites.insert(SpritePlacement::new(0x10, 48 + 5, 2, true, [0; 4]));
+        let dex = ScreenDex::from_level(&level(fg)).unwrap();
+        assert_eq!(dex.screens.len(), 4);
+    }
+
+    #[test]
+    fn more_than_128_screens_make_a_wide_dex() {
+        let mut fg = PScrGrid::new(32, 32);
+        for i in 0 .. 200 {
+            fg.screen_at_mut(i % 32, i / 32).tiles[0] = i as u16 + 0x100;
+        }
+        let dex = ScreenDex::from_level(&level(fg)).unwrap();
+        assert!(dex.is_wide());
+        assert_eq!(dex.screens.len(), 201);
+        assert_eq!(dex.dex_bytes().len(), 32 * 32 * 2 * 2);
+        assert_eq!(dex.dex[199], 199);
+
+        let mut fg = PScrGrid::new(32, 32);
+        fg.screen_at_mut(0, 0).tiles[0] = 1;
+        assert!(!ScreenDex::from_level(&level(fg)).unwrap().is_wide());
+    }
+}
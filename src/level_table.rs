@@ -1,112 +1,183 @@
-use super::address::Address;
-use super::address::Mapper::*;
+use super::address::{Address, Mapper};
+use super::rats::scan::{self, MARKER_LEN, TAG_LEN};
+use std::ops::Range;
 
-pub fn get_level_ptr<B: AsRef<[u8]>>(rombytes: &B, level: u16) -> Address {
+/// Where the level table is, as a SNES address.
+const LEVEL_TABLE: usize = 0x05_e000;
+
+/// The marker after the RATS tag of every level exlev inserts.
+pub const MARKER: &[u8; MARKER_LEN] = b"CLNP";
+
+/// Gets the pointer to a level, or `None` if it doesn't point into ROM
+/// (e.g. because the level was removed).
+pub fn get_level_ptr<B: AsRef<[u8]>>(rombytes: &B, map: Mapper, level: u16) -> Option<Address> {
     let rb = rombytes.as_ref();
-    let ptr_ofs = get_level_ptr_ofs(level).pc_ofs();
-    let ptr = Address::new_from_snes_bytes(&rb[ptr_ofs .. ptr_ofs + 3], Lorom).unwrap();
-    ptr
+    let ptr_ofs = get_level_ptr_ofs(map, level).pc_ofs();
+    Address::new_from_snes_bytes(&rb[ptr_ofs .. ptr_ofs + 3], map)
 }
 
-pub fn set_level_ptr(rombytes: &mut [u8], level: u16, value: u32) {
-    let ptr_ofs = get_level_ptr_ofs(level).pc_ofs();
+pub fn set_level_ptr(rombytes: &mut [u8], map: Mapper, level: u16, value: u32) {
+    let ptr_ofs = get_level_ptr_ofs(map, level).pc_ofs();
     rombytes[ptr_ofs] = value as u8;
     rombytes[ptr_ofs + 1] = (value >> 8) as u8;
     rombytes[ptr_ofs + 2] = (value >> 16) as u8;
 }
 
-fn get_level_ptr_ofs(level: u16) -> Address {
+/// Whether the level table and the version table's pointer are both in the ROM,
+/// which they won't be if `map` is the wrong mapper for it.
+pub fn fits(rombytes: &[u8], map: Mapper) -> bool {
+    let ends = [
+        Address::new_from_snes(LEVEL_TABLE + 0x200 * 3 - 1, map),
+        Address::new_from_snes(VERSION_PTR_LOC + 2, map),
+    ];
+    ends.iter().all(|a| a.is_some_and(|a| a.pc_ofs() < rombytes.len()))
+}
+
+fn get_level_ptr_ofs(map: Mapper, level: u16) -> Address {
     assert!(level < 0x200, "tried to access level with too high level number");
-    let addr = 0x05_e000 + level as usize * 3;
-    Address::new_from_snes(addr, Lorom).unwrap()
+    let addr = LEVEL_TABLE + level as usize * 3;
+    Address::new_from_snes(addr, map).unwrap()
 }
 
-pub fn rm_level(rombytes: &mut [u8], level: u16) -> Option<(Address, usize)> {
-    let start = get_level_ptr(&rombytes, level).pc_ofs();
-    if start < 12 {
-        return None;
-    };
-    let tag_start = start - 12;
-    if !is_rats_clnp(&rombytes[tag_start .. start]) {
-        return None;
-    };
+/// Gets the pointer to a level, if it points to a level that exlev inserted.
+pub fn get_exlev_level_ptr(rombytes: &[u8], map: Mapper, level: u16) -> Option<Address> {
+    let start = get_level_ptr(&rombytes, map, level)?;
+    exlev_tag(rombytes, start).map(|_| start)
+}
 
-    let len = (rombytes[tag_start + 4] as usize | ((rombytes[tag_start + 5] as usize) << 8)) + 8;
+/// The RATS tag in front of a level that starts at `start`,
+/// if it has exlev's marker.
+fn exlev_tag(rombytes: &[u8], start: Address) -> Option<scan::Tag> {
+    let tag_start = start.checked_sub(TAG_LEN + MARKER_LEN)?.pc_ofs();
+    scan::read_tag(rombytes.get(tag_start ..)?, tag_start).filter(|t| t.has_marker(MARKER))
+}
 
-    let end = if tag_start + len > rombytes.len() { rombytes.len() } else { tag_start + len };
+pub fn rm_level(rombytes: &mut [u8], map: Mapper, level: u16) -> Option<(Address, usize)> {
+    let start = get_exlev_level_ptr(rombytes, map, level)?;
+    let tag = exlev_tag(rombytes, start).unwrap();
+    let len = ::rats::clear(rombytes, &tag);
 
-    for i in tag_start .. end {
-        rombytes[i] = 0;
-    }
+    set_level_ptr(rombytes, map, level, 0);
 
-    set_level_ptr(rombytes, level, 0);
-    set_version(rombytes, level);
+    Some((Address::new_from_pc(tag.start, map).unwrap(), len))
+}
 
-    let new_ptr = Address::new_from_pc(tag_start, Lorom).unwrap();
-    Some((new_ptr, len))
+/// Every pointer in the tables exlev keeps: the level table and the
+/// pointer to the version table.
+pub fn exlev_pointers(rombytes: &[u8], map: Mapper) -> Vec<Address> {
+    let mut ptrs = (0 .. 0x200).filter_map(|n| get_level_ptr(&rombytes, map, n)).collect::<Vec<_>>();
+    ptrs.extend(get_version_table_ptr(rombytes, map));
+    ptrs
 }
 
-fn is_rats_clnp(bytes: &[u8]) -> bool {
-    let b = bytes.as_ref();
-    if b.len() < 12 {
-        false
-    } else {
-        b[0..4] == b"STAR"[..]
-        && b[4] == !b[6] && b[5] == !b[7]
-        && b[8..12] == b"CLNP"[..]
-    }
+/// The blocks with exlev's marker that nothing in its tables points into,
+/// like ones left behind by an insert that didn't finish,
+/// or by a level pointer that was changed by hand.
+pub fn orphaned_blocks(rombytes: &[u8], map: Mapper) -> Vec<scan::Tag> {
+    let ptrs = exlev_pointers(rombytes, map);
+    scan::tags(rombytes, ::rats::FREESPACE_START)
+        .filter(|t| t.has_marker(MARKER))
+        .filter(|t| !ptrs.iter().any(|p| p.pc_ofs() >= t.data_start() && p.pc_ofs() < t.end()))
+        .collect()
 }
 
-const VERSION_PTR_LOC: usize = 0x7f080;
+/// Where the pointer to the version table is, as a SNES address.
+const VERSION_PTR_LOC: usize = 0x0f_f080;
 
-pub fn get_version(rombytes: &[u8], lvlnum: u16) -> (u8, u8, u8) {
-    if let Some(table_ptr) = get_version_table_ptr(rombytes) {
-        let ofs = table_ptr.pc_ofs() + (lvlnum as usize) * 3;
-        (rombytes[ofs], rombytes[ofs + 1], rombytes[ofs + 2])
-    } else {
-        (0, 0, 0)
+fn version_ptr_ofs(map: Mapper) -> usize {
+    Address::new_from_snes(VERSION_PTR_LOC, map).unwrap().pc_ofs()
+}
+
+/// The format version level `lvlnum` was written in, or 0.0.0 if there's no version table.
+pub fn get_version(rombytes: &[u8], map: Mapper, lvlnum: u16) -> Result<(u8, u8, u8), String> {
+    match get_version_table_ptr(rombytes, map) {
+        Some(table_ptr) => {
+            let v = &rombytes[version_range(rombytes, table_ptr, lvlnum)?];
+            Ok((v[0], v[1], v[2]))
+        },
+        None => Ok((0, 0, 0)),
     }
 }
 
-pub fn set_version(rombytes: &mut [u8], lvlnum: u16) -> Result<(), String> {
-    let level_version = [0, 1, 0];
-    let table_ptr = match get_version_table_ptr(rombytes) {
+pub fn set_version(rombytes: &mut [u8], map: Mapper, lvlnum: u16, level_version: (u8, u8, u8)) -> Result<(), String> {
+    let table_ptr = match get_version_table_ptr(rombytes, map) {
         None =>
-            init_version_table(rombytes).ok_or("no dang space for the level table")?,
+            init_version_table(rombytes, map).ok_or("no dang space for the level table")?,
         Some(a) => a,
     };
 
-    println!("ver table: {:08x}", table_ptr.pc_ofs());
-
-    let targ = table_ptr.pc_ofs() + (lvlnum as usize) * 3;
-    rombytes[targ] = level_version[0];
-    rombytes[targ + 1] = level_version[1];
-    rombytes[targ + 2] = level_version[2];
+    let targ = version_range(rombytes, table_ptr, lvlnum)?;
+    rombytes[targ].copy_from_slice(&[level_version.0, level_version.1, level_version.2]);
     Ok(())
 }
 
-fn get_version_table_ptr(rombytes: &[u8]) -> Option<Address> {
-    let a = Address::new_from_snes_bytes(
-        &rombytes[VERSION_PTR_LOC .. VERSION_PTR_LOC + 3],
-        Lorom
-    ).unwrap();
-    if a.snes_ofs().unwrap() == 0xff_ffff {
+/// Where level `lvlnum`'s entry in the version table at `table_ptr` is,
+/// as long as it's in the ROM.
+fn version_range(rombytes: &[u8], table_ptr: Address, lvlnum: u16) -> Result<Range<usize>, String> {
+    table_ptr.checked_add(lvlnum as usize * 3)
+        .map(|a| a.pc_ofs() .. a.pc_ofs() + 3)
+        .filter(|r| r.end <= rombytes.len())
+        .ok_or_else(|| format!("the version table at {} runs past the end of the ROM", table_ptr))
+}
+
+fn get_version_table_ptr(rombytes: &[u8], map: Mapper) -> Option<Address> {
+    let loc = version_ptr_ofs(map);
+    let ptr = rombytes.get(loc .. loc + 3)?;
+    // $FF:FFFF is ROM for some mappers, so it's checked before it's mapped;
+    // anything else that isn't ROM means there's no table either
+    if ptr == [0xff; 3] {
         None
     } else {
-        Some(a)
+        Address::new_from_snes_bytes(ptr, map)
     }
 }
 
-fn init_version_table(rombytes: &mut [u8]) -> Option<Address> {
+fn init_version_table(rombytes: &mut [u8], map: Mapper) -> Option<Address> {
     let default = &[0; 512 * 3];
-    let a = match ::rats::insert_free(rombytes, default) {
-        None => return None,
-        Some(a) => a,
-    };
+    let a = ::rats::insert_free(rombytes, map, default)?;
     let ofs = a.snes_ofs().unwrap();
-    rombytes[VERSION_PTR_LOC] = ofs as u8;
-    rombytes[VERSION_PTR_LOC + 1] = (ofs >> 8) as u8;
-    rombytes[VERSION_PTR_LOC + 2] = (ofs >> 16) as u8;
+    let loc = version_ptr_ofs(map);
+    rombytes[loc] = ofs as u8;
+    rombytes[loc + 1] = (ofs >> 8) as u8;
+    rombytes[loc + 2] = (ofs >> 16) as u8;
     Some(a)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rats;
+
+    /// Inserts `data` after exlev's marker, returning where `data` starts.
+    fn insert_marked(rom: &mut [u8], data: &[u8]) -> Address {
+        let mut block = MARKER.to_vec();
+        block.extend_from_slice(data);
+        let a = rats::insert_free(rom, Mapper::Lorom, &block).unwrap();
+        a.checked_add(MARKER_LEN).unwrap()
+    }
+
+    #[test]
+    fn orphaned_blocks_are_the_ones_nothing_points_into() {
+        let mut rom = vec![0; 0x10_0000];
+        let level = insert_marked(&mut rom, &[0x55; 0x20]);
+        set_level_ptr(&mut rom, Mapper::Lorom, 0x105, level.snes_ofs().unwrap() as u32);
+        let orphan = insert_marked(&mut rom, &[0x55; 0x20]);
+        // blocks without the marker aren't exlev's to free
+        rats::insert_free(&mut rom, Mapper::Lorom, &[0x55; 0x20]).unwrap();
+        set_version(&mut rom, Mapper::Lorom, 0x105, (0, 3, 0)).unwrap();
+
+        let found = orphaned_blocks(&rom, Mapper::Lorom);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].data_start(), orphan.pc_ofs() - MARKER_LEN);
+
+        // a pointer anywhere into a block keeps it
+        let inside = orphan.checked_add(0x10).unwrap();
+        set_level_ptr(&mut rom, Mapper::Lorom, 0x106, inside.snes_ofs().unwrap() as u32);
+        assert!(orphaned_blocks(&rom, Mapper::Lorom).is_empty());
+
+        // and removing a level leaves nothing behind
+        rm_level(&mut rom, Mapper::Lorom, 0x105).unwrap();
+        assert!(orphaned_blocks(&rom, Mapper::Lorom).is_empty());
+    }
+}
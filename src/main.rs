@@ -14,13 +14,19 @@ pub mod level_table;
 pub mod snes_color;
 pub mod compression;
 pub mod rats;
+pub mod rom;
+pub mod crc;
 
-use std::io::{Cursor, SeekFrom};
+use std::error::Error;
+use std::io::SeekFrom;
 use std::io::prelude::*;
 
 use std::path::PathBuf;
 use std::fs::{File, OpenOptions};
 
+use address::{Address, Mapper};
+use rats::scan::{MARKER_LEN, TAG_LEN};
+
 fn main() {
     match submain() {
         Err(e) => println!("Error: {}", e),
@@ -30,17 +36,38 @@ fn main() {
 
 #[derive(Clone, Debug)]
 struct Arguments {
-    rom_path: PathBuf,
-    item_path: PathBuf,
+    rom_path: Option<PathBuf>,
+    item_path: Option<PathBuf>,
     action: CliAction,
+    loader_crc: bool,
+    /// The mapper to use instead of the one in the ROM's header.
+    mapper: Option<Mapper>,
 }
 
 #[derive(Clone, Debug)]
 enum CliAction {
 	InsertGfx(Option<u16>),
 	ExtractGfx(Option<u16>),
-	InsertTmx(u16),
+	InsertTmx(Option<u16>),
 	ExtractTmx(u16),
+	DumpLevel(u16),
+	/// Dumps the level block at an address, as text still to be parsed
+	/// since that needs the mapper.
+	DumpAt(String),
+	Migrate,
+	Lint,
+	Gc,
+	InitTiled,
+}
+
+impl CliAction {
+    fn needs_item(&self) -> bool {
+        !matches!(*self, CliAction::DumpLevel(_) | CliAction::DumpAt(_) | CliAction::Migrate | CliAction::Lint | CliAction::Gc)
+    }
+
+    fn needs_rom(&self) -> bool {
+        !matches!(*self, CliAction::InitTiled)
+    }
 }
 
 macro_rules! try_hex_arg {
@@ -57,78 +84,139 @@ fn parse_arguments() -> Option<Arguments> {
     let mut rom_path = None;
     let mut item_path = None;
     let mut action = None;
+    let mut loader_crc = false;
+    let mut mapper = None;
 
     for arg in std::env::args().skip(1) {
-        if arg.starts_with("--rom=") {
-            if !rom_path.is_none() { return None; };
-            rom_path = Some(PathBuf::from(&arg["--rom=".len()..]));
-        } else if arg.starts_with("--insert-tmx=") {
-            if !action.is_none() { return None; };
-            let num_s = &arg["--insert-tmx=".len()..];
+        if let Some(path) = arg.strip_prefix("--rom=") {
+            if rom_path.is_some() { return None; };
+            rom_path = Some(PathBuf::from(path));
+        } else if let Some(num_s) = arg.strip_prefix("--insert-tmx=") {
+            if action.is_some() { return None; };
             let lnum = try_hex_arg!(num_s);
-            action = Some(CliAction::InsertTmx(lnum));
-        } else if arg.starts_with("--extract-tmx=") {
-            if !action.is_none() { return None; };
-            let num_s = &arg["--extract-tmx=".len()..];
+            action = Some(CliAction::InsertTmx(Some(lnum)));
+        } else if arg == "--insert-tmx" {
+            if action.is_some() { return None; };
+            action = Some(CliAction::InsertTmx(None));
+        } else if let Some(num_s) = arg.strip_prefix("--extract-tmx=") {
+            if action.is_some() { return None; };
             let lnum = try_hex_arg!(num_s);
             action = Some(CliAction::ExtractTmx(lnum));
-        } else if arg.starts_with("--extract-gfx=") {
-            if !action.is_none() { return None; };
-            let num_s = &arg["--extract-gfx=".len()..];
+        } else if let Some(num_s) = arg.strip_prefix("--dump-level=") {
+            if action.is_some() { return None; };
+            let lnum = try_hex_arg!(num_s);
+            action = Some(CliAction::DumpLevel(lnum));
+        } else if let Some(addr) = arg.strip_prefix("--dump-at=") {
+            if action.is_some() { return None; };
+            action = Some(CliAction::DumpAt(addr.to_string()));
+        } else if arg == "--migrate" {
+            if action.is_some() { return None; };
+            action = Some(CliAction::Migrate);
+        } else if arg == "--lint" {
+            if action.is_some() { return None; };
+            action = Some(CliAction::Lint);
+        } else if arg == "--gc" {
+            if action.is_some() { return None; };
+            action = Some(CliAction::Gc);
+        } else if arg == "--init-tiled" {
+            if action.is_some() { return None; };
+            action = Some(CliAction::InitTiled);
+        } else if arg == "--loader-crc" {
+            loader_crc = true;
+        } else if let Some(name) = arg.strip_prefix("--mapper=") {
+            if mapper.is_some() { return None; };
+            mapper = Some(rom::mapper_by_name(name)?);
+        } else if let Some(num_s) = arg.strip_prefix("--extract-gfx=") {
+            if action.is_some() { return None; };
             let gnum = try_hex_arg!(num_s);
             action = Some(CliAction::ExtractGfx(Some(gnum)));
-        } else if arg.starts_with("--insert-gfx=") {
-            if !action.is_none() { return None; };
-            let num_s = &arg["--insert-gfx=".len()..];
+        } else if let Some(num_s) = arg.strip_prefix("--insert-gfx=") {
+            if action.is_some() { return None; };
             let gnum = try_hex_arg!(num_s);
             action = Some(CliAction::InsertGfx(Some(gnum)));
         } else if arg.starts_with("--extract-gfx") {
-            if !action.is_none() { return None; };
+            if action.is_some() { return None; };
             action = Some(CliAction::ExtractGfx(None))
         } else if arg.starts_with("--insert-gfx") {
-            if !action.is_none() { return None; };
+            if action.is_some() { return None; };
             action = Some(CliAction::InsertGfx(None));
         } else {
-            if !item_path.is_none() { return None; };
+            if item_path.is_some() { return None; };
             item_path = Some(PathBuf::from(arg));
         };
     }
 
-    if action.is_some() && rom_path.is_some() && item_path.is_some() {
-        Some(Arguments {
-            rom_path: rom_path.unwrap(),
-            item_path: item_path.unwrap(),
-            action: action.unwrap(),
-        })
-    } else {
-        None
+    match action {
+        Some(action) => {
+            if action.needs_item() != item_path.is_some() || action.needs_rom() != rom_path.is_some() {
+                return None;
+            }
+            Some(Arguments { rom_path, item_path, action, loader_crc, mapper })
+        },
+        None => None,
     }
 }
 
-fn submain() -> Result<(), Box<std::error::Error>> {
+fn submain() -> Result<(), Box<dyn Error>> {
     let args = if let Some(a) = parse_arguments() {
         a
     } else {
         panic!("you goofed it on the command line see the readme");
     };
-    println!("{:?}", args);
 
-    let mut rom = OpenOptions::new().read(true).write(true).open(args.rom_path)?;
+    let rom_path = match args.action {
+        CliAction::InitTiled => {
+            let dir = args.item_path.as_ref().unwrap();
+            tmx::init_tiled(dir)?;
+            println!("wrote {} and {} to {}",
+                tmx::PROJECT_FILE, tmx::STARTER_MAP, dir.to_string_lossy());
+            return Ok(());
+        },
+        _ => args.rom_path.unwrap(),
+    };
+
+    let mut rom = OpenOptions::new().read(true).write(true).open(rom_path)?;
 
     // 4 MB is a nice enough guess.
     let mut rombytes = Vec::with_capacity(4 * 1024 * 1024);
 
     rom.read_to_end(&mut rombytes)?;
 
+    let map = match args.mapper {
+        Some(m) => m,
+        None => {
+            let m = rom::detect_mapper(&rombytes)
+                .ok_or("couldn't tell which mapper the ROM uses from its header; pick one with --mapper")?;
+            println!("Detected mapper: {}", rom::mapper_name(m));
+            m
+        },
+    };
+    if !level_table::fits(&rombytes, map) {
+        return Err(format!("the level table isn't in the ROM if it's {:?}; is that the right mapper?", map).into());
+    }
+
     match args.action {
         CliAction::InsertTmx(lvln) =>
-            rombytes = insert_level(rombytes, lvln, &args.item_path)?,
-        CliAction::ExtractTmx(_lvln) =>
-            unimplemented!(),
-        CliAction::InsertGfx(_) =>
-            unimplemented!(),
-        CliAction::ExtractGfx(_) =>
-            unimplemented!(),
+            rombytes = insert_level(rombytes, map, lvln, args.item_path.as_ref().unwrap(), args.loader_crc)?,
+        CliAction::ExtractTmx(lvln) =>
+            return Err(format!("extracting level {:03x} to TMX isn't implemented yet", lvln).into()),
+        CliAction::InsertGfx(gnum) | CliAction::ExtractGfx(gnum) =>
+            return Err(gfx_unimplemented(gnum).into()),
+        CliAction::DumpLevel(lvln) =>
+            return dump_level(&rombytes, map, lvln),
+        CliAction::DumpAt(ref text) => {
+            let start = Address::parse(text, map)
+                .ok_or_else(|| format!("{} isn't an address in the ROM", text))?;
+            return dump_at(&rombytes, map, start);
+        },
+        CliAction::Migrate =>
+            rombytes = migrate(rombytes, map, args.loader_crc)?,
+        CliAction::Lint =>
+            return lint(&rombytes, map),
+        CliAction::Gc =>
+            rombytes = gc(rombytes, map)?,
+        CliAction::InitTiled =>
+            unreachable!(),
     }
 
     rom.seek(SeekFrom::Start(0))?;
@@ -137,39 +225,182 @@ fn submain() -> Result<(), Box<std::error::Error>> {
     Ok(())
 }
 
-fn insert_level(mut rombytes: Vec<u8>, lvlnum: u16, path: &PathBuf)
--> Result<Vec<u8>, Box<std::error::Error>> {
-    let space = rats::find_free(&rombytes, 0x8000).expect("Need free bank");
-     // points just past RATS_CLNP tag
-    let start = address::Address::new_from_pc(
-        space.pc_ofs() + 12, address::Mapper::Lorom
-    ).unwrap();
+fn gfx_unimplemented(gnum: Option<u16>) -> String {
+    match gnum {
+        Some(n) => format!("graphics file {:02x} can't be inserted or extracted yet", n),
+        None => "graphics can't be inserted or extracted yet".to_string(),
+    }
+}
 
+fn dump_level(rombytes: &[u8], map: Mapper, lvlnum: u16) -> Result<(), Box<dyn Error>> {
+    let start = level_table::get_level_ptr(&rombytes, map, lvlnum)
+        .ok_or_else(|| format!("level {:03x} has no valid level pointer", lvlnum))?;
+
+    let version = level_table::get_version(rombytes, map, lvlnum)?;
+
+    println!("level {:03x} @ {} (PC {:#}), format {}.{}.{}",
+        lvlnum, start, start, version.0, version.1, version.2);
+    let stdout = std::io::stdout();
+    let problems = binlevel::dump_level(&mut stdout.lock(), rombytes, start, map, version)?;
+
+    if problems != 0 {
+        println!("{} problem(s) found", problems);
+    }
+    Ok(())
+}
+
+/// Inserts the level numbered `lvlnum` from a map,
+/// or without a number, every level group in it.
+/// Dumps a level block that the level table might not point to,
+/// like one that `--lint` found, as if it were in the current format.
+fn dump_at(rombytes: &[u8], map: Mapper, start: Address) -> Result<(), Box<dyn Error>> {
+    let (a, b, c) = binlevel::FORMAT_VERSION;
+    println!("level block @ {} (PC {:#}), read as format {}.{}.{}", start, start, a, b, c);
+    let stdout = std::io::stdout();
+    let problems = binlevel::dump_level(&mut stdout.lock(), rombytes, start, map, binlevel::FORMAT_VERSION)?;
+
+    if problems != 0 {
+        println!("{} problem(s) found", problems);
+    }
+    Ok(())
+}
+
+fn insert_level(mut rombytes: Vec<u8>, map: Mapper, lvlnum: Option<u16>, path: &PathBuf, loader_crc: bool)
+-> Result<Vec<u8>, Box<dyn Error>> {
     let mut f = File::open(path)?;
-    let lvl = tmx::read_level(&mut f, "lev", lvlnum)?;
+    // palettes and external tilesets are found relative to the map
+    let dir = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+    let json = matches!(path.extension().and_then(|e| e.to_str()), Some("tmj") | Some("json"));
+    let levels = match lvlnum {
+        Some(n) if json => vec![(n, tmx::read_level_json(&mut f, dir, n)?)],
+        Some(n) => vec![(n, tmx::read_level(&mut f, dir, n)?)],
+        None if json => tmx::read_levels_json(&mut f, dir)?,
+        None => tmx::read_levels(&mut f, dir)?,
+    };
 
-    println!("{:?}", level_table::rm_level(&mut rombytes, lvlnum));
+    for (n, lvl) in levels {
+        println!("inserting level {:03x}", n);
+        rombytes = write_level(rombytes, map, n, &lvl, loader_crc)?;
+    }
+    Ok(rombytes)
+}
 
-    let mut romcur = Cursor::new(rombytes);
-    romcur.seek(SeekFrom::Start(start.pc_ofs() as u64))?;
+/// Checks that every level exlev inserted still decodes,
+/// including that none of them have been written over since.
+fn lint(rombytes: &[u8], map: Mapper) -> Result<(), Box<dyn Error>> {
+    let (mut count, mut bad) = (0, 0);
+    for lvlnum in 0 .. 0x200 {
+        let start = match level_table::get_exlev_level_ptr(rombytes, map, lvlnum) {
+            Some(a) => a,
+            None => continue,
+        };
+        count += 1;
+        let read = level_table::get_version(rombytes, map, lvlnum)
+            .and_then(|version| binlevel::read_level(rombytes, start, map, version).map_err(|e| e.to_string()));
+        if let Err(e) = read {
+            println!("level {:03x}: {}", lvlnum, e);
+            bad += 1;
+        }
+    }
 
-    let start_ptr = start.snes_ofs().unwrap();
+    println!("{} level(s) checked, {} with problems", count, bad);
+    Ok(())
+}
 
-    let len = binlevel::write_level_body(&mut romcur, &lvl, start_ptr as u32)?;
+/// Lists the blocks exlev inserted that nothing points to anymore,
+/// and frees them if the user says to.
+fn gc(mut rombytes: Vec<u8>, map: Mapper) -> Result<Vec<u8>, Box<dyn Error>> {
+    let orphans = level_table::orphaned_blocks(&rombytes, map);
+    let mut total = 0;
+    for t in &orphans {
+        let at = Address::new_from_pc(t.start, map).unwrap();
+        let len = rats::scan::block_end(&rombytes, t) - t.start;
+        println!("orphaned block @ {} (PC {:#}), {} bytes", at, at, len);
+        total += len;
+    }
+    println!("{} orphaned block(s), {} bytes in all", orphans.len(), total);
+    if orphans.is_empty() {
+        return Ok(rombytes);
+    }
 
-    println!("level is {}kB", len / 1024);
+    print!("free them? [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        println!("left them alone");
+        return Ok(rombytes);
+    }
 
-    let (len_lo, len_hi) = (len as u8, (len >> 8) as u8);
+    for t in &orphans {
+        rats::clear(&mut rombytes, t);
+    }
+    println!("freed {} block(s)", orphans.len());
+    Ok(rombytes)
+}
 
-    romcur.seek(SeekFrom::Start(space.pc_ofs() as u64))?;
-    romcur.write_all(&b"STAR"[..])?;
-    romcur.write_all(&[len_lo, len_hi, !len_lo, !len_hi])?;
-    romcur.write_all(&b"CLNP"[..])?;
+/// Re-encodes every level that was written in an older format version.
+fn migrate(mut rombytes: Vec<u8>, map: Mapper, loader_crc: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut count = 0;
+    for lvlnum in 0 .. 0x200 {
+        let start = match level_table::get_exlev_level_ptr(&rombytes, map, lvlnum) {
+            Some(a) => a,
+            None => continue,
+        };
+        let version = level_table::get_version(&rombytes, map, lvlnum)
+            .map_err(|e| format!("level {:03x}: {}", lvlnum, e))?;
+        if version == binlevel::FORMAT_VERSION {
+            continue;
+        }
 
-    let mut rombytes = romcur.into_inner();
+        let lvl = binlevel::read_level(&rombytes, start, map, version)
+            .map_err(|e| format!("level {:03x}: {}", lvlnum, e))?;
+        rombytes = write_level(rombytes, map, lvlnum, &lvl, loader_crc)?;
 
-    level_table::set_level_ptr(rombytes.as_mut_slice(), lvlnum, start_ptr);
+        let (a, b, c) = version;
+        println!("migrated level {:03x} from format {}.{}.{}", lvlnum, a, b, c);
+        count += 1;
+    }
 
+    let (a, b, c) = binlevel::FORMAT_VERSION;
+    println!("{} level(s) migrated to format {}.{}.{}", count, a, b, c);
     Ok(rombytes)
 }
 
+fn write_level(mut rombytes: Vec<u8>, map: Mapper, lvlnum: u16, lvl: &level::Level, loader_crc: bool)
+-> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some((at, len)) = level_table::rm_level(&mut rombytes, map, lvlnum) {
+        println!("removed old level {:03x} @ {}, {} bytes", lvlnum, at, len);
+    }
+
+    // The version table might have to be allocated,
+    // so it needs to happen before the level's own space is picked.
+    level_table::set_version(&mut rombytes, map, lvlnum, binlevel::FORMAT_VERSION)?;
+
+    let mut blob = binlevel::write_level_body(lvl)?;
+    blob.loader_crc = loader_crc;
+    println!("level is {} bytes", blob.len());
+
+    // The level is preceded by the RATS tag and "CLNP".
+    // Its pointers are relocated by adding to them, so it can't cross a bank,
+    // not even when it starts right at the start of one.
+    let before = TAG_LEN + MARKER_LEN;
+    let block = blob.len() + before;
+    if Address::new_from_pc(0, map).unwrap().crosses_bank(block) {
+        return Err(format!(
+            "level is too big to insert ({} bytes, max is {})", blob.len(), map.bank_size() - before
+        ).into());
+    }
+    let space = rats::find_aligned(&rombytes, map, block, 1).ok_or("no freespace big enough for the level")?;
+    assert!(!space.crosses_bank(block), "freespace for a level crosses a bank");
+    let start = space.checked_add(before).unwrap();
+    let start_ptr = start.snes_ofs().unwrap();
+
+    let mut data = level_table::MARKER.to_vec();
+    data.extend(blob.relocate(start_ptr));
+    rats::insert_at(&mut rombytes, space, &data);
+
+    level_table::set_level_ptr(rombytes.as_mut_slice(), map, lvlnum, start_ptr);
+
+    Ok(rombytes)
+}
@@ -0,0 +1,249 @@
+pub mod scan;
+
+use address::{Address, Mapper};
+use self::scan::TAG_LEN;
+
+pub fn find_free(rombytes: &[u8], map: Mapper, len: usize) -> Option<Address> {
+    find_aligned(rombytes, map, len, 0)
+}
+
+/// The original game's 512 KiB, which is never freespace.
+pub const FREESPACE_START: usize = 0x8_0000;
+
+/// Finds `len` bytes of freespace that start at a multiple of `1 << align`
+/// and don't cross one of `map`'s banks.
+/// Banks that `map` doesn't show at all, like SA-1 segments
+/// that aren't in a slot, are skipped, as is anything a RATS tag protects.
+pub fn find_aligned(rombytes: &[u8], map: Mapper, len: usize, align: u8) -> Option<Address> {
+    let bank = map.bank_size();
+    assert!(len <= bank, "too long freespace to exist");
+    assert!(align < 17, "too high alignment for freespace search");
+
+    let filt = (1 << align) - 1;
+    let align_up = |i: usize| (i + filt) & !filt;
+    let mut i = FREESPACE_START;
+    // the start of the current block of free bytes
+    let mut start = align_up(i);
+    // the bank `i` was last checked to be in
+    let mut checked = None;
+    let mut tags = scan::tags(rombytes, i).peekable();
+
+    while i < rombytes.len() {
+        if checked != Some(i / bank) {
+            checked = Some(i / bank);
+            if Address::new_from_pc(i, map).and_then(|a| a.snes_ofs()).is_none() {
+                i = (i / bank + 1) * bank;
+                start = align_up(i);
+                continue;
+            }
+        }
+        // skipping a bank can go past some tags, or into the middle of one
+        if let Some(t) = tags.peek().filter(|t| t.start <= i).cloned() {
+            tags.next();
+            let end = scan::block_end(rombytes, &t);
+            if end > i {
+                i = end;
+                start = align_up(i);
+            }
+            continue;
+        }
+        i += 1;
+        if i >= start && i - start >= len {
+            return Address::new_from_pc(start, map);
+        }
+        // reset on bank boundaries
+        if i.is_multiple_of(bank) {
+            start = align_up(i);
+        }
+    }
+    None
+}
+
+pub fn insert<W: ::std::io::Write>(buf: &mut W, data: &[u8]) {
+    assert!(data.len() <= 0x1_0000, "tried to insert too large (>64KiB) object");
+    assert!(!data.is_empty(), "tried to insert zero-length object");
+    // the tag stores len - 1, so that it can go up to 64 KiB
+    let len = (data.len() - 1) as u16;
+    let tag = &[
+        b'S', b'T', b'A', b'R',
+        len as u8, (len >> 8) as u8,
+        (!len) as u8, (!len >> 8) as u8,
+    ];
+    buf.write_all(tag).unwrap();
+    buf.write_all(data).unwrap();
+}
+
+pub fn insert_free(rombytes: &mut [u8], map: Mapper, data: &[u8]) -> Option<Address> {
+    let block = data.len() + TAG_LEN;
+    find_free(&*rombytes, map, block).map(|a| insert_at(rombytes, a, data))
+}
+
+/// Writes a RATS tag and `data` at `at`, which should be freespace
+/// with room for both. Returns the address of `data`.
+pub fn insert_at(rombytes: &mut [u8], at: Address, data: &[u8]) -> Address {
+    let ofs = at.pc_ofs();
+    let block = data.len() + TAG_LEN;
+    // Write is only impl'd for `&mut [u8]`, and we need &mut (something with Write)
+    insert(&mut &mut rombytes[ofs .. ofs + block], data);
+    at.checked_add(TAG_LEN).unwrap()
+}
+
+/// Zeroes out `tag` and everything it protects, and returns how many bytes that was.
+pub fn clear(rombytes: &mut [u8], tag: &scan::Tag) -> usize {
+    let end = scan::block_end(rombytes, tag).min(rombytes.len());
+    for b in &mut rombytes[tag.start .. end] {
+        *b = 0;
+    }
+    end - tag.start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address::BOOT_SA1ROM;
+
+    /// Puts a tag protecting `len` bytes of 0x55 at `at`.
+    fn tag_at(rom: &mut [u8], at: usize, len: usize) {
+        insert(&mut &mut rom[at .. at + TAG_LEN + len], &vec![0x55; len]);
+    }
+
+    /// Protects every bank from `from` up to `to`.
+    fn fill_banks(rom: &mut [u8], from: usize, to: usize) {
+        for at in (from .. to).step_by(0x8000) {
+            tag_at(rom, at, 0x8000 - TAG_LEN);
+        }
+    }
+
+    fn found(rom: &[u8], map: Mapper, len: usize, align: u8) -> Option<usize> {
+        find_aligned(rom, map, len, align).map(|a| a.pc_ofs())
+    }
+
+    #[test]
+    fn starts_after_the_original_game() {
+        let rom = vec![0; 0x10_0000];
+        assert_eq!(found(&rom, Mapper::Lorom, 0x10, 0), Some(FREESPACE_START));
+        assert_eq!(found(&rom, Mapper::Lorom, 0x8000, 0), Some(FREESPACE_START));
+        assert_eq!(found(&rom[.. FREESPACE_START], Mapper::Lorom, 1, 0), None);
+    }
+
+    #[test]
+    fn skips_tags() {
+        let mut rom = vec![0; 0x10_0000];
+        tag_at(&mut rom, 0x8_0000, 0x20);
+        tag_at(&mut rom, 0x8_0030, 0x10);
+        assert_eq!(found(&rom, Mapper::Lorom, 8, 0), Some(0x8_0028));
+        assert_eq!(found(&rom, Mapper::Lorom, 9, 0), Some(0x8_0048));
+
+        // a tag with a bad complement only keeps itself from being written over
+        let mut rom = vec![0; 0x10_0000];
+        rom[0x8_0000 .. 0x8_0008].copy_from_slice(b"STAR\x10\x00\x00\x00");
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 0), Some(0x8_0008));
+    }
+
+    #[test]
+    fn resets_at_banks() {
+        let mut rom = vec![0; 0x10_0000];
+        tag_at(&mut rom, 0x8_0000, 0x7ff0 - TAG_LEN);
+        assert_eq!(found(&rom, Mapper::Lorom, 0x10, 0), Some(0x8_7ff0));
+        assert_eq!(found(&rom, Mapper::Lorom, 0x11, 0), Some(0x8_8000));
+        // HiROM banks are twice as big
+        assert_eq!(found(&rom, Mapper::Hirom, 0x11, 0), Some(0x8_7ff0));
+        assert_eq!(found(&rom, Mapper::Hirom, 0x8011, 0), Some(0x9_0000));
+    }
+
+    #[test]
+    fn tag_at_a_bank_edge() {
+        let mut rom = vec![0; 0x10_0000];
+        // the tag is in one bank, and what it protects in the next
+        tag_at(&mut rom, 0x8_7ffc, 0x10);
+        assert_eq!(found(&rom, Mapper::Lorom, 0x7ffc, 0), Some(0x8_0000));
+        assert_eq!(found(&rom, Mapper::Lorom, 0x7ffd, 0), Some(0x9_0000));
+        assert_eq!(found(&rom, Mapper::Lorom, 0x10, 0), Some(0x8_0000));
+        tag_at(&mut rom, 0x8_0000, 0x7ff4 - TAG_LEN);
+        assert_eq!(found(&rom, Mapper::Lorom, 0x10, 0), Some(0x8_8014));
+        assert_eq!(found(&rom, Mapper::Lorom, 0x7fec, 0), Some(0x8_8014));
+        assert_eq!(found(&rom, Mapper::Lorom, 0x7fed, 0), Some(0x9_0000));
+    }
+
+    #[test]
+    fn alignment() {
+        let mut rom = vec![0; 0x20_0000];
+        tag_at(&mut rom, 0x8_0000, 1);
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 0), Some(0x8_0009));
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 1), Some(0x8_000a));
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 4), Some(0x8_0010));
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 15), Some(0x8_8000));
+        // more than a LoROM bank, so the next bank isn't aligned enough
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 16), Some(0x9_0000));
+    }
+
+    #[test]
+    fn old_level_before_freespace() {
+        let mut rom = vec![0; 0x10_0000];
+        let old = scan::tests::old_level(0x8_0000, 0x40);
+        rom[0x8_0000 .. 0x8_004c].copy_from_slice(&old);
+        // the last 3 bytes of the level aren't freespace, even though its tag leaves them out
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 0), Some(0x8_004c));
+
+        // nor are they when a level was inserted right after them
+        tag_at(&mut rom, 0x8_004c, 4);
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 0), Some(0x8_0058));
+    }
+
+    #[test]
+    fn old_insert_before_a_tag() {
+        let mut rom = vec![0; 0x10_0000];
+        let old = scan::tests::old_insert(&[0x55; 4]);
+        rom[0x8_0000 .. 0x8_000c].copy_from_slice(&old);
+        tag_at(&mut rom, 0x8_000c, 4);
+        assert_eq!(found(&rom, Mapper::Lorom, 4, 0), Some(0x8_0018));
+    }
+
+    #[test]
+    fn skips_unmapped_sa1_segments() {
+        let mut rom = vec![0; 0x60_0000];
+        fill_banks(&mut rom, FREESPACE_START, 0x30_0000);
+        // segments 3 and 4 aren't in any slot
+        let map = Mapper::Sa1rom([0, 1, 2, 5]);
+        assert_eq!(found(&rom, map, 0x10, 0), Some(0x50_0000));
+        assert_eq!(found(&rom, BOOT_SA1ROM, 0x10, 0), Some(0x30_0000));
+        fill_banks(&mut rom, 0x30_0000, 0x40_0000);
+        assert_eq!(found(&rom, BOOT_SA1ROM, 0x10, 0), None);
+    }
+
+    #[test]
+    fn clears_a_block() {
+        let mut rom = vec![0xaa; 0x40];
+        tag_at(&mut rom, 0x10, 0x10);
+        let t = scan::read_tag(&rom[0x10 ..], 0x10).unwrap();
+        assert_eq!(clear(&mut rom, &t), TAG_LEN + 0x10);
+        assert!(rom[0x10 .. 0x28].iter().all(|&b| b == 0));
+        assert!(rom[.. 0x10].iter().chain(&rom[0x28 ..]).all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn clears_all_of_an_old_level() {
+        let mut rom = scan::tests::old_level(0, 0x40);
+        rom.extend(vec![0xaa; 0x10]);
+        let t = scan::read_tag(&rom, 0).unwrap();
+        assert_eq!(clear(&mut rom, &t), 0x4c);
+        assert!(rom[.. 0x4c].iter().all(|&b| b == 0));
+        assert!(rom[0x4c ..].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn clear_leaves_the_next_tag_after_an_old_one() {
+        let mut rom = scan::tests::old_insert(&[0x55; 4]);
+        rom.extend(vec![0; TAG_LEN + 4]);
+        tag_at(&mut rom, 12, 4);
+        let t = scan::read_tag(&rom, 0).unwrap();
+        assert_eq!(clear(&mut rom, &t), 12);
+        assert!(rom[.. 12].iter().all(|&b| b == 0));
+        assert!(scan::read_tag(&rom[12 ..], 12).unwrap().valid);
+
+        // an old tag at the end of the ROM doesn't clear past it
+        let mut rom = scan::tests::old_insert(&[0x55; 4]);
+        let t = scan::read_tag(&rom, 0).unwrap();
+        assert_eq!(clear(&mut rom, &t), 12);
+    }
+}
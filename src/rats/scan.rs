@@ -0,0 +1,285 @@
+//! Finds every RATS tag in a ROM.
+//!
+//! A tag is "STAR", then one less than the number of bytes it protects,
+//! then that number's complement, both 16-bit little-endian.
+//! The protected bytes come right after the tag, and often start with a
+//! marker saying which tool put them there, like exlev's "CLNP".
+
+/// The length of a tag, not counting what it protects.
+pub const TAG_LEN: usize = 8;
+/// The length of a marker after a tag.
+pub const MARKER_LEN: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tag {
+    /// The PC offset of the "STAR".
+    pub start: usize,
+    /// How many bytes after the tag it protects.
+    pub len: usize,
+    /// Whether the length's complement matches it.
+    /// A tag where it doesn't is probably just data that happens to say "STAR".
+    pub valid: bool,
+    /// The first bytes of what the tag protects, if they look like a marker:
+    /// 4 capital letters or digits.
+    pub marker: Option<[u8; MARKER_LEN]>,
+}
+
+impl Tag {
+    /// The PC offset of what the tag protects.
+    pub fn data_start(&self) -> usize {
+        self.start + TAG_LEN
+    }
+
+    /// One past the last byte the tag protects.
+    pub fn end(&self) -> usize {
+        self.data_start() + self.len
+    }
+
+    /// One past the last byte that shouldn't be written over because of this tag.
+    /// An invalid tag protects nothing, but it's still not freespace itself.
+    pub fn protected_end(&self) -> usize {
+        if self.valid { self.end() } else { self.data_start() }
+    }
+
+    pub fn has_marker(&self, marker: &[u8; MARKER_LEN]) -> bool {
+        self.valid && self.marker.as_ref() == Some(marker)
+    }
+}
+
+/// How long a level's header was in the formats before 0.2.0,
+/// where it was the last thing in the level.
+const OLD_HEADER_LEN: usize = 18;
+
+/// Where the block `t` protects really ends, which is usually `t.protected_end()`.
+/// Older versions of exlev got the length in the tag wrong in two ways.
+/// Levels from before format 0.2.0 had the length of the level without its
+/// marker, so their tags say they protect 3 bytes less than they do.
+/// After that, blocks had the length itself instead of length - 1,
+/// so their tags say they protect a byte more than they do;
+/// if that byte is where the next tag starts, it isn't this one's.
+pub fn block_end(rombytes: &[u8], t: &Tag) -> usize {
+    let end = t.protected_end();
+    let next_tag = |at: usize| rombytes.get(at ..).and_then(|b| read_tag(b, at)).is_some_and(|n| n.valid);
+    if old_level_len(rombytes, t) == Some(t.len - 1) {
+        end + MARKER_LEN - 1
+    } else if t.valid && next_tag(end - 1) {
+        end - 1
+    } else {
+        end
+    }
+}
+
+/// How long the level after `t`'s marker would be if it were from before
+/// format 0.2.0, going by its pointer to its header.
+/// Those levels were only ever put in LoROM, where the low 15 bits of a
+/// SNES address are the same as its PC offset's, and never crossed a bank.
+fn old_level_len(rombytes: &[u8], t: &Tag) -> Option<usize> {
+    if !t.has_marker(::level_table::MARKER) {
+        return None;
+    }
+    let level = t.data_start() + MARKER_LEN;
+    let ptr = rombytes.get(level + 3 .. level + 5)?;
+    let header = read_u16(ptr) as usize;
+    Some((header.wrapping_sub(level) & 0x7fff) + OLD_HEADER_LEN)
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    buf[0] as u16 | (buf[1] as u16) << 8
+}
+
+/// Reads the tag at the start of `buf`, which is at `start` in the ROM,
+/// whether or not it's valid.
+pub fn read_tag(buf: &[u8], start: usize) -> Option<Tag> {
+    if buf.len() < TAG_LEN || !buf.starts_with(b"STAR") {
+        return None;
+    }
+    let len = read_u16(&buf[4 ..]);
+    let valid = len == !read_u16(&buf[6 ..]);
+    let len = len as usize + 1;
+    let marker = match buf.get(TAG_LEN .. TAG_LEN + MARKER_LEN) {
+        Some(m) if valid && len >= MARKER_LEN
+            && m.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) => {
+            Some([m[0], m[1], m[2], m[3]])
+        },
+        _ => None,
+    };
+    Some(Tag { start, len, valid, marker })
+}
+
+/// Every tag in `rombytes` from `from` on, in order.
+/// What a valid tag protects is skipped, so that data that happens
+/// to have "STAR" in it isn't taken for more tags.
+pub fn tags(rombytes: &[u8], from: usize) -> Tags<'_> {
+    Tags { rom: rombytes, at: from }
+}
+
+pub struct Tags<'r> {
+    rom: &'r [u8],
+    at: usize,
+}
+
+impl<'r> Iterator for Tags<'r> {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        while self.at + TAG_LEN <= self.rom.len() {
+            if let Some(t) = read_tag(&self.rom[self.at ..], self.at) {
+                self.at = block_end(self.rom, &t);
+                return Some(t);
+            }
+            self.at += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use address::{Address, Mapper};
+
+    /// A level `len` bytes long after its marker, with its tag at `pc`
+    /// in a LoROM ROM, the way exlev wrote them before format 0.2.0:
+    /// the tag has the length of the level, and the header is last.
+    pub fn old_level(pc: usize, len: usize) -> Vec<u8> {
+        let n = len as u16;
+        let mut v = b"STAR".to_vec();
+        v.extend(&[n as u8, (n >> 8) as u8, !n as u8, (!n >> 8) as u8]);
+        v.extend(b"CLNP");
+        let level = Address::new_from_pc(pc + TAG_LEN + MARKER_LEN, Mapper::Lorom).unwrap();
+        let header = level.snes_ofs().unwrap() as usize + len - OLD_HEADER_LEN;
+        v.extend(&[0x55; 3]);
+        v.extend(&[header as u8, (header >> 8) as u8, (header >> 16) as u8]);
+        v.extend(&[0, 0]);
+        v.resize(TAG_LEN + MARKER_LEN + len, 0x55);
+        v
+    }
+
+    /// `data` in a block the way `insert` wrote it before format 0.2.0,
+    /// with the length itself in the tag.
+    pub fn old_insert(data: &[u8]) -> Vec<u8> {
+        let n = data.len() as u16;
+        let mut v = b"STAR".to_vec();
+        v.extend(&[n as u8, (n >> 8) as u8, !n as u8, (!n >> 8) as u8]);
+        v.extend(data);
+        v
+    }
+
+    fn tag(len: usize, comp_ok: bool) -> Vec<u8> {
+        let n = (len - 1) as u16;
+        let c = if comp_ok { !n } else { !n ^ 1 };
+        let mut v = b"STAR".to_vec();
+        v.extend(&[n as u8, (n >> 8) as u8, c as u8, (c >> 8) as u8]);
+        v
+    }
+
+    #[test]
+    fn reads_a_tag() {
+        let mut rom = vec![0; 0x10];
+        rom.extend(tag(0x10, true));
+        rom.extend(b"CLNP");
+        rom.extend(vec![0x55; 0x0c]);
+        rom.extend(vec![0; 0x10]);
+
+        let found = tags(&rom, 0).collect::<Vec<_>>();
+        assert_eq!(found, vec![Tag { start: 0x10, len: 0x10, valid: true, marker: Some(*b"CLNP") }]);
+        assert_eq!(found[0].data_start(), 0x18);
+        assert_eq!(found[0].end(), 0x28);
+        assert!(found[0].has_marker(b"CLNP"));
+    }
+
+    #[test]
+    fn skips_what_a_tag_protects() {
+        let mut rom = tag(0x20, true);
+        // this one is data, so it's not a tag
+        rom.extend(&[0; 4]);
+        rom.extend(tag(4, true));
+        rom.extend(vec![0; 0x14]);
+        rom.extend(tag(1, true));
+        rom.push(0xab);
+
+        let found = tags(&rom, 0).collect::<Vec<_>>();
+        assert_eq!(found.iter().map(|t| (t.start, t.len)).collect::<Vec<_>>(), vec![(0, 0x20), (0x28, 1)]);
+        assert_eq!(found[0].marker, None);
+        assert_eq!(found[1].end(), rom.len());
+    }
+
+    #[test]
+    fn bad_complement() {
+        let mut rom = tag(0x100, false);
+        rom.extend(tag(2, true));
+        rom.extend(&[1, 2]);
+
+        let found = tags(&rom, 0).collect::<Vec<_>>();
+        assert_eq!(found.len(), 2);
+        assert!(!found[0].valid);
+        assert_eq!(found[0].protected_end(), TAG_LEN);
+        assert!(found[1].valid);
+        assert_eq!(found[1].start, TAG_LEN);
+    }
+
+    #[test]
+    fn full_bank_and_odd_markers() {
+        // Lunar Magic-style: a whole 32 KiB bank, length field 0x7fff
+        let mut rom = b"STAR\xff\x7f\x00\x80".to_vec();
+        rom.extend(b"clnp");
+        let t = read_tag(&rom, 0x10_0000).unwrap();
+        assert_eq!((t.len, t.valid, t.marker), (0x8000, true, None));
+        assert_eq!(t.end(), 0x10_8008);
+
+        // a marker can't be longer than what's protected
+        let mut rom = tag(2, true);
+        rom.extend(b"CLNP");
+        assert_eq!(read_tag(&rom, 0).unwrap().marker, None);
+    }
+
+    #[test]
+    fn old_level_after_its_tag() {
+        let mut rom = vec![0; 0x8_0000];
+        rom.extend(old_level(0x8_0000, 0x40));
+        rom.extend(tag(4, true));
+        rom.extend(b"CLNP");
+
+        let found = tags(&rom, 0x8_0000).collect::<Vec<_>>();
+        assert_eq!(found.iter().map(|t| t.start).collect::<Vec<_>>(), vec![0x8_0000, 0x8_004c]);
+        // the tag reads as 0x41 bytes, but the level and its marker are 0x44
+        assert_eq!(found[0].len, 0x41);
+        assert_eq!(block_end(&rom, &found[0]), 0x8_004c);
+        assert_eq!(block_end(&rom, &found[1]), rom.len());
+    }
+
+    #[test]
+    fn old_insert_before_a_tag() {
+        // the version table, as `insert` used to write it
+        let mut rom = old_insert(&[0; 512 * 3]);
+        rom.extend(tag(4, true));
+        rom.extend(b"CLNP");
+
+        let found = tags(&rom, 0).collect::<Vec<_>>();
+        assert_eq!(found.iter().map(|t| t.start).collect::<Vec<_>>(), vec![0, 0x608]);
+        assert_eq!(block_end(&rom, &found[0]), 0x608);
+        // a tag that protects one byte too many is fine when nothing's after it
+        assert_eq!(block_end(&rom[.. 0x608], &found[0]), 0x609);
+    }
+
+    #[test]
+    fn not_a_tag() {
+        assert_eq!(read_tag(b"STAR\x00\x00\xff", 0), None);
+        assert_eq!(read_tag(b"STAB\x00\x00\xff\xff", 0), None);
+        assert_eq!(tags(b"xxSTAR\x00\x00", 0).count(), 0);
+        assert_eq!(tags(&tag(4, true), 1).count(), 0);
+    }
+
+    #[test]
+    fn reads_back_what_insert_writes() {
+        for &len in &[1, 4, 0x1234, 0x1_0000] {
+            let data = vec![0x42; len];
+            let mut rom = Vec::new();
+            super::super::insert(&mut rom, &data);
+            let t = read_tag(&rom, 0).unwrap();
+            assert!(t.valid);
+            assert_eq!(t.len, len);
+            assert_eq!(t.end(), rom.len());
+        }
+    }
+}
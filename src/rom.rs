@@ -0,0 +1,214 @@
+//! Works out which `Mapper` a ROM uses from its internal header.
+//!
+//! The header is at $00:FFC0 in whatever the mapper makes of the ROM,
+//! so each mapper has it at a different PC offset. Every place it could be
+//! is read as if it were the header and given a score for how much it looks
+//! like one, and the mapper of the best-looking one wins.
+
+use address::{Mapper, BOOT_SA1ROM, BOOT_SDDROM};
+
+/// A ROM's internal header, or something being read as if it were one.
+#[derive(Debug, Clone, Copy)]
+pub struct Header<'r> {
+    /// Where it was found, as a PC offset.
+    pub pc: usize,
+    bytes: &'r [u8],
+    rom_len: usize,
+}
+
+// Where the header is for LoROM, HiROM and ExHiROM.
+// ExLoROM has it at the same place as LoROM, and the others are LoROM-like.
+const LO_HEADER: usize = 0x00_7fc0;
+const HI_HEADER: usize = 0x00_ffc0;
+const EXHI_HEADER: usize = 0x40_ffc0;
+
+// the header is 0x40 bytes, counting the vectors after it
+const HEADER_LEN: usize = 0x40;
+
+impl<'r> Header<'r> {
+    /// The header at `pc`, if the ROM is long enough to have one there.
+    pub fn at(rombytes: &'r [u8], pc: usize) -> Option<Header<'r>> {
+        rombytes.get(pc .. pc + HEADER_LEN).map(|bytes| Header { pc, bytes, rom_len: rombytes.len() })
+    }
+
+    fn u16_at(&self, ofs: usize) -> u16 {
+        self.bytes[ofs] as u16 | (self.bytes[ofs + 1] as u16) << 8
+    }
+
+    pub fn title(&self) -> &'r [u8] {
+        &self.bytes[0x00 .. 0x15]
+    }
+
+    /// The map mode, without the FastROM bit.
+    pub fn map_mode(&self) -> u8 {
+        self.bytes[0x15] & !0x10
+    }
+
+    pub fn chipset(&self) -> u8 {
+        self.bytes[0x16]
+    }
+
+    /// How big the header says the ROM is, in bytes.
+    pub fn rom_size(&self) -> Option<usize> {
+        match self.bytes[0x17] {
+            n @ 0x07 ..= 0x0d => Some(0x400 << n),
+            _ => None,
+        }
+    }
+
+    pub fn checksum_ok(&self) -> bool {
+        self.u16_at(0x1c) ^ self.u16_at(0x1e) == 0xffff
+    }
+
+    /// The emulation mode reset vector.
+    pub fn reset(&self) -> u16 {
+        self.u16_at(0x3c)
+    }
+
+    /// How much this looks like a real header. Anything that isn't
+    /// above 0 probably isn't one.
+    pub fn score(&self) -> i32 {
+        let mut score = 0;
+        if self.checksum_ok() {
+            score += 4;
+        }
+        // The map mode is the surest sign, since hacks often don't bother
+        // fixing the checksum. It's always 0x2_ or 0x3_ in a real header.
+        let mode = self.bytes[0x15];
+        if mode & 0xe0 == 0x20 {
+            score += 2;
+            if self.mapper() == self.expected() {
+                score += 3;
+            }
+        } else {
+            score -= 4;
+        }
+        // code starts in ROM, and it's a long shot that it starts with BRK
+        match self.reset() {
+            r if r < 0x8000 => score -= 4,
+            _ => score += 1,
+        }
+        match self.rom_size() {
+            Some(size) if size >= self.rom_len => score += 2,
+            Some(_) => score += 1,
+            None => score -= 1,
+        }
+        if self.title().iter().all(|b| (0x20 .. 0x7f).contains(b)) {
+            score += 1;
+        }
+        score
+    }
+
+    /// The mapper for a ROM whose header is where this one is.
+    /// Everything but HiROM and ExHiROM has it where LoROM does,
+    /// so there the header itself has to say which it is.
+    fn expected(&self) -> Mapper {
+        match (self.pc, self.mapper()) {
+            (HI_HEADER, _) => Mapper::Hirom,
+            (EXHI_HEADER, _) => Mapper::Exhirom,
+            (_, Mapper::Hirom) | (_, Mapper::Exhirom) => Mapper::Lorom,
+            (_, m) => m,
+        }
+    }
+
+    /// The mapper the header's map mode and chipset say the ROM uses.
+    pub fn mapper(&self) -> Mapper {
+        match (self.map_mode() & 0x0f, self.chipset()) {
+            (_, 0x34) | (_, 0x35) | (0x03, _) => BOOT_SA1ROM,
+            (_, 0x43) | (_, 0x45) => BOOT_SDDROM,
+            (_, 0x13 ..= 0x15) | (_, 0x1a) => Mapper::Sfxrom,
+            (0x02, _) if self.rom_len > 0x40_0000 => Mapper::Exlorom,
+            (0x05, _) => Mapper::Exhirom,
+            (0x01, _) => Mapper::Hirom,
+            _ => Mapper::Lorom,
+        }
+    }
+}
+
+/// Every place the header could be, best-looking first.
+pub fn headers(rombytes: &[u8]) -> Vec<Header<'_>> {
+    let mut found = [LO_HEADER, HI_HEADER, EXHI_HEADER].iter()
+        .filter_map(|&pc| Header::at(rombytes, pc))
+        .collect::<Vec<_>>();
+    // sort_by_key is stable, so ties go to the lower offset
+    found.sort_by_key(|h| -h.score());
+    found
+}
+
+/// The mapper the ROM's header says it uses,
+/// or `None` if nothing in it looks enough like a header.
+pub fn detect_mapper(rombytes: &[u8]) -> Option<Mapper> {
+    headers(rombytes).first()
+        .filter(|h| h.score() > 0)
+        .map(|h| h.expected())
+}
+
+/// What the mapper is usually called, for showing to the user.
+pub fn mapper_name(map: Mapper) -> &'static str {
+    match map {
+        Mapper::Lorom => "LoROM",
+        Mapper::Hirom => "HiROM",
+        Mapper::Exlorom => "ExLoROM",
+        Mapper::Exhirom => "ExHiROM",
+        Mapper::Sfxrom => "SuperFX",
+        Mapper::Sa1rom(_) => "SA-1",
+        Mapper::Sddrom(_) => "SDD-1",
+    }
+}
+
+/// Reads a mapper's name as given on the command line.
+pub fn mapper_by_name(name: &str) -> Option<Mapper> {
+    Some(match &*name.to_lowercase() {
+        "lorom" => Mapper::Lorom,
+        "hirom" => Mapper::Hirom,
+        "exlorom" => Mapper::Exlorom,
+        "exhirom" => Mapper::Exhirom,
+        "sfxrom" | "superfx" => Mapper::Sfxrom,
+        "sa1rom" | "sa-1" | "sa1" => BOOT_SA1ROM,
+        "sddrom" | "sdd-1" | "sdd1" => BOOT_SDDROM,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_header(len: usize, at: usize, mode: u8, chipset: u8) -> Vec<u8> {
+        let mut rom = vec![0; len];
+        let h = &mut rom[at .. at + HEADER_LEN];
+        h[.. 0x15].copy_from_slice(b"EXLEV TEST           ");
+        h[0x15] = mode;
+        h[0x16] = chipset;
+        h[0x17] = 0x0c;
+        h[0x1c .. 0x20].copy_from_slice(&[0x34, 0x12, 0xcb, 0xed]);
+        h[0x3c .. 0x3e].copy_from_slice(&[0x00, 0x80]);
+        rom
+    }
+
+    #[test]
+    fn detects_mappers() {
+        let cases = [
+            (LO_HEADER, 0x20, 0x02, Mapper::Lorom),
+            (LO_HEADER, 0x30, 0x00, Mapper::Lorom),
+            (HI_HEADER, 0x31, 0x02, Mapper::Hirom),
+            (LO_HEADER, 0x23, 0x35, BOOT_SA1ROM),
+            (LO_HEADER, 0x20, 0x15, Mapper::Sfxrom),
+            (LO_HEADER, 0x32, 0x43, BOOT_SDDROM),
+        ];
+        for &(at, mode, chipset, map) in &cases {
+            let rom = rom_with_header(0x40_0000, at, mode, chipset);
+            assert_eq!(detect_mapper(&rom), Some(map), "mode {:02x} chipset {:02x}", mode, chipset);
+        }
+        let rom = rom_with_header(0x60_0000, EXHI_HEADER, 0x35, 0x02);
+        assert_eq!(detect_mapper(&rom), Some(Mapper::Exhirom));
+        let rom = rom_with_header(0x60_0000, LO_HEADER, 0x32, 0x02);
+        assert_eq!(detect_mapper(&rom), Some(Mapper::Exlorom));
+    }
+
+    #[test]
+    fn no_header() {
+        assert_eq!(detect_mapper(&vec![0; 0x40_0000]), None);
+        assert_eq!(detect_mapper(&vec![0; 0x100]), None);
+    }
+}
@@ -156,7 +156,23 @@ impl SnesPal {
         }
         v
     }
-    
+
+    pub fn from_binary_snes(bytes: &[u8]) -> Option<SnesPal> {
+        if bytes.len() < 514 {
+            return None;
+        }
+
+        let word = |i: usize| bytes[i * 2] as u16 | ((bytes[i * 2 + 1] as u16) << 8);
+
+        let bg = SnesColor::from_snes(word(0));
+        let mut colors = [bg; 256];
+        for (i, c) in colors.iter_mut().enumerate() {
+            *c = SnesColor::from_snes(word(i + 1));
+        }
+
+        Some(SnesPal {bg, colors})
+    }
+
     pub fn iter(&self) -> SnesPalIter {
         SnesPalIter { idx: 0, pal: &self }
     }
@@ -1,5 +1,6 @@
 use std::collections::BTreeSet;
 use std::cmp;
+use std::hash::Hasher;
 
 #[derive(Copy, Clone, Debug)]
 pub struct SpritePlacement {
@@ -36,6 +37,24 @@ impl SpritePlacement {
         (self.pos_y as usize) % 16
     }
 
+    /// Whether two sprites are the same, other than what screen they're on.
+    pub fn eq_local(&self, other: &SpritePlacement) -> bool {
+        self.id == other.id
+        && self.local_ofs_x() == other.local_ofs_x()
+        && self.local_ofs_y() == other.local_ofs_y()
+        && self.xbit == other.xbit
+        && self.xbytes == other.xbytes
+    }
+
+    /// Hashes the parts of the sprite that `eq_local` compares.
+    pub fn hash_local<H: Hasher>(&self, state: &mut H) {
+        state.write_u16(self.id);
+        state.write_usize(self.local_ofs_x());
+        state.write_usize(self.local_ofs_y());
+        state.write_u8(self.xbit as u8);
+        state.write(&self.xbytes);
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let long = self.xbytes[1] != 0 || self.xbytes[2] != 0 || self.xbytes[3] != 0;
         let mut out = vec![
@@ -53,6 +72,9 @@ impl SpritePlacement {
     }
     
     pub const TERMINATOR_BYTES: [u8; 4] = [0x80, 0, 0, 0];
+
+    /// The highest sprite number a record has room for.
+    pub const MAX_ID: u16 = 0x3ff;
 }
 
 impl cmp::Ord for SpritePlacement {
@@ -0,0 +1,103 @@
+//! Decoding of standard (RFC 4648) base64, which is how Tiled stores
+//! non-CSV layer data. Whitespace is skipped, since Tiled indents the data.
+
+fn sextet(c: u8) -> Option<u32> {
+    match c {
+        b'A' ..= b'Z' => Some((c - b'A') as u32),
+        b'a' ..= b'z' => Some((c - b'a') as u32 + 26),
+        b'0' ..= b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let mut acc = 0_u32;
+    let mut count = 0;
+    let mut padding = 0;
+
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            padding += 1;
+            continue;
+        }
+        // nothing but padding can come after padding
+        if padding != 0 {
+            return None;
+        }
+        acc = acc << 6 | sextet(c)?;
+        count += 1;
+        if count == 4 {
+            out.extend_from_slice(&[(acc >> 16) as u8, (acc >> 8) as u8, acc as u8]);
+            acc = 0;
+            count = 0;
+        }
+    }
+
+    match (count, padding) {
+        (0, 0) => (),
+        (2, 2) | (2, 0) => out.push((acc >> 4) as u8),
+        (3, 1) | (3, 0) => out.extend_from_slice(&[(acc >> 10) as u8, (acc >> 2) as u8]),
+        _ => return None,
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_4648_vectors() {
+        let cases: [(&str, &[u8]); 7] = [
+            ("", b""),
+            ("Zg==", b"f"),
+            ("Zm8=", b"fo"),
+            ("Zm9v", b"foo"),
+            ("Zm9vYg==", b"foob"),
+            ("Zm9vYmE=", b"fooba"),
+            ("Zm9vYmFy", b"foobar"),
+        ];
+        for &(s, bytes) in &cases {
+            assert_eq!(decode(s), Some(bytes.to_vec()), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn padding_is_optional() {
+        assert_eq!(decode("Zg"), Some(b"f".to_vec()));
+        assert_eq!(decode("Zm8"), Some(b"fo".to_vec()));
+        assert_eq!(decode("Zm9vYg"), Some(b"foob".to_vec()));
+    }
+
+    #[test]
+    fn all_sextets() {
+        assert_eq!(decode("+/+/"), Some(vec![0xfb, 0xff, 0xbf]));
+        assert_eq!(decode("AAAA"), Some(vec![0, 0, 0]));
+        assert_eq!(decode("////"), Some(vec![0xff, 0xff, 0xff]));
+    }
+
+    #[test]
+    fn skips_whitespace() {
+        assert_eq!(decode("\n   Zm9v\n   YmFy\n  "), Some(b"foobar".to_vec()));
+        assert_eq!(decode("Zm 9v\tYg =\r\n="), Some(b"foob".to_vec()));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        // not in the alphabet
+        assert_eq!(decode("Zm9v*mFy"), None);
+        assert_eq!(decode("Zm9-"), None);
+        // data after padding
+        assert_eq!(decode("Zg==Zm9v"), None);
+        assert_eq!(decode("Zm8=v"), None);
+        // wrong amounts of padding, or a lone sextet
+        assert_eq!(decode("Zg="), None);
+        assert_eq!(decode("Zm8=="), None);
+        assert_eq!(decode("Zm9v="), None);
+        assert_eq!(decode("Z"), None);
+        assert_eq!(decode("Zm9vY"), None);
+    }
+}
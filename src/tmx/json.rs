@@ -0,0 +1,411 @@
+//! A small JSON parser, enough to read the maps and tilesets Tiled saves,
+//! and a writer for the files exlev sets Tiled up with.
+//!
+//! Objects keep their keys in the order they were written,
+//! since nothing here is big enough for lookups to need a map.
+
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Looks up `key`, if this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref kvs) => kvs.iter().find(|kv| kv.0 == key).map(|kv| &kv.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the value out as JSON, indented the way Tiled writes its files.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.write_indented(f, 0)
+    }
+}
+
+impl Json {
+    fn write_indented(&self, f: &mut fmt::Formatter, depth: usize) -> Result<(), fmt::Error> {
+        let pad = |f: &mut fmt::Formatter, depth: usize| write!(f, "{:1$}", "", depth * 4);
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref v) if v.is_empty() => write!(f, "[]"),
+            Json::Object(ref kvs) if kvs.is_empty() => write!(f, "{{}}"),
+            Json::Array(ref v) => {
+                writeln!(f, "[")?;
+                for (i, e) in v.iter().enumerate() {
+                    pad(f, depth + 1)?;
+                    e.write_indented(f, depth + 1)?;
+                    writeln!(f, "{}", if i + 1 < v.len() { "," } else { "" })?;
+                }
+                pad(f, depth)?;
+                write!(f, "]")
+            },
+            Json::Object(ref kvs) => {
+                writeln!(f, "{{")?;
+                for (i, (k, v)) in kvs.iter().enumerate() {
+                    pad(f, depth + 1)?;
+                    write_string(f, k)?;
+                    write!(f, ": ")?;
+                    v.write_indented(f, depth + 1)?;
+                    writeln!(f, "{}", if i + 1 < kvs.len() { "," } else { "" })?;
+                }
+                pad(f, depth)?;
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> Result<(), fmt::Error> {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+#[derive(Debug, Clone)]
+pub struct JsonError {
+    pub line: usize,
+    pub col: usize,
+    pub msg: &'static str,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "line {}, column {}: {}", self.line, self.col, self.msg)
+    }
+}
+
+impl error::Error for JsonError {
+    fn description(&self) -> &str {
+        "failure parsing JSON"
+    }
+}
+
+pub fn parse(s: &str) -> Result<Json, JsonError> {
+    let mut p = Parser { s: s.as_bytes(), idx: 0 };
+    let v = p.value(0)?;
+    p.skip_ws();
+    if p.idx != p.s.len() {
+        return Err(p.error("trailing characters after the document"));
+    }
+    Ok(v)
+}
+
+// Deeper than this is surely not a map, and would overflow the stack eventually.
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'a> {
+    s: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &'static str) -> JsonError {
+        let before = &self.s[.. self.idx.min(self.s.len())];
+        let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+        let col = before.iter().rev().take_while(|&&c| c != b'\n').count() + 1;
+        JsonError { line, col, msg }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.idx).cloned()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.idx += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8, msg: &'static str) -> Result<(), JsonError> {
+        self.skip_ws();
+        if self.peek() == Some(c) {
+            self.idx += 1;
+            Ok(())
+        } else {
+            Err(self.error(msg))
+        }
+    }
+
+    fn literal(&mut self, word: &'static str, v: Json) -> Result<Json, JsonError> {
+        if self.s[self.idx ..].starts_with(word.as_bytes()) {
+            self.idx += word.len();
+            Ok(v)
+        } else {
+            Err(self.error("unknown literal"))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        self.skip_ws();
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-') | Some(b'0' ..= b'9') => self.number(),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.idx += 1;
+        let mut kvs = Vec::new();
+        self.skip_ws();
+        if self.peek() == Some(b'}') {
+            self.idx += 1;
+            return Ok(Json::Object(kvs));
+        }
+        loop {
+            self.skip_ws();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.expect(b':', "expected ':' after a key")?;
+            let v = self.value(depth + 1)?;
+            kvs.push((key, v));
+            self.skip_ws();
+            match self.peek() {
+                Some(b',') => self.idx += 1,
+                Some(b'}') => {
+                    self.idx += 1;
+                    return Ok(Json::Object(kvs));
+                },
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.idx += 1;
+        let mut vs = Vec::new();
+        self.skip_ws();
+        if self.peek() == Some(b']') {
+            self.idx += 1;
+            return Ok(Json::Array(vs));
+        }
+        loop {
+            vs.push(self.value(depth + 1)?);
+            self.skip_ws();
+            match self.peek() {
+                Some(b',') => self.idx += 1,
+                Some(b']') => {
+                    self.idx += 1;
+                    return Ok(Json::Array(vs));
+                },
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.idx;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0' ..= b'9') = self.peek() {
+            self.idx += 1;
+        }
+        // the slice is all ASCII, so it's valid UTF-8
+        let text = ::std::str::from_utf8(&self.s[start .. self.idx]).unwrap();
+        text.parse::<f64>().map(Json::Number).map_err(|_| {
+            self.idx = start;
+            self.error("invalid number")
+        })
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.s.get(self.idx .. self.idx + 4).ok_or_else(|| self.error("truncated \\u escape"))?;
+        // from_str_radix would take a sign too
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(self.error("invalid \\u escape"));
+        }
+        let text = ::std::str::from_utf8(digits).unwrap();
+        let v = u32::from_str_radix(text, 16).map_err(|_| self.error("invalid \\u escape"))?;
+        self.idx += 4;
+        Ok(v)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.idx += 1;
+        let mut out = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.idx += 1;
+                    break;
+                },
+                Some(b'\\') => {
+                    self.idx += 1;
+                    let c = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.idx += 1;
+                    let ch = match c {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut cp = self.hex4()?;
+                            // a surrogate pair is two escapes
+                            if (0xd800 .. 0xdc00).contains(&cp) && self.s[self.idx ..].starts_with(b"\\u") {
+                                self.idx += 2;
+                                let lo = self.hex4()?;
+                                if !(0xdc00 .. 0xe000).contains(&lo) {
+                                    return Err(self.error("invalid surrogate pair"));
+                                }
+                                cp = 0x10000 + ((cp - 0xd800) << 10) + (lo - 0xdc00);
+                            }
+                            ::std::char::from_u32(cp).ok_or_else(|| self.error("invalid \\u escape"))?
+                        },
+                        _ => return Err(self.error("unknown escape")),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                },
+                Some(c) => {
+                    out.push(c);
+                    self.idx += 1;
+                },
+            }
+        }
+        // the input was a &str and escapes were encoded properly, so this is valid
+        Ok(String::from_utf8(out).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(v: &str) -> Json {
+        Json::String(v.to_string())
+    }
+
+    #[test]
+    fn literals_and_numbers() {
+        assert_eq!(parse("null").ok(), Some(Json::Null));
+        assert_eq!(parse(" true ").ok(), Some(Json::Bool(true)));
+        assert_eq!(parse("false").ok(), Some(Json::Bool(false)));
+        let numbers = [("0", 0.0), ("-12", -12.0), ("3.25", 3.25), ("1e3", 1000.0), ("-2.5E-1", -0.25), ("4294967295", 4294967295.0)];
+        for &(text, n) in &numbers {
+            assert_eq!(parse(text).ok(), Some(Json::Number(n)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(parse(r#""plain""#).ok(), Some(s("plain")));
+        assert_eq!(parse(r#""\"\\\/\b\f\n\r\t""#).ok(), Some(s("\"\\/\u{8}\u{c}\n\r\t")));
+        assert_eq!(parse(r#""\u0041\u00e9\u2603""#).ok(), Some(s("A\u{e9}\u{2603}")));
+        assert_eq!(parse(r#""caf\u00E9 ☃""#).ok(), Some(s("caf\u{e9} \u{2603}")));
+        // U+1F600, as a surrogate pair
+        assert_eq!(parse(r#""\ud83d\ude00""#).ok(), Some(s("\u{1f600}")));
+    }
+
+    #[test]
+    fn nesting() {
+        let v = parse(r#"{"a": [1, {"b": []}, {}], "c": {"d": "e"}}"#).unwrap();
+        assert_eq!(v, Json::Object(vec![
+            ("a".to_string(), Json::Array(vec![
+                Json::Number(1.0),
+                Json::Object(vec![("b".to_string(), Json::Array(vec![]))]),
+                Json::Object(vec![]),
+            ])),
+            ("c".to_string(), Json::Object(vec![("d".to_string(), s("e"))])),
+        ]));
+        assert_eq!(v.get("c").and_then(|c| c.get("d")).and_then(Json::as_str), Some("e"));
+        assert_eq!(v.get("a").and_then(Json::as_array).map(|a| a.len()), Some(3));
+        assert_eq!(v.get("z"), None);
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let text = "{\n    \"a\": [\n        1,\n        \"x\\\"y\\n\"\n    ],\n    \"b\": {},\n    \"c\": []\n}";
+        let v = parse(text).unwrap();
+        assert_eq!(v.to_string(), text);
+        assert_eq!(parse(&v.to_string()).ok(), Some(v));
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        let bad = [
+            "", "   ", "{", "[", "[1,", "[1 2]", "{\"a\" 1}", "{\"a\": 1,}", "{1: 2}", "[1,]",
+            "\"abc", "\"\\", "\"\\x\"", "\"\\u12\"", "\"\\u+123\"", "\"\\uzzzz\"",
+            // a lone surrogate, and a pair with a bad second half
+            "\"\\ud83d\"", "\"\\ude00\"", "\"\\ud83d\\u0041\"",
+            "tru", "nul", "-", "1.2.3", "--1", "+1", "1 2", "{} x", "@",
+        ];
+        for text in &bad {
+            assert!(parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn error_positions() {
+        let e = parse("{\n  \"a\": tru\n}").unwrap_err();
+        assert_eq!((e.line, e.col), (2, 8));
+        assert_eq!(e.msg, "unknown literal");
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let deep = "[".repeat(MAX_DEPTH + 2) + &"]".repeat(MAX_DEPTH + 2);
+        assert!(parse(&deep).is_err());
+        let ok = "[".repeat(MAX_DEPTH) + &"]".repeat(MAX_DEPTH);
+        assert!(parse(&ok).is_ok());
+    }
+}
@@ -0,0 +1,276 @@
+//! The parts of a Tiled map that exlev cares about,
+//! with nothing left that depends on whether it came from TMX or JSON.
+//! `xml` and `tmj` read the two formats into this, and `read` makes
+//! a `Level` out of it.
+
+use super::TmxError;
+use super::base64;
+use super::tileset::Tileset;
+use compression::inflate;
+
+#[derive(Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    /// Tiled's name for the type: "string", "int", "bool", "file" and so on.
+    pub ty: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Properties(pub Vec<Property>);
+
+impl Properties {
+    pub fn get(&self, name: &str) -> Option<&Property> {
+        self.0.iter().find(|p| p.name == name)
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, Property> {
+        self.0.iter()
+    }
+
+    /// These properties, plus any of `base`'s that they don't replace.
+    pub fn over(mut self, base: Properties) -> Properties {
+        for p in base.0 {
+            if self.get(&p.name).is_none() {
+                self.0.push(p);
+            }
+        }
+        self
+    }
+}
+
+pub struct Map {
+    pub properties: Properties,
+    pub tilesets: Vec<Tileset>,
+    pub layers: Vec<Layer>,
+}
+
+pub enum Layer {
+    Tiles(TileLayer),
+    Objects(ObjectGroup),
+    Group(Group),
+}
+
+pub struct TileLayer {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Row-major, with 0 for no tile.
+    /// These are raw, so they still have their flip flags.
+    pub gids: Vec<u32>,
+}
+
+/// A group layer. One with a `level-number` property is a whole level
+/// of its own; any other is only there to organize the layers in it.
+pub struct Group {
+    pub name: String,
+    pub properties: Properties,
+    pub layers: Vec<Layer>,
+}
+
+impl Group {
+    pub fn is_level(&self) -> bool {
+        self.properties.get("level-number").is_some()
+    }
+}
+
+pub struct ObjectGroup {
+    pub name: String,
+    pub objects: Vec<Object>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub id: u32,
+    pub name: String,
+    /// Raw, like a `TileLayer`'s gids.
+    pub gid: Option<u32>,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub properties: Properties,
+}
+
+// Tiled keeps how a tile is flipped in the top bits of its gid.
+const FLIP_H: u32 = 0x8000_0000;
+const FLIP_V: u32 = 0x4000_0000;
+const FLIP_D: u32 = 0x2000_0000;
+// only used by hexagonal maps, but it's still not part of the tile ID
+const ROTATE_HEX: u32 = 0x1000_0000;
+
+/// A gid split into the tile it refers to and how that tile is flipped.
+/// Tiled does 90-degree rotations as a diagonal flip plus an H or V flip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gid {
+    pub id: u32,
+    pub flip_h: bool,
+    pub flip_v: bool,
+    pub flip_d: bool,
+}
+
+impl Gid {
+    pub fn from_raw(raw: u32) -> Gid {
+        Gid {
+            id: raw & !(FLIP_H | FLIP_V | FLIP_D | ROTATE_HEX),
+            flip_h: raw & FLIP_H != 0,
+            flip_v: raw & FLIP_V != 0,
+            flip_d: raw & FLIP_D != 0,
+        }
+    }
+
+    pub fn is_flipped(&self) -> bool {
+        self.flip_h || self.flip_v || self.flip_d
+    }
+
+    /// The name of the flip, like "flip-h" or "flip-hvd".
+    /// A tile in the tileset can have a property of this name
+    /// to say which tile to use when it's flipped this way.
+    pub fn flip_name(&self) -> String {
+        let mut s = String::from("flip-");
+        if self.flip_h { s.push('h'); }
+        if self.flip_v { s.push('v'); }
+        if self.flip_d { s.push('d'); }
+        s
+    }
+}
+
+/// The part of a map that one level is made from:
+/// either the whole map, or one of its level groups.
+pub struct Scope<'m> {
+    /// The level group's name, if it's one.
+    pub group: Option<&'m str>,
+    /// The map's properties, with the level group's over them.
+    pub properties: Properties,
+    pub tilesets: &'m [Tileset],
+    layers: &'m [Layer],
+}
+
+impl Map {
+    /// The map as one level, made of every layer that isn't in a level group.
+    pub fn whole(&self) -> Scope<'_> {
+        Scope {
+            group: None,
+            properties: self.properties.clone(),
+            tilesets: &self.tilesets,
+            layers: &self.layers,
+        }
+    }
+
+    /// Each of the map's level groups as a level.
+    pub fn level_groups(&self) -> Vec<Scope<'_>> {
+        let mut groups = Vec::new();
+        find_level_groups(&self.layers, &mut groups);
+        groups.into_iter().map(|g| Scope {
+            group: Some(&g.name),
+            properties: g.properties.clone().over(self.properties.clone()),
+            tilesets: &self.tilesets,
+            layers: &g.layers,
+        }).collect()
+    }
+}
+
+fn find_level_groups<'m>(layers: &'m [Layer], found: &mut Vec<&'m Group>) {
+    for l in layers {
+        if let Layer::Group(ref g) = *l {
+            if g.is_level() {
+                found.push(g);
+            } else {
+                find_level_groups(&g.layers, found);
+            }
+        }
+    }
+}
+
+/// Every layer in `layers` that belongs to the same level,
+/// looking inside groups that aren't levels of their own.
+fn level_layers<'m>(layers: &'m [Layer]) -> Box<dyn Iterator<Item = &'m Layer> + 'm> {
+    Box::new(layers.iter().flat_map(|l| -> Box<dyn Iterator<Item = &'m Layer> + 'm> {
+        match *l {
+            Layer::Group(ref g) if g.is_level() => Box::new(::std::iter::empty()),
+            Layer::Group(ref g) => level_layers(&g.layers),
+            _ => Box::new(::std::iter::once(l)),
+        }
+    }))
+}
+
+impl<'m> Scope<'m> {
+    /// Finds the tile layer named `name`, which the level has to have exactly one of.
+    pub fn tile_layer(&self, name: &str) -> Result<&'m TileLayer, TmxError> {
+        match self.optional_tile_layer(name) {
+            Ok(Some(t)) => Ok(t),
+            _ => Err(format!("need exactly 1 layer named \"{}\"", name).into()),
+        }
+    }
+
+    /// Finds the tile layer named `name`, which the level can have at most one of.
+    pub fn optional_tile_layer(&self, name: &str) -> Result<Option<&'m TileLayer>, TmxError> {
+        let mut found = level_layers(self.layers).filter_map(|l| match *l {
+            Layer::Tiles(ref t) if t.name == name => Some(t),
+            _ => None,
+        });
+        match (found.next(), found.next()) {
+            (t, None) => Ok(t),
+            _ => Err(format!("there's more than 1 layer named \"{}\"", name).into()),
+        }
+    }
+
+    /// All the objects in the level's object groups named `name`.
+    pub fn objects(&self, name: &'m str) -> impl Iterator<Item = &'m Object> + 'm {
+        level_layers(self.layers).filter_map(move |l| match *l {
+            Layer::Objects(ref g) if g.name == name => Some(g),
+            _ => None,
+        }).flat_map(|g| g.objects.iter())
+    }
+}
+
+/// The error for the `i`th tile of a layer not being a number.
+pub fn bad_tile_id(layer: &str, width: u32, i: usize) -> TmxError {
+    let width = width.max(1) as usize;
+    format!("invalid tile ID at ({}, {}) in layer {}", i % width, i / width, layer).into()
+}
+
+/// Decodes base64 layer data, with the compression Tiled says it has,
+/// into little-endian 32-bit gids.
+pub fn gids_from_base64(text: &str, compression: Option<&str>) -> Result<Vec<u32>, TmxError> {
+    let raw = base64::decode(text).ok_or("a layer has invalid base64 data")?;
+
+    let bytes = match compression {
+        None | Some("") => Ok(raw),
+        Some("zlib") => inflate::zlib_decomp(&raw),
+        Some("gzip") => inflate::gzip_decomp(&raw),
+        Some(s) => return Err(
+            format!("a layer has an unsupported compression (compression=\"{}\")", s).into()
+        ),
+    }.map_err(|e| format!("a layer has bad {} data: {}", compression.unwrap_or(""), e))?;
+
+    if bytes.len() % 4 != 0 {
+        return Err("a layer has data that isn't a whole number of tiles".into());
+    }
+    Ok(bytes.chunks(4).map(|b| {
+        b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gids_split_into_tile_and_flips() {
+        let plain = Gid::from_raw(0x1234);
+        assert_eq!(plain, Gid { id: 0x1234, flip_h: false, flip_v: false, flip_d: false });
+        assert!(!plain.is_flipped());
+
+        let rotated = Gid::from_raw(FLIP_H | FLIP_D | 7);
+        assert_eq!(rotated.id, 7);
+        assert!(rotated.is_flipped());
+        assert_eq!(rotated.flip_name(), "flip-hd");
+        assert_eq!(Gid::from_raw(FLIP_H | FLIP_V | FLIP_D | 7).flip_name(), "flip-hvd");
+
+        // the hex rotation bit isn't part of the ID, but exlev doesn't flip for it
+        let hex = Gid::from_raw(ROTATE_HEX | 7);
+        assert_eq!(hex.id, 7);
+        assert!(!hex.is_flipped());
+    }
+}
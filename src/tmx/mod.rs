@@ -1,10 +1,19 @@
 //#![allow(unused_imports, unused_variables)]
 
 mod tmxerror;
+mod base64;
+mod json;
+mod tileset;
+mod template;
+mod project;
+mod map;
+mod xml;
+mod tmj;
 mod read;
 mod write;
 
-pub use self::tmxerror::TmxError;
+pub use self::tmxerror::{TmxError, Problem, Location};
 
-pub use self::read::read_level;
+pub use self::read::{read_level, read_level_json, read_levels, read_levels_json};
 pub use self::write::write_level;
+pub use self::project::{init_tiled, PROJECT_FILE, STARTER_MAP};
@@ -0,0 +1,311 @@
+//! Sets up a Tiled project for making levels: custom property types, so that
+//! names and values can be picked from a list instead of typed,
+//! and a starter map with the layers and object groups `read` expects.
+//!
+//! Tiled only saves a class's members when they've been changed from their
+//! defaults, so each default here has to be what `read` does without the
+//! property. Members with no sensible default, like an entrance's fragment,
+//! default to nothing, so that setting them always saves them.
+
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::Path;
+
+use super::json::Json;
+use level::{LevelHeader, Palette};
+use spr::SpritePlacement;
+use super::TmxError;
+
+pub const PROJECT_FILE: &str = "exlev.tiled-project";
+pub const STARTER_MAP: &str = "level.tmx";
+
+// The starter map's tilesets, one after the other.
+const FG_TILES: u32 = 0x200;
+// one tile for every sprite number
+const SPRITE_TILES: u32 = SpritePlacement::MAX_ID as u32 + 1;
+const ENTRANCE_TILES: u32 = 8;
+
+/// Writes the project file and starter map into `dir`.
+/// Neither is written if either is already there.
+pub fn init_tiled(dir: &Path) -> Result<(), TmxError> {
+    let files = [
+        (dir.join(PROJECT_FILE), format!("{}\n", project())),
+        (dir.join(STARTER_MAP), starter_map()),
+    ];
+    for (path, _) in &files {
+        if path.exists() {
+            return Err(format!("{} already exists", path.to_string_lossy()).into());
+        }
+    }
+    for (path, contents) in &files {
+        let mut f = OpenOptions::new().write(true).create_new(true).open(path)
+            .map_err(|e| format!("couldn't create {}: {}", path.to_string_lossy(), e))?;
+        f.write_all(contents.as_bytes())
+            .map_err(|e| format!("couldn't write {}: {}", path.to_string_lossy(), e))?;
+    }
+    Ok(())
+}
+
+fn obj(kvs: Vec<(&str, Json)>) -> Json {
+    Json::Object(kvs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn string(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+fn int(n: u8) -> Json {
+    Json::Number(n as f64)
+}
+
+/// `count` values named by their number in hex.
+fn hex_values(count: u32, digits: usize) -> Vec<String> {
+    (0 .. count).map(|n| format!("{:01$x}", n, digits)).collect()
+}
+
+/// An enum whose value is the position of its name in `values`.
+fn int_enum(name: &str, values: Vec<String>) -> Json {
+    enum_type(name, "int", values)
+}
+
+fn enum_type(name: &str, storage: &str, values: Vec<String>) -> Json {
+    obj(vec![
+        ("name", string(name)),
+        ("storageType", string(storage)),
+        ("type", string("enum")),
+        ("values", Json::Array(values.into_iter().map(Json::String).collect())),
+        ("valuesAsFlags", Json::Bool(false)),
+    ])
+}
+
+fn member(name: &str, ty: &str, value: Json) -> Json {
+    obj(vec![("name", string(name)), ("type", string(ty)), ("value", value)])
+}
+
+fn enum_member(name: &str, ty: &str, enum_name: &str, value: Json) -> Json {
+    obj(vec![
+        ("name", string(name)),
+        ("propertyType", string(enum_name)),
+        ("type", string(ty)),
+        ("value", value),
+    ])
+}
+
+fn class(name: &str, use_as: &[&str], members: Vec<Json>) -> Json {
+    obj(vec![
+        ("color", string("#ffa0a0a4")),
+        ("drawFill", Json::Bool(true)),
+        ("members", Json::Array(members)),
+        ("name", string(name)),
+        ("type", string("class")),
+        ("useAs", Json::Array(use_as.iter().map(|s| string(s)).collect())),
+    ])
+}
+
+/// The header's properties, with `LevelHeader`'s defaults.
+fn header_members() -> Vec<Json> {
+    let hed = LevelHeader::default();
+    let shared = match hed.palette {
+        Palette::Shared(p) => p,
+        Palette::Custom(_) => unreachable!("the default header has a shared palette"),
+    };
+    vec![
+        enum_member("level-mode", "int", "LevelMode", int(hed.mode)),
+        member("audio-track", "int", int(hed.audio_track)),
+        enum_member("fg-tileset", "int", "Tileset", int(hed.tileset_fg)),
+        enum_member("sp-tileset", "int", "Tileset", int(hed.tileset_sp)),
+        member("time-limit", "int", int(hed.time)),
+        member("scroll-allowance-numeric", "int", int(hed.scroll)),
+        enum_member("layer-3-image", "int", "Layer3Image", int(hed.l3_img)),
+        member("layer-3-priority", "bool", Json::Bool(hed.l3_prio)),
+        member("palette", "file", string("")),
+        member("palette-fg", "int", int(shared.fg)),
+        member("palette-bg", "int", int(shared.bg)),
+        member("palette-sp", "int", int(shared.sp)),
+        member("palette-sky", "int", int(shared.sky)),
+        member("scroll-filter-auto", "bool", Json::Bool(false)),
+    ]
+}
+
+fn property_types() -> Vec<Json> {
+    let mut fragments = vec!["m0".to_string(), "m1".to_string()];
+    fragments.extend((0 .. 0x20).map(|n| format!("s{:x}", n)));
+
+    let mut group_members = vec![member("level-number", "string", string(""))];
+    group_members.extend(header_members());
+
+    vec![
+        int_enum("EntranceAnimation", hex_values(8, 1)),
+        enum_type("EntranceFragment", "string", fragments),
+        int_enum("LevelMode", hex_values(0x20, 2)),
+        int_enum("Tileset", hex_values(0x10, 1)),
+        int_enum("Layer3Image", hex_values(4, 1)),
+        class("LevelHeader", &["map"], header_members()),
+        class("LevelGroup", &["layer"], group_members),
+        class("Entrance", &["object"], vec![
+            enum_member("fragment", "string", "EntranceFragment", string("")),
+            // Unless this is changed, the entrance's tile says which animation it has.
+            // Picking 0 here does nothing, since it's the default; use its tile instead.
+            enum_member("animation", "int", "EntranceAnimation", int(0)),
+            member("water", "bool", Json::Bool(false)),
+            member("slippery", "bool", Json::Bool(false)),
+        ]),
+        class("Exit", &["object"], vec![member("target", "string", string(""))]),
+        class("Sprite", &["object"], vec![
+            member("ebit", "bool", Json::Bool(false)),
+            member("xb1", "string", string("00")),
+            member("xb2", "string", string("00")),
+            member("xb3", "string", string("00")),
+            member("xb4", "string", string("00")),
+        ]),
+    ]
+}
+
+fn project() -> Json {
+    let types = property_types().into_iter().enumerate().map(|(i, t)| {
+        // Tiled wants every type to have its own ID
+        match t {
+            Json::Object(mut kvs) => {
+                kvs.insert(0, ("id".to_string(), Json::Number((i + 1) as f64)));
+                Json::Object(kvs)
+            },
+            t => t,
+        }
+    }).collect();
+
+    obj(vec![
+        ("automappingRulesFile", string("")),
+        ("commands", Json::Array(Vec::new())),
+        ("extensionsPath", string("extensions")),
+        ("folders", Json::Array(vec![string(".")])),
+        ("propertyTypes", Json::Array(types)),
+    ])
+}
+
+/// An empty 512x512 layer.
+fn empty_layer(id: u32, name: &str) -> String {
+    let row = vec!["0"; 512].join(",");
+    let rows = vec![row; 512].join(",\n");
+    format!(concat!(
+        " <layer id=\"{}\" name=\"{}\" width=\"512\" height=\"512\">\n",
+        "  <data encoding=\"csv\">\n{}\n</data>\n",
+        " </layer>\n",
+    ), id, name, rows)
+}
+
+fn starter_map() -> String {
+    let sprites_gid = 1 + FG_TILES;
+    let entrances_gid = sprites_gid + SPRITE_TILES;
+
+    let mut s = String::new();
+    s.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    s.push_str(concat!(
+        "<map version=\"1.10\" class=\"LevelHeader\" orientation=\"orthogonal\" renderorder=\"right-down\" ",
+        "width=\"512\" height=\"512\" tilewidth=\"16\" tileheight=\"16\" infinite=\"0\" ",
+        "nextlayerid=\"7\" nextobjectid=\"2\">\n",
+    ));
+    s.push_str(&format!(concat!(
+        " <tileset firstgid=\"1\" name=\"fg\" tilewidth=\"16\" tileheight=\"16\" tilecount=\"{}\" columns=\"16\">\n",
+        "  <image source=\"fg.png\" width=\"256\" height=\"{}\"/>\n",
+        " </tileset>\n",
+    ), FG_TILES, FG_TILES));
+    s.push_str(&format!(concat!(
+        " <tileset firstgid=\"{}\" name=\"sprites\" tilewidth=\"32\" tileheight=\"32\" tilecount=\"{}\" columns=\"16\">\n",
+        "  <image source=\"sprites.png\" width=\"512\" height=\"{}\"/>\n",
+        " </tileset>\n",
+    ), sprites_gid, SPRITE_TILES, SPRITE_TILES * 2));
+    s.push_str(&format!(concat!(
+        " <tileset firstgid=\"{}\" name=\"entrances\" tilewidth=\"32\" tileheight=\"32\" tilecount=\"{}\" columns=\"{}\">\n",
+        "  <image source=\"entrances.png\" width=\"{}\" height=\"32\"/>\n",
+        " </tileset>\n",
+    ), entrances_gid, ENTRANCE_TILES, ENTRANCE_TILES, ENTRANCE_TILES * 32));
+    s.push_str(&empty_layer(1, "BG"));
+    s.push_str(&empty_layer(2, "Level"));
+    s.push_str(" <objectgroup id=\"3\" name=\"Scroll\"/>\n");
+    s.push_str(" <objectgroup id=\"4\" name=\"Sprites\"/>\n");
+    s.push_str(" <objectgroup id=\"5\" name=\"Exits\"/>\n");
+    s.push_str(&format!(concat!(
+        " <objectgroup id=\"6\" name=\"Entrances\">\n",
+        "  <object id=\"1\" type=\"Entrance\" gid=\"{}\" x=\"32\" y=\"416\" width=\"32\" height=\"32\">\n",
+        "   <properties>\n",
+        "    <property name=\"fragment\" propertytype=\"EntranceFragment\" value=\"m0\"/>\n",
+        "   </properties>\n",
+        "  </object>\n",
+        " </objectgroup>\n",
+    ), entrances_gid));
+    s.push_str("</map>\n");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::json;
+    use super::super::read::read_level;
+
+    fn header_of(tmx: &str) -> LevelHeader {
+        match read_level(&mut tmx.as_bytes(), ".", 0x105) {
+            Ok(lvl) => lvl.header,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn project_reads_back() {
+        let p = project();
+        assert_eq!(json::parse(&p.to_string()).unwrap(), p);
+
+        let types = p.get("propertyTypes").and_then(|t| t.as_array()).unwrap();
+        let mut ids = types.iter().map(|t| t.get("id").and_then(|i| i.as_f64()).unwrap() as u32).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), types.len());
+    }
+
+    #[test]
+    fn starter_map_loads() {
+        let hed = header_of(&starter_map());
+        assert_eq!(format!("{:?}", hed), format!("{:?}", LevelHeader::default()));
+    }
+
+    #[test]
+    fn every_sprite_has_a_tile() {
+        assert_eq!(SPRITE_TILES, 0x400);
+        // the last sprite is the sprites tileset's last tile
+        let gid = 1 + FG_TILES + SPRITE_TILES - 1;
+        let map = starter_map().replace(" <objectgroup id=\"4\" name=\"Sprites\"/>\n", &format!(concat!(
+            " <objectgroup id=\"4\" name=\"Sprites\">\n",
+            "  <object id=\"2\" type=\"Sprite\" gid=\"{}\" x=\"64\" y=\"416\" width=\"32\" height=\"32\"/>\n",
+            " </objectgroup>\n",
+        ), gid));
+        assert_ne!(map, starter_map());
+        header_of(&map);
+    }
+
+    #[test]
+    fn header_defaults_are_what_read_does() {
+        let p = project();
+        let types = p.get("propertyTypes").and_then(|t| t.as_array()).unwrap();
+        let class = types.iter().find(|t| t.get("name").and_then(|n| n.as_str()) == Some("LevelHeader")).unwrap();
+
+        let mut props = String::from(" <properties>\n");
+        for m in class.get("members").and_then(|m| m.as_array()).unwrap() {
+            let name = m.get("name").and_then(|n| n.as_str()).unwrap();
+            let ty = m.get("type").and_then(|t| t.as_str()).unwrap();
+            let value = match *m.get("value").unwrap() {
+                Json::Bool(b) => b.to_string(),
+                Json::Number(n) => n.to_string(),
+                // a default of nothing means the property isn't saved
+                Json::String(ref s) if s.is_empty() => continue,
+                ref v => panic!("{} has default {}", name, v),
+            };
+            props.push_str(&format!("  <property name=\"{}\" type=\"{}\" value=\"{}\"/>\n", name, ty, value));
+        }
+        props.push_str(" </properties>\n");
+
+        let map = starter_map();
+        let at = map.find(" <tileset").unwrap();
+        let with_props = format!("{}{}{}", &map[.. at], props, &map[at ..]);
+        assert_eq!(format!("{:?}", header_of(&with_props)), format!("{:?}", header_of(&map)));
+    }
+}
@@ -3,441 +3,1058 @@ use std::path::Path;
 use std::fs::File;
 use std::io::prelude::*;
 use std::collections::BTreeMap;
-use sxd_document::parser::parse;
-use sxd_document::dom::Element;
-use sxd_xpath::{Context, Value};
-use sxd_xpath::nodeset::Node;
-use why_sxd::{make_xpath, make_xpath_static, xpath_nodes, xpath_nodes_str, attr_u32, attr_hexbyte,
-              only_match, only_match_str, node_element_attr, attr_bool};
-
-use super::TmxError;
+use std::ops::RangeInclusive;
+
+use super::{TmxError, Problem, Location};
+use super::map::{Map, Scope, Object, Properties, Property, Gid};
+use super::tileset::{self, Tileset};
+use super::{tmj, xml};
 use snes_color::SnesPal;
 
 use spr::*;
 use entrance::{EntrancePlacement, EntranceId};
 use level::{self, Level, PScrGrid, LevelHeader, Palette};
 
+/// Reads a level from a TMX map.
+/// Files the map refers to, like tilesets and palettes, are found relative to `dir`.
+/// If the map has level groups, this is the one numbered `levelnum`.
 pub fn read_level<R: io::Read, P: AsRef<Path>>(source: &mut R, dir: P, levelnum: u16) -> Result<Level, TmxError> {
-    let pkg = {
-        let mut buf = String::new();
-        source.read_to_string(&mut buf).unwrap();
-        parse(&buf).map_err(|_| "bad TMX file")?
-    };
-    let doc = pkg.as_document();
-    let root_node = doc.root().into();
-    let ctx = Context::new();
-
-    let first_gid_fg = not_nan(
-        make_xpath_static("/map/tileset[@name='fg']/@firstgid")
-            .evaluate(&ctx, root_node)?
-            .number(),
-    ).ok_or(
-        "couldn't get first tile for fg; tileset 'fg' may be missing",
-    )? as u16;
-
-    let mut fg = read_block_grid(&ctx, root_node, "Level", first_gid_fg)?;
-    let bg = read_block_grid(&ctx, root_node, "BG", first_gid_fg)?;
-
-    let map = xpath_nodes_str(&ctx, root_node, "map")
-        .unwrap()
-        .document_order_first()
-        .unwrap();
-    let sprs = read_sprite_layer(&ctx, &map)?;
-    level::place_sprites(&mut fg, &sprs);
-    let exits = read_exit_layer(&ctx, &map, levelnum)?;
-    level::place_exits(&mut fg, &exits);
-    
-    let sf = read_scroll_filter(&ctx, root_node)?;
-    
-    let dir_path = dir.as_ref();
-    let hed = read_header(&ctx, root_node, dir_path)?;
-    
-    let ens = read_entrance_layer(&ctx, &map, levelnum)?;
-    
-    let lvl = Level::from_parts(fg, bg, sf, ens, hed);
+    let map = xml::read_map(source, dir.as_ref())?;
+    one_level(levels_from_map(&map, dir.as_ref(), Some(levelnum))?)
+}
 
-    Ok(lvl)
+/// Reads a level from a Tiled JSON map, the same way `read_level` does from a TMX one.
+pub fn read_level_json<R: io::Read, P: AsRef<Path>>(source: &mut R, dir: P, levelnum: u16) -> Result<Level, TmxError> {
+    let map = tmj::read_map(source, dir.as_ref())?;
+    one_level(levels_from_map(&map, dir.as_ref(), Some(levelnum))?)
 }
 
+/// Reads every level group in a TMX map, with their level numbers.
+pub fn read_levels<R: io::Read, P: AsRef<Path>>(source: &mut R, dir: P) -> Result<Vec<(u16, Level)>, TmxError> {
+    let map = xml::read_map(source, dir.as_ref())?;
+    levels_from_map(&map, dir.as_ref(), None)
+}
 
-fn not_nan(x: f64) -> Option<f64> {
-    if !x.is_nan() { Some(x) } else { None }
+/// Reads every level group in a Tiled JSON map, like `read_levels`.
+pub fn read_levels_json<R: io::Read, P: AsRef<Path>>(source: &mut R, dir: P) -> Result<Vec<(u16, Level)>, TmxError> {
+    let map = tmj::read_map(source, dir.as_ref())?;
+    levels_from_map(&map, dir.as_ref(), None)
 }
 
-fn read_block_grid(
-    context: &Context,
-    node: Node,
-    name: &'static str,
-    firstgid: u16,
-) -> Result<PScrGrid, TmxError> {
-    let tiles = read_block_layer(context, node, name, firstgid)?;
-    Ok(level::pscreens_from_linear_tiles(&tiles, 32, 32))
+fn one_level(mut levels: Vec<(u16, Level)>) -> Result<Level, TmxError> {
+    levels.pop().map(|(_, l)| l).ok_or_else(|| "the map has no such level".into())
 }
 
+/// Reads the map's levels: either all its level groups, or just the one numbered `only`.
+/// A map with no level groups is one level, which needs `only` to say its number.
+fn levels_from_map(map: &Map, dir: &Path, only: Option<u16>) -> Result<Vec<(u16, Level)>, TmxError> {
+    // Everything is checked before giving up, so that one run reports all of
+    // a map's problems. Whatever's wrong gets a placeholder in the meantime.
+    let mut problems = Problems::default();
 
-fn read_block_layer(
-    context: &Context,
-    node: Node,
-    name: &'static str,
-    firstgid: u16,
-) -> Result<Vec<u16>, TmxError> {
-    let layer_path = make_xpath(&format!("/map/layer[@name='{}']", name))?;
+    let groups = map.level_groups();
+    if groups.is_empty() {
+        let levelnum = only.ok_or("the map has no level groups, so it needs a level number")?;
+        let lvl = level_from_scope(&map.whole(), dir, levelnum, &[], &mut problems);
+        problems.finish()?;
+        return Ok(lvl.map(|l| (levelnum, l)).into_iter().collect());
+    }
 
-    let layer = only_match(context, node, &layer_path).ok_or_else(|| {
-        format!("need exactly 1 layer named {}", name)
-    })?;
+    // level groups can send exits to each other by name
+    let mut siblings = Vec::new();
+    for scope in &groups {
+        let name = scope.group.unwrap_or("");
+        problems.group = Some(name.to_string());
+        let prop = scope.properties.get("level-number").unwrap();
+        match hex_number(prop).filter(|&n| n < 0x200) {
+            Some(n) if siblings.iter().any(|&(_, m)| m == n) => problems.add(
+                Location::map().property("level-number"),
+                format!("another level group is level {:03x} too", n),
+            ),
+            Some(n) => siblings.push((name, n)),
+            None => problems.add(
+                Location::map().property("level-number"),
+                format!("\"{}\" isn't a level number", prop.value),
+            ),
+        }
+    }
+    problems.group = None;
+    problems.finish()?;
+
+    let mut levels = Vec::new();
+    for (scope, &(name, levelnum)) in groups.iter().zip(&siblings) {
+        if only.is_some_and(|n| n != levelnum) {
+            continue;
+        }
+        problems.group = Some(name.to_string());
+        if let Some(lvl) = level_from_scope(scope, dir, levelnum, &siblings, &mut problems) {
+            levels.push((levelnum, lvl));
+        }
+    }
+    problems.finish()?;
 
-    Ok(read_tiles(context, &layer, firstgid).unwrap())
+    match only {
+        Some(n) if levels.is_empty() => Err(format!("the map has no level group for level {:03x}", n).into()),
+        _ => Ok(levels),
+    }
 }
 
-fn read_scroll_filter(context: &Context, node: Node) -> Result<Vec<bool>, TmxError> {
-    let layer_path = make_xpath_static("/map/layer[@name='Scroll']");
+/// Reads one level, noting whatever's wrong with it in `problems`.
+/// If there's anything, there's no level, since `Level::from_parts`
+/// can't take parts that are wrong.
+fn level_from_scope(scope: &Scope, dir: &Path, levelnum: u16, siblings: &[(&str, u16)], problems: &mut Problems) -> Option<Level> {
+    let before = problems.len();
+    let fg_set = problems.check(Location::map(), tileset::find(scope.tilesets, "fg"));
 
-    let layer = only_match(context, node, &layer_path).ok_or(
-        "need exactly 1 layer named \"Scroll\"",
-    )?;
+    let fg_tiles = read_block_layer(scope, "Level", fg_set, problems);
+    let mut fg = level::pscreens_from_linear_tiles(&fg_tiles, 32, 32);
+    let bg = read_block_grid(scope, "BG", fg_set, problems);
 
-    // We can use a fake first GID because we don’t care at all what kind of tile we find,
-    // only whether tiles exist or not.
-    let tiles = read_tiles(context, &layer, 1)?;
+    let sprs = read_sprite_layer(scope, problems);
+    let exits = read_exit_layer(scope, levelnum, siblings, problems);
 
+    let sf = read_scroll_filter(scope, &fg_tiles, problems);
+
+    let hed = read_header(&scope.properties, dir, problems);
+
+    let ens = read_entrance_layer(scope, levelnum, problems);
+
+    level::place_sprites(&mut fg, &sprs);
+    level::place_exits(&mut fg, &exits);
+    if problems.len() != before {
+        return None;
+    }
+    Some(Level::from_parts(fg, bg, sf, ens, hed))
+}
+
+/// The problems found in a map so far.
+#[derive(Default)]
+struct Problems {
+    found: Vec<Problem>,
+    /// The level group being read, which every problem is in.
+    group: Option<String>,
+}
+
+impl Problems {
+    fn add<S: Into<String>>(&mut self, mut at: Location, msg: S) {
+        at.group = self.group.clone();
+        self.found.push(Problem { at, msg: msg.into() });
+    }
+
+    /// The value in `r`, or `None` after noting its error at `at`.
+    fn check<T>(&mut self, at: Location, r: Result<T, TmxError>) -> Option<T> {
+        r.map_err(|e| self.add(at, e.to_string())).ok()
+    }
+
+    fn len(&self) -> usize {
+        self.found.len()
+    }
+
+    /// An error with every problem so far, if there are any.
+    fn finish(&mut self) -> Result<(), TmxError> {
+        if self.found.is_empty() {
+            Ok(())
+        } else {
+            Err(TmxError::Invalid(self.found.split_off(0)))
+        }
+    }
+}
+
+fn read_block_grid(scope: &Scope, name: &'static str, set: Option<&Tileset>, problems: &mut Problems) -> PScrGrid {
+    let tiles = read_block_layer(scope, name, set, problems);
+    level::pscreens_from_linear_tiles(&tiles, 32, 32)
+}
+
+fn read_block_layer(scope: &Scope, name: &'static str, set: Option<&Tileset>, problems: &mut Problems) -> Vec<u16> {
+    let mut tiles = vec![0x0025; 512 * 512];
+    if let (Some(gids), Some(set)) = (read_tiles(scope, name, problems), set) {
+        for (i, &raw) in gids.iter().enumerate() {
+            match tile_val(Gid::from_raw(raw), set) {
+                Ok(t) => tiles[i] = t,
+                Err(e) => problems.add(Location::tile(name, i as u32 % 512, i as u32 / 512), e),
+            }
+        }
+    }
+    tiles
+}
+
+/// Reads which screens the scroll filter is on for.
+/// Any of these can say a screen is filtered:
+///  * a tile anywhere on it in the "Scroll" layer
+///  * a rectangle over it in the "Scroll" object group
+///  * having no tiles in the Level layer, if `scroll-filter-auto` is set
+fn read_scroll_filter(scope: &Scope, fg_tiles: &[u16], problems: &mut Problems) -> Vec<bool> {
     let mut filt = vec![false; 1024];
 
-    // iterate over screens
-    for i in 0..filt.len() {
-        let sx = i % 32;
-        let sy = i / 32;
-
-        // iterate over tiles in the screen
-        for y in 0..16 {
-            for x in 0..16 {
-                let tile_idx = (sy * 16 + y) * 512 + (sx * 16 + x);
-                if tiles[tile_idx] != 0x0025 {
-                    filt[i] = true;
-                    break;
-                }
+    // We don’t care at all what kind of tile we find,
+    // only whether tiles exist or not.
+    let layer = problems.check(Location::layer("Scroll"), scope.optional_tile_layer("Scroll"));
+    if matches!(layer, Some(Some(_))) {
+        if let Some(tiles) = read_tiles(scope, "Scroll", problems) {
+            for (i, f) in filt.iter_mut().enumerate() {
+                *f |= screen_tiles(i).any(|t| tiles[t] != 0);
             }
         }
     }
 
-    Ok(filt)
-}
+    for obj in scope.objects("Scroll") {
+        let (xs, ys) = match screens_covered(obj) {
+            Some(s) => s,
+            None => {
+                problems.add(object_location("Scroll", obj), "a scroll filter rectangle isn't inside the level");
+                continue;
+            },
+        };
+        for y in ys {
+            for x in xs.clone() {
+                filt[y as usize * 32 + x as usize] = true;
+            }
+        }
+    }
 
-fn read_tiles(context: &Context, layer: &Node, firstgid: u16) -> Result<Vec<u16>, TmxError> {
+    let mut auto = false;
+    problems.check(Location::map().property("scroll-filter-auto"),
+        header_bool(&scope.properties, &mut auto, "scroll-filter-auto"));
+    if auto {
+        for (i, f) in filt.iter_mut().enumerate() {
+            *f |= screen_tiles(i).all(|t| fg_tiles[t] == 0x0025);
+        }
+    }
 
-    if attr_u32(layer, "width") != Some(512) || attr_u32(layer, "height") != Some(512) {
-        return Err("all layers need to be 512x512".into());
-    };
+    filt
+}
 
-    let data = if let Some(v) = only_match_str(context, *layer, "data").and_then(|n| n.element()) {
-        v
+/// An object's x or y as a whole pixel, as long as it's inside the level's 512x512 tiles.
+fn level_pixel(v: f64) -> Option<u32> {
+    if (0.0 .. 512.0 * 16.0).contains(&v) {
+        Some(v as u32)
     } else {
-        return Err("a <layer> is badly formatted".into());
-    };
-
-    let encoding = data.attribute("encoding").map(|a| a.value()).unwrap_or(
-        "xml",
-    );
+        None
+    }
+}
 
-    let mut tiles = Vec::with_capacity(512 * 512);
+/// The indices into a 512x512 layer of the tiles on screen `i`.
+fn screen_tiles(i: usize) -> impl Iterator<Item = usize> {
+    let sx = i % 32;
+    let sy = i / 32;
+    (0 .. 16).flat_map(move |y| (0 .. 16).map(move |x| (sy * 16 + y) * 512 + (sx * 16 + x)))
+}
 
-    match encoding {
-        //"xml" => read_tiles_xml(context, layer, &mut tiles, firstgid)?,
-        "csv" => read_tiles_csv(layer, &mut tiles, firstgid)?,
-        s => {
-            return Err(
-                format!("a <layer> has an unsupported encoding (encoding=\"{}\")", s).into(),
-            )
+/// The columns and rows of screens an object covers, or `None` if any of it is outside the level.
+/// A point covers only the screen it's on, and a rectangle every screen it touches.
+fn screens_covered(obj: &Object) -> Option<(RangeInclusive<u32>, RangeInclusive<u32>)> {
+    let screens = |start: f64, len: f64| {
+        if start.is_nan() || start < 0.0 {
+            return None;
         }
+        let end = if len > 0.0 { (start + len).ceil() } else { start + 1.0 };
+        let first = start as u32 / 256;
+        let last = (end as u32 - 1) / 256;
+        Some(first ..= last).filter(|_| last < 32)
     };
+    Some((screens(obj.x, obj.width)?, screens(obj.y, obj.height)?))
+}
 
-    if tiles.len() < 512 * 512 {
-        Err("a <layer> has too few tiles for its dimensions".into())
-    } else if tiles.len() > 512 * 512 {
-        Err("a <layer> has too many tiles for its dimensions".into())
-    } else {
-        Ok(tiles)
-    }
+fn read_tiles<'m>(scope: &Scope<'m>, name: &str, problems: &mut Problems) -> Option<&'m [u32]> {
+    let layer = problems.check(Location::layer(name), scope.tile_layer(name))?;
+    if layer.width != 512 || layer.height != 512 {
+        problems.add(
+            Location::layer(name),
+            format!("is {}x{}, but all layers need to be 512x512", layer.width, layer.height),
+        );
+        return None;
+    };
+    Some(&layer.gids)
 }
 
-fn read_header(context: &Context, doc: Node, dir: &Path) -> Result<LevelHeader, TmxError> {
+fn read_header(props: &Properties, dir: &Path, problems: &mut Problems) -> LevelHeader {
     let mut hed = LevelHeader::default();
-    header_bitfield(context, doc, &mut hed.mode, "level-mode", 5)?;
-    header_byte(context, doc, &mut hed.audio_track, "audio-track")?;
-    header_bitfield(context, doc, &mut hed.tileset_fg, "fg-tileset", 4)?;
-    header_bitfield(context, doc, &mut hed.tileset_sp, "sp-tileset", 4)?;
-    header_bitfield(context, doc, &mut hed.scroll, "scroll-allowance-numeric", 2)?;
-    header_bitfield(context, doc, &mut hed.l3_img, "layer-3-image", 2)?;
-    header_bool(context, doc, &mut hed.l3_prio, "layer-3-priority")?;
-    header_palette(context, doc, &mut hed.palette, dir)?;
-    Ok(hed)
+    {
+        let mut field = |name: &str, r: Result<(), TmxError>| {
+            problems.check(Location::map().property(name), r);
+        };
+        field("level-mode", header_bitfield(props, &mut hed.mode, "level-mode", 5));
+        field("audio-track", header_byte(props, &mut hed.audio_track, "audio-track"));
+        field("fg-tileset", header_bitfield(props, &mut hed.tileset_fg, "fg-tileset", 4));
+        field("sp-tileset", header_bitfield(props, &mut hed.tileset_sp, "sp-tileset", 4));
+        field("time-limit", header_bitfield(props, &mut hed.time, "time-limit", 4));
+        field("scroll-allowance-numeric",
+            header_bitfield(props, &mut hed.scroll, "scroll-allowance-numeric", 3));
+        field("layer-3-image", header_bitfield(props, &mut hed.l3_img, "layer-3-image", 2));
+        field("layer-3-priority", header_bool(props, &mut hed.l3_prio, "layer-3-priority"));
+        field("palette", header_palette(props, &mut hed.palette, dir));
+    }
+    read_shared_palette(props, &mut hed.palette, problems);
+    hed
 }
 
-fn el_type_is(el: &Element, name: &str) -> bool {
-    el.attribute("type").map_or(false, |v| v.value() == name)
+/// Reads the indices of a shared palette, for levels that don't have their own.
+/// Any index that isn't given keeps its default.
+fn read_shared_palette(props: &Properties, targ: &mut Palette, problems: &mut Problems) {
+    const NAMES: [&str; 4] = ["palette-fg", "palette-bg", "palette-sp", "palette-sky"];
+
+    if props.get("palette").is_some() {
+        for name in NAMES.iter().filter(|&&name| props.get(name).is_some()) {
+            problems.add(Location::map().property(name),
+                "a level with a palette file can't use shared palettes too");
+        }
+        return;
+    }
+    let mut shared = match *targ {
+        Palette::Shared(p) => p,
+        Palette::Custom(_) => return,
+    };
+
+    {
+        // these are the widths they're packed into in the header
+        let mut index = |name: &str, targ: &mut u8, width: u8| {
+            problems.check(Location::map().property(name), header_bitfield(props, targ, name, width));
+        };
+        index("palette-fg", &mut shared.fg, 5);
+        index("palette-bg", &mut shared.bg, 3);
+        index("palette-sp", &mut shared.sp, 3);
+        index("palette-sky", &mut shared.sky, 5);
+    }
+    *targ = Palette::Shared(shared);
 }
 
-fn header_byte(context: &Context, doc: Node, targ: &mut u8, name: &str) -> Result<(), TmxError> {
-    header_bitfield(context, doc, targ, name, 8)
+fn header_byte(props: &Properties, targ: &mut u8, name: &str) -> Result<(), TmxError> {
+    header_bitfield(props, targ, name, 8)
 }
 
-fn header_bitfield(context: &Context, doc: Node, targ: &mut u8, name: &str, width: u8) -> Result<(), TmxError> {
+fn header_bitfield(props: &Properties, targ: &mut u8, name: &str, width: u8) -> Result<(), TmxError> {
     assert!(width <= 8, "tried to extract too wide bitfield");
     assert!(width != 0, "tried to extract zero-width bitfield");
     let filter = 0xff >> (8 - width);
-    
-    if let Some(el) = header_element(context, doc, name)? {
-        if !el_type_is(&el, "int")  {
-            return Err(format!("bad header component ({}): should have type \"int\"", name).into());
-        };
-        if let Some(ev) = el.attribute("value") {
-            let v = ev.value().parse::<u8>()
-                .map_err(|_| "that's not a number binch")?;
-            if v > filter {
-                return Err(
-                    format!("bad header component ({}): out-of-range (max is {})", name, filter).into()
-                );
-            }
-            *targ = v;
+
+    if let Some(prop) = header_property(props, name)? {
+        if prop.ty != "int" {
+            return Err("should have type \"int\"".into());
         };
+        let v = prop.value.parse::<u32>()
+            .map_err(|_| "that's not a number binch")?;
+        if v > filter as u32 {
+            return Err(format!("{} is out of range (max is {})", v, filter).into());
+        }
+        *targ = v as u8;
     };
-    
+
     Ok(())
 }
 
-fn header_bool(context: &Context, doc: Node, targ: &mut bool, name: &str) -> Result<(), TmxError> {
-    if let Some(el) = header_element(context, doc, name)? {
-        if !el_type_is(&el, "bool") {
-            return Err(format!("bad header component ({}): should have type \"bool\"", name).into());
-        };
-        if let Some(ev) = el.attribute("value") {
-            let v = ev.value().parse::<bool>()
-                .map_err(|_| "that's not a bool binch")?;
-            *targ = v;
+fn header_bool(props: &Properties, targ: &mut bool, name: &str) -> Result<(), TmxError> {
+    if let Some(prop) = header_property(props, name)? {
+        if prop.ty != "bool" {
+            return Err("should have type \"bool\"".into());
         };
+        let v = prop.value.parse::<bool>()
+            .map_err(|_| "that's not a bool binch")?;
+        *targ = v;
     };
-    
+
     Ok(())
 }
 
-fn header_palette(context: &Context, doc: Node, targ: &mut Palette, dir: &Path) -> Result<(), TmxError> {
-    if let Some(el) = header_element(context, doc, "palette")? {
-        if !el_type_is(&el, "file") {
-            return Err(format!("bad header component (palette): should have type \"file\"").into());
+fn header_palette(props: &Properties, targ: &mut Palette, dir: &Path) -> Result<(), TmxError> {
+    if let Some(prop) = header_property(props, "palette")? {
+        // a palette set in a class in a JSON map has lost its type
+        if prop.ty != "file" && prop.ty != "string" {
+            return Err("should have type \"file\"".into());
         };
-        
-        if let Some(ev) = el.attribute("value") {
-            let mut p = dir.to_path_buf();
-            p.push(ev.value());
-            let mut f = File::open(p.clone()).map_err(|e| format!("bad header component (palette): {}", e))?;
-            let mut buf = Vec::new();
-            f.read_to_end(&mut buf).map_err(|e| format!("bad header component (palette): {}", e))?;
-            *targ = Palette::Custom(
-                SnesPal::from_lm_pal(&buf).ok_or_else(
-                    || format!("bad header componenent (palette): file \"{:}\" is not a valid .pal file", p.to_string_lossy())
-                )?
-            );
-        } else {
-            return Err(format!("bad header component (palette): has no value").into())
+        if prop.value.is_empty() {
+            return Err("has no value".into())
         };
+
+        let p = dir.join(&prop.value);
+        let mut f = File::open(&p).map_err(|e| format!("{}", e))?;
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf).map_err(|e| format!("{}", e))?;
+        *targ = Palette::Custom(
+            SnesPal::from_lm_pal(&buf).ok_or_else(
+                || format!("file \"{:}\" is not a valid .pal file", p.to_string_lossy())
+            )?
+        );
     };
-    
+
     Ok(())
 }
 
-fn header_element<'d>(context: &Context<'d>, doc: Node<'d>, name: &str) -> Result<Option<Element<'d>>, TmxError> {
-    let path = make_xpath(&format!("/map/properties/property[@name = \"{}\"]", name))?;
-    
-    let nodes = if let Value::Nodeset(ns) = path.evaluate(context, doc)? {
-        ns
+fn header_property<'p>(props: &'p Properties, name: &str) -> Result<Option<&'p Property>, TmxError> {
+    let mut found = props.iter().filter(|p| p.name == name);
+    match (found.next(), found.next()) {
+        (None, _) => Ok(None),
+        (Some(p), None) => Ok(Some(p)),
+        (Some(_), Some(_)) => Err("appears more than once".into()),
+    }
+}
+
+fn tile_val(gid: Gid, set: &Tileset) -> Result<u16, String> {
+    if gid.id == 0 {
+        return Ok(0x0025);
+    } else if gid.id < set.firstgid || gid.id - set.firstgid > 0xffff {
+        return Err("invalid tile ID".into());
+    }
+    let tile = gid.id - set.firstgid;
+    if !gid.is_flipped() {
+        return Ok(tile as u16);
+    }
+
+    // Map16 tiles can't be flipped, so a flipped tile has to be swapped for
+    // another tile that the tileset says looks like it flipped.
+    let flip = gid.flip_name();
+    let variant = set.props(tile).and_then(|p| p.get(&flip)).ok_or_else(|| {
+        format!("tile {:x} is flipped ({}), but has no \"{}\" property to say what to use instead",
+            tile, flip, flip)
+    })?;
+    hex_number(variant).ok_or_else(|| {
+        format!("tile {:x} has an invalid \"{}\" property \"{}\"", tile, flip, variant.value)
+    })
+}
+
+/// Reads a number that Lunar Magic would show in hex, like a tile or sprite
+/// number in a tileset's `flip-*` or `sprite` property, or a group's `level-number`.
+/// These are hex too, unless the property's an int.
+fn hex_number(prop: &Property) -> Option<u16> {
+    if prop.ty == "int" {
+        prop.value.parse::<u16>().ok()
     } else {
-        return Ok(None);
+        u16::from_str_radix(&prop.value, 16).ok()
+    }
+}
+
+fn read_sprite_layer(scope: &Scope, problems: &mut Problems) -> SprSet {
+    let mut sprlist = SprSet::new();
+
+    let set = match problems.check(Location::layer("Sprites"), tileset::find(scope.tilesets, "sprites")) {
+        Some(set) => set,
+        None => return sprlist,
     };
-    
-    if nodes.size() == 0 {
-        Ok(None)
-    } else if nodes.size() > 1 {
-        Err(format!("too many entries for header field {}", name).into())
-    } else {
-        if let Some(Node::Element(e)) = nodes.document_order_first() {
-            Ok(Some(e))
-        } else {
-            Err(format!("header componenent {} is deeply messed up", name).into())
+
+    for obj in scope.objects("Sprites") {
+        if let Some(spr) = sprite_from_object(obj, set, problems) {
+            sprlist.insert(spr);
         }
     }
+
+    sprlist
+}
+
+fn object_location(group: &str, obj: &Object) -> Location {
+    Location::object(group, obj.id, &obj.name, obj.x, obj.y)
 }
 
-fn tile_val(n: u16, first: u16) -> u16 {
-    if n != 0 { n - first } else { 0x0025 }
+fn hexbyte(prop: &Property) -> Result<u8, String> {
+    u8::from_str_radix(&prop.value, 16)
+        .map_err(|_| format!("\"{}\" should be a hex byte", prop.value))
 }
 
-fn read_tiles_csv(layer: &Node, buf: &mut Vec<u16>, firstgid: u16) -> Result<(), TmxError> {
-    let s = layer.string_value();
-    for chunk in s.split(',') {
-        let core = chunk.trim();
-        let value = core.parse::<u16>().map_err(|_| "invalid tile ID")?;
-        buf.push(tile_val(value, firstgid));
+/// A bool property, or a hex one where anything but 0 is true.
+fn flag(prop: &Property) -> Result<bool, String> {
+    if prop.ty == "bool" {
+        prop.value.parse::<bool>().map_err(|_| "should be a boolean".into())
+    } else {
+        hexbyte(prop).map(|b| b != 0)
     }
-    Ok(())
 }
 
-fn read_sprite_layer(context: &Context, map: &Node) -> Result<SprSet, TmxError> {
-    let mut sprlist = SprSet::new();
+/// What the tile a sprite object uses says about the sprite.
+/// The tile's properties are the defaults for every object using it:
+///  * `sprite`: the sprite number, if it isn't the tile's ID
+///  * `ebit`, `xb1` .. `xb4`: the extra bit and extra bytes
+///  * `extra-bytes`: how many extra bytes the sprite has (up to 4)
+///  * `xb1-name` .. `xb4-name`: a name objects can set that extra byte by
+struct SpriteDef {
+    id: u16,
+    xbit: bool,
+    xbytes: [u8; 4],
+    xbyte_count: usize,
+    names: [Option<String>; 4],
+}
 
-    let firstgid = not_nan(
-        make_xpath_static("tileset[@name='sprites']/@firstgid")
-            .evaluate(&context, *map)?
-            .number(),
-    ).ok_or(
-        "couldn't find first tile for sprites; tileset 'sprites' may be missing",
-    )? as u16;
-
-    for node in xpath_nodes_str(&context, *map, "objectgroup[@name='Sprites']/object")? {
-        sprlist.insert(sprite_from_node(&context, &node, firstgid)?);
-    }
-
-    Ok(sprlist)
-}
-
-fn sprite_from_node(
-    context: &Context,
-    node: &Node,
-    firstgid: u16,
-) -> Result<SpritePlacement, TmxError> {
-    let path = make_xpath_static("properties/property");
-
-    let mut xbit = false;
-    let mut xbytes = [0; 4];
-
-    for prop_node in xpath_nodes(context, *node, &path)? {
-        let name = node_element_attr(&prop_node, "name")
-            .ok_or("Invalid sprite property (has no name)")?;
-        match name {
-            "ebit" => xbit = attr_hexbyte(&prop_node, "value").unwrap_or(0) != 0,
-            "xb1" => xbytes[0] = attr_hexbyte(&prop_node, "value").unwrap_or(0),
-            "xb2" => xbytes[1] = attr_hexbyte(&prop_node, "value").unwrap_or(0),
-            "xb3" => xbytes[2] = attr_hexbyte(&prop_node, "value").unwrap_or(0),
-            "xb4" => xbytes[3] = attr_hexbyte(&prop_node, "value").unwrap_or(0),
-            x => return Err(format!("Invalid sprite property: {}", x).into()),
+impl SpriteDef {
+    fn from_tile(set: &Tileset, tile: u32) -> Result<SpriteDef, String> {
+        let mut def = SpriteDef {
+            id: tile as u16,
+            xbit: false,
+            xbytes: [0; 4],
+            xbyte_count: 4,
+            names: Default::default(),
+        };
+        let props = match set.props(tile) {
+            Some(p) => p,
+            None => return Ok(def),
         };
+
+        for prop in props.iter() {
+            let bad = |e: String| format!("sprite tile {:x} has a bad \"{}\" property: {}", tile, prop.name, e);
+            let name = &prop.name[..];
+            match name {
+                "sprite" => def.id = hex_number(prop)
+                    .filter(|&n| n <= SpritePlacement::MAX_ID)
+                    .ok_or_else(|| bad(format!("\"{}\" isn't a sprite number", prop.value)))?,
+                "ebit" => def.xbit = flag(prop).map_err(bad)?,
+                "extra-bytes" => def.xbyte_count = prop.value.parse::<usize>().ok()
+                    .filter(|&n| n <= 4)
+                    .ok_or_else(|| bad("should be 0 to 4".into()))?,
+                _ => if let Some(i) = xbyte_index(name) {
+                    def.xbytes[i] = hexbyte(prop).map_err(bad)?;
+                } else if let Some(i) = name.strip_suffix("-name").and_then(xbyte_index) {
+                    def.names[i] = Some(prop.value.clone());
+                },
+            }
+        }
+        Ok(def)
+    }
+
+    /// Which extra byte a property of a sprite object sets, if it sets one.
+    fn field(&self, name: &str) -> Option<usize> {
+        xbyte_index(name).or_else(|| self.names.iter().position(|n| n.as_deref() == Some(name)))
+    }
+}
+
+/// Which extra byte `xb1` .. `xb4` refers to.
+fn xbyte_index(name: &str) -> Option<usize> {
+    match name {
+        "xb1" => Some(0),
+        "xb2" => Some(1),
+        "xb3" => Some(2),
+        "xb4" => Some(3),
+        _ => None,
+    }
+}
+
+fn sprite_from_object(obj: &Object, set: &Tileset, problems: &mut Problems) -> Option<SpritePlacement> {
+    let at = object_location("Sprites", obj);
+    let before = problems.len();
+
+    // sprites face whichever way the game decides, so flipping them does nothing
+    let def = match obj.gid.map(|g| Gid::from_raw(g).id) {
+        None => {
+            problems.add(at.clone(), "a sprite has to be a tile object");
+            None
+        },
+        Some(gid) if !set.contains(gid) => {
+            problems.add(at.clone(), "a sprite's tile isn't from the 'sprites' tileset");
+            None
+        },
+        Some(gid) => SpriteDef::from_tile(set, gid - set.firstgid)
+            .map_err(|e| problems.add(at.clone(), e))
+            .ok(),
+    };
+
+    if let Some(ref def) = def {
+        if def.id > SpritePlacement::MAX_ID {
+            problems.add(at.clone(), format!("sprite {:x} is too high (max is {:x})", def.id, SpritePlacement::MAX_ID));
+        }
+    }
+
+    let mut xbit = def.as_ref().is_some_and(|d| d.xbit);
+    let mut xbytes = def.as_ref().map_or([0; 4], |d| d.xbytes);
+
+    for prop in obj.properties.iter() {
+        let v = if prop.name == "ebit" {
+            flag(prop).map(|b| xbit = b)
+        } else {
+            match def.as_ref().map(|d| (d.field(&prop.name), d.xbyte_count)) {
+                Some((Some(i), count)) if i >= count =>
+                    Err(format!("this sprite only has {} extra bytes", count)),
+                Some((Some(i), _)) => hexbyte(prop).map(|b| xbytes[i] = b),
+                Some((None, _)) => Err("isn't a property of this sprite".into()),
+                // the sprite's tile is already wrong, so there's no telling
+                None => Ok(()),
+            }
+        };
+        if let Err(e) = v {
+            problems.add(at.clone().property(&prop.name), e);
+        }
     }
 
-    let id = attr_u32(node, "gid").ok_or("A sprite has an invalid ID")? as u16 - firstgid;
     // x + 16, y - 16 is the center of a 32x32 square
-    let pos_x = (attr_u32(node, "x").ok_or("A sprite has an invalid X pos")? + 16) as u16 / 16;
-    let pos_y = (attr_u32(node, "y").ok_or("A sprite has an invalid Y pos")? - 16) as u16 / 16;
+    let pos_x = level_pixel(obj.x).and_then(|x| x.checked_add(16)).map(|x| x / 16).filter(|&x| x < 512);
+    let pos_y = level_pixel(obj.y).and_then(|y| y.checked_sub(16)).map(|y| y / 16);
+    if pos_x.is_none() || pos_y.is_none() {
+        problems.add(at, "a sprite isn't inside the level");
+    }
 
-    Ok(SpritePlacement::new(id, pos_x, pos_y, xbit, xbytes))
+    if problems.len() != before {
+        return None;
+    }
+    Some(SpritePlacement::new(def?.id, pos_x? as u16, pos_y? as u16, xbit, xbytes))
 }
 
-fn read_entrance_layer(context: &Context, map: &Node, levelnum: u16) -> Result<Vec<EntrancePlacement>, TmxError> {
+fn read_entrance_layer(scope: &Scope, levelnum: u16, problems: &mut Problems) -> Vec<EntrancePlacement> {
     let mut entlist = Vec::new();
-    
-    let firstgid = not_nan(
-        make_xpath_static("tileset[@name='entrances']/@firstgid")
-            .evaluate(&context, *map)?
-            .number(),
-    ).ok_or(
-        "couldn't find first tile for entrances; tileset 'entrances' may be missing",
-    )? as u16;
-    
-    for node in xpath_nodes_str(&context, *map, "objectgroup[@name='Entrances']/object")? {
-        entlist.push(entrance_from_node(&context, &node, levelnum, firstgid)?);
-    }
-
-    Ok(entlist)
-}
-
-fn entrance_from_node(
-    context: &Context,
-    node: &Node,
-    levelnum: u16,
-    firstgid: u16,
-) -> Result<EntrancePlacement, TmxError> {
-    let path = make_xpath_static("properties/property");
-    
+
+    let set = match problems.check(Location::layer("Entrances"), tileset::find(scope.tilesets, "entrances")) {
+        Some(set) => set,
+        None => return entlist,
+    };
+
+    for obj in scope.objects("Entrances") {
+        if let Some(en) = entrance_from_object(obj, levelnum, set, problems) {
+            entlist.push(en);
+        }
+    }
+
+    let primaries = entlist.iter().filter(|en| !en.id.secondary).count();
+    if primaries > 2 {
+        problems.add(Location::layer("Entrances"),
+            format!("there are {} main entrances (max is 2)", primaries));
+    }
+    let secondaries = entlist.len() - primaries;
+    if secondaries > 32 {
+        problems.add(Location::layer("Entrances"),
+            format!("there are {} secondary entrances (max is 32)", secondaries));
+    }
+
+    entlist
+}
+
+fn entrance_from_object(obj: &Object, levelnum: u16, set: &Tileset, problems: &mut Problems) -> Option<EntrancePlacement> {
+    let at = object_location("Entrances", obj);
+    let before = problems.len();
+
     let mut id = None;
+    let mut anim = None;
     let mut water = false;
     let mut slippery = false;
-    
-    for prop_node in xpath_nodes(context, *node, &path)? {
-        let name = node_element_attr(&prop_node, "name")
-            .ok_or("Invalid entrance property (has no name)")?;
-        match name {
-            // "fragment" => id = Some(EntranceId::from_num_and_fragment(levelnum, "m00").unwrap()),
-            "fragment" => {
-                id = Some(
-                    EntranceId::from_num_and_fragment(
-                        levelnum,
-                        node_element_attr(&prop_node, "value")
-                            .ok_or("Invalid entrance property (fragment): has no value")?,
-                    ).ok_or("Invalid entrance property (fragment)")?
-                );
+
+    for prop in obj.properties.iter() {
+        let v = match &prop.name[..] {
+            "fragment" => match EntranceId::from_num_and_fragment(levelnum, &prop.value) {
+                Some(ref i) if i.secondary && i.sub_id >= 0x20 =>
+                    Err("secondary entrances go from s0 to s1f".into()),
+                Some(ref i) if !i.secondary && i.sub_id >= 2 =>
+                    Err("main entrances go from m0 to m1".into()),
+                Some(i) => { id = Some(i); Ok(()) },
+                None => Err(format!("\"{}\" isn't an entrance", prop.value)),
             },
-            "water" =>
-                water = attr_bool(&prop_node, "value").ok_or("An entrance has an invalid boolean")?,
-            "slippery" =>
-                slippery = attr_bool(&prop_node, "value").ok_or("An entrance has an invalid boolean")?,
-            x => return Err(format!("Invalid entrance property: {}", x).into()),
+            "animation" => match prop.value.parse::<u8>() {
+                Ok(a) if a < 8 => { anim = Some(a); Ok(()) },
+                _ => Err(format!("\"{}\" isn't an animation, should be in 0 ..= 7", prop.value)),
+            },
+            "water" => prop.value.parse::<bool>().map(|b| water = b)
+                .map_err(|_| "should be a boolean".into()),
+            "slippery" => prop.value.parse::<bool>().map(|b| slippery = b)
+                .map_err(|_| "should be a boolean".into()),
+            _ => Err("isn't an entrance property".into()),
         };
+        if let Err(e) = v {
+            problems.add(at.clone().property(&prop.name), e);
+        }
     }
-    
-    let idv = id.ok_or("Entrance has no fragment")?;
-    
-    let anim = (attr_u32(node, "gid").ok_or("An entrance has an invalid animation")? - (firstgid as u32)) as u8;
-    
-    if anim >= 8 {
-        return Err(format!("An entrance has an invalid animation ({}), should be in 0 ..= 7", anim).into())
+
+    if id.is_none() && obj.properties.get("fragment").is_none() {
+        problems.add(at.clone(), "an entrance has no fragment");
     }
-    
+
+    // an "animation" property takes the place of the tile's
+    let tile_anim = match obj.gid.map(|g| Gid::from_raw(g).id) {
+        None => {
+            problems.add(at.clone(), "an entrance has to be a tile object");
+            None
+        },
+        Some(gid) if !set.contains(gid) => {
+            problems.add(at.clone(), "an entrance's tile isn't from the 'entrances' tileset");
+            None
+        },
+        Some(gid) if gid - set.firstgid >= 8 && anim.is_none() => {
+            problems.add(at.clone(), format!(
+                "an entrance has an invalid animation ({}), should be in 0 ..= 7", gid - set.firstgid
+            ));
+            None
+        },
+        Some(gid) => Some((gid - set.firstgid) as u8),
+    };
+    let anim = anim.or(tile_anim);
+
     // x, y - 32 is the top left of a 32x32 square
-    let pos_x = (attr_u32(node, "x").ok_or("An entrance has an invalid X pos")?) as u16 / 16;
-    let pos_y = (attr_u32(node, "y").ok_or("An entrance has an invalid Y pos")? - 32) as u16 / 16;
-    
-    Ok(EntrancePlacement::new(idv, pos_x, pos_y, anim, water, slippery))
+    let pos_x = level_pixel(obj.x).map(|x| x / 16);
+    let pos_y = level_pixel(obj.y).and_then(|y| y.checked_sub(32)).map(|y| y / 16);
+    if pos_x.is_none() || pos_y.is_none() {
+        problems.add(at, "an entrance isn't inside the level");
+    }
+
+    if problems.len() != before {
+        return None;
+    }
+    Some(EntrancePlacement::new(id?, pos_x? as u16, pos_y? as u16, anim?, slippery, water))
 }
 
 
-fn read_exit_layer(context: &Context, map: &Node, levelnum: u16) -> Result<BTreeMap<(u8, u8), EntranceId>, TmxError> {
+fn read_exit_layer(scope: &Scope, levelnum: u16, siblings: &[(&str, u16)], problems: &mut Problems)
+-> BTreeMap<(u8, u8), EntranceId> {
     let mut exitmap = BTreeMap::new();
-    for node in xpath_nodes_str(&context, *map, "objectgroup[@name='Exits']/object")? {
-        let (x, y, exit) = exit_from_node(&context, &node, levelnum)?;
-        if let Some(_) = exitmap.insert((x, y), exit) {
-            return Err(format!("screen {}, {} has two exit objects", x, y).into());
+    // which object each screen's exit came from
+    let mut owners = BTreeMap::new();
+    for obj in scope.objects("Exits") {
+        let (xs, ys, exit) = match exit_from_object(obj, levelnum, siblings, problems) {
+            Some(e) => e,
+            None => continue,
+        };
+        let mut clashes = Vec::new();
+        for y in ys {
+            for x in xs.clone() {
+                if let Some(other) = owners.insert((x, y), obj.id) {
+                    clashes.push((x, y, other));
+                }
+                exitmap.insert((x, y), exit);
+            }
+        }
+        if let Some(&(x, y, other)) = clashes.first() {
+            let more = match clashes.len() - 1 {
+                0 => String::new(),
+                n => format!(", as do {} other screen(s)", n),
+            };
+            problems.add(object_location("Exits", obj),
+                format!("screen {}, {} has two exit objects (this and object {}){}", x, y, other, more));
         }
     }
-    
-    Ok(exitmap)
+
+    exitmap
 }
 
-fn exit_from_node(context: &Context, node: &Node, levelnum: u16) -> Result<(u8, u8, EntranceId), TmxError> {
-    let path = make_xpath_static("properties/property");
-    
+/// An exit's target is either an entrance's full name like `105#s01`,
+/// `#s01` for an entrance in the same level,
+/// or `Name#s01` for one in the level group called `Name`.
+fn exit_target(v: &str, levelnum: u16, siblings: &[(&str, u16)]) -> Option<EntranceId> {
+    let (level, fragment) = match v.find('#') {
+        Some(i) => (&v[..i], &v[i + 1..]),
+        None => return None,
+    };
+    if level.is_empty() {
+        return EntranceId::from_num_and_fragment(levelnum, fragment);
+    }
+    match siblings.iter().find(|&&(name, _)| name == level) {
+        Some(&(_, n)) => EntranceId::from_num_and_fragment(n, fragment),
+        None => EntranceId::from_name(v),
+    }
+}
+
+/// Reads an exit, with the columns and rows of screens it covers.
+fn exit_from_object(obj: &Object, levelnum: u16, siblings: &[(&str, u16)], problems: &mut Problems)
+-> Option<(RangeInclusive<u8>, RangeInclusive<u8>, EntranceId)> {
+    let at = object_location("Exits", obj);
+    let before = problems.len();
+
     let mut id = None;
-    
-    for prop_node in xpath_nodes(context, *node, &path)? {
-        let name = node_element_attr(&prop_node, "name")
-            .ok_or("Invalid entrance property (has no name)")?;
-        match name {
+
+    for prop in obj.properties.iter() {
+        let v = match &prop.name[..] {
             "target" => {
-                let v = node_element_attr(&prop_node, "value")
-                            .ok_or("Invalid exit property (target): has no value")?;
-                id = Some(if v.starts_with('#') {
-                    let (_, rest) = v.split_at(1);
-                    EntranceId::from_num_and_fragment(levelnum, rest).ok_or("Invalid exit property (target)")?
-                } else {
-                    EntranceId::from_name(v).ok_or("Invalid exit property (target)")?
-                });
+                let v = &prop.value;
+                let target = exit_target(v, levelnum, siblings);
+                id = target;
+                target.map(|_| ()).ok_or_else(|| format!("\"{}\" isn't an entrance", v))
             },
-            x => return Err(format!("Invalid exit property: {}", x).into()),
+            _ => Err("isn't an exit property".into()),
         };
+        if let Err(e) = v {
+            problems.add(at.clone().property(&prop.name), e);
+        }
     }
-    
-    let idv = id.ok_or("Exit has no path")?;
-    
-    let scr_x = (attr_u32(node, "x").ok_or("An entrance has an invalid X pos")? / 256) as u8;
-    let scr_y = (attr_u32(node, "y").ok_or("An entrance has an invalid Y pos")? / 256) as u8;
-    
-    Ok((scr_x, scr_y, idv))
+
+    if id.is_none() && obj.properties.get("target").is_none() {
+        problems.add(at.clone(), "an exit has no target");
+    }
+
+    let covered = screens_covered(obj);
+    if covered.is_none() {
+        problems.add(at, "an exit isn't inside the level");
+    }
+
+    if problems.len() != before {
+        return None;
+    }
+    let (xs, ys) = covered?;
+    let narrow = |r: RangeInclusive<u32>| *r.start() as u8 ..= *r.end() as u8;
+    Some((narrow(xs), narrow(ys), id?))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fg_with(props: Vec<Property>) -> Tileset {
+        let mut tile_props = BTreeMap::new();
+        tile_props.insert(0x130, Properties(props));
+        Tileset { firstgid: 1, name: "fg".into(), tilecount: None, tile_props }
+    }
+
+    fn prop(name: &str, ty: &str, value: &str) -> Property {
+        Property { name: name.into(), ty: ty.into(), value: value.into() }
+    }
+
+    #[test]
+    fn flipped_tiles_use_their_variants() {
+        let set = fg_with(vec![prop("flip-h", "string", "131"), prop("flip-v", "int", "306")]);
+        assert_eq!(tile_val(Gid::from_raw(0), &set), Ok(0x25));
+        assert_eq!(tile_val(Gid::from_raw(0x131), &set), Ok(0x130));
+        assert_eq!(tile_val(Gid::from_raw(0x8000_0131), &set), Ok(0x131));
+        assert_eq!(tile_val(Gid::from_raw(0x4000_0131), &set), Ok(306));
+    }
+
+    #[test]
+    fn flips_without_a_variant_are_errors() {
+        let set = fg_with(vec![prop("flip-h", "string", "zzz")]);
+        assert_eq!(tile_val(Gid::from_raw(0x8000_0131), &set),
+            Err("tile 130 has an invalid \"flip-h\" property \"zzz\"".to_string()));
+        assert!(tile_val(Gid::from_raw(0xa000_0131), &set).unwrap_err().contains("no \"flip-hd\" property"));
+        assert!(tile_val(Gid::from_raw(0x4000_0002), &set).is_err());
+    }
+
+    /// A TMX map with empty Level and BG layers, and then `groups`.
+    fn map_with(groups: &str) -> String {
+        let zeros = vec!["0"; 512 * 512].join(",");
+        format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.10" orientation="orthogonal" width="512" height="512" tilewidth="16" tileheight="16">
+<tileset firstgid="1" name="fg" tilecount="65536"/>
+<tileset firstgid="70000" name="sprites" tilecount="1024"/>
+<tileset firstgid="100" name="entrances" tilecount="8"/>
+<layer id="1" name="Level" width="512" height="512"><data encoding="csv">{0}</data></layer>
+<layer id="2" name="BG" width="512" height="512"><data encoding="csv">{0}</data></layer>
+{1}
+</map>"#, zeros, groups)
+    }
+
+    fn map_with_entrances(objects: &str) -> String {
+        map_with(&format!(r#"<objectgroup id="3" name="Entrances">{}</objectgroup>"#, objects))
+    }
+
+    fn problems_in(tmx: &str) -> Vec<String> {
+        match read_level(&mut tmx.as_bytes(), ".", 0x105) {
+            Err(TmxError::Invalid(problems)) => problems.into_iter().map(|p| p.msg).collect(),
+            Err(e) => panic!("{}", e),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    fn entrance(id: u32, x: u32, fragment: &str) -> String {
+        format!(r#"<object id="{}" gid="100" x="{}" y="64" width="32" height="32"><properties><property name="fragment" value="{}"/></properties></object>"#,
+            id, x, fragment)
+    }
+
+    #[test]
+    fn too_many_main_entrances_is_a_problem() {
+        let objects = entrance(10, 32, "m0") + &entrance(11, 64, "m1") + &entrance(12, 96, "m0");
+        assert_eq!(problems_in(&map_with_entrances(&objects)), ["there are 3 main entrances (max is 2)"]);
+    }
+
+    #[test]
+    fn two_main_entrances_are_fine() {
+        let objects = entrance(10, 32, "m0") + &entrance(11, 64, "m1");
+        assert!(problems_in(&map_with_entrances(&objects)).is_empty());
+    }
+
+    #[test]
+    fn objects_outside_the_level() {
+        let entrance_at = |x: &str, y: &str| map_with_entrances(&format!(
+            r#"<object id="10" gid="100" x="{}" y="{}"><properties><property name="fragment" value="m0"/></properties></object>"#,
+            x, y));
+        let outside = ["an entrance isn't inside the level"];
+        assert!(problems_in(&entrance_at("0", "32")).is_empty());
+        assert!(problems_in(&entrance_at("8191", "8191")).is_empty());
+        assert_eq!(problems_in(&entrance_at("-16", "64")), outside);
+        assert_eq!(problems_in(&entrance_at("nan", "64")), outside);
+        assert_eq!(problems_in(&entrance_at("8192", "64")), outside);
+        assert_eq!(problems_in(&entrance_at("32", "16")), outside);
+
+        let sprite_at = |x: &str, y: &str| map_with(&format!(
+            r#"<objectgroup id="3" name="Sprites"><object id="10" gid="70001" x="{}" y="{}"/></objectgroup>"#,
+            x, y));
+        let outside = ["a sprite isn't inside the level"];
+        assert!(problems_in(&sprite_at("0", "16")).is_empty());
+        assert_eq!(problems_in(&sprite_at("-32", "64")), outside);
+        assert_eq!(problems_in(&sprite_at("inf", "64")), outside);
+        assert_eq!(problems_in(&sprite_at("8176", "64")), outside);
+        assert_eq!(problems_in(&sprite_at("1e300", "64")), outside);
+        assert_eq!(problems_in(&sprite_at("64", "-64")), outside);
+    }
+
+    fn header_from(props: Vec<Property>) -> (LevelHeader, Vec<String>) {
+        let mut problems = Problems::default();
+        let hed = read_header(&Properties(props), Path::new("."), &mut problems);
+        (hed, problems.found.into_iter().map(|p| p.msg).collect())
+    }
+
+    #[test]
+    fn time_limit_scroll_and_shared_palette() {
+        let (hed, problems) = header_from(vec![
+            prop("time-limit", "int", "15"),
+            prop("scroll-allowance-numeric", "int", "7"),
+            prop("palette-fg", "int", "31"),
+            prop("palette-sky", "int", "2"),
+        ]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!((hed.time, hed.scroll), (15, 7));
+        match hed.palette {
+            Palette::Shared(p) => assert_eq!((p.fg, p.sky), (31, 2)),
+            Palette::Custom(_) => panic!("a shared palette came out custom"),
+        }
+    }
+
+    #[test]
+    fn shared_palette_indices_are_checked() {
+        let (_, problems) = header_from(vec![prop("time-limit", "int", "16"), prop("palette-bg", "int", "8")]);
+        assert_eq!(problems, ["16 is out of range (max is 15)", "8 is out of range (max is 7)"]);
+
+        let (_, problems) = header_from(vec![prop("palette", "file", "level.pal"), prop("palette-sp", "int", "1")]);
+        assert!(problems.contains(&"a level with a palette file can't use shared palettes too".to_string()), "{:?}", problems);
+    }
+
+    fn sprites_with(props: Vec<Property>) -> Tileset {
+        let mut tile_props = BTreeMap::new();
+        tile_props.insert(5, Properties(props));
+        Tileset { firstgid: 70000, name: "sprites".into(), tilecount: Some(1024), tile_props }
+    }
+
+    fn sprite_problems(set: &Tileset, props: Vec<Property>) -> Vec<String> {
+        let obj = Object {
+            id: 10, name: String::new(), gid: Some(70005),
+            x: 64.0, y: 64.0, width: 32.0, height: 32.0,
+            properties: Properties(props),
+        };
+        let mut problems = Problems::default();
+        sprite_from_object(&obj, set, &mut problems);
+        problems.found.into_iter().map(|p| p.msg).collect()
+    }
+
+    #[test]
+    fn sprite_tiles_give_numbers_and_defaults() {
+        let set = sprites_with(vec![
+            prop("sprite", "string", "1a3"),
+            prop("ebit", "bool", "true"),
+            prop("extra-bytes", "int", "2"),
+            prop("xb2", "string", "7f"),
+            prop("xb1-name", "string", "speed"),
+        ]);
+        let def = SpriteDef::from_tile(&set, 5).unwrap();
+        assert_eq!((def.id, def.xbit, def.xbytes, def.xbyte_count), (0x1a3, true, [0, 0x7f, 0, 0], 2));
+        assert_eq!(def.field("speed"), Some(0));
+        assert_eq!(def.field("xb4"), Some(3));
+        assert_eq!(def.field("colour"), None);
+
+        let plain = SpriteDef::from_tile(&set, 6).unwrap();
+        assert_eq!((plain.id, plain.xbit, plain.xbyte_count), (6, false, 4));
+    }
+
+    #[test]
+    fn sprite_objects_are_checked_against_their_tile() {
+        let set = sprites_with(vec![prop("extra-bytes", "int", "1"), prop("xb1-name", "string", "speed")]);
+        assert!(sprite_problems(&set, vec![prop("speed", "string", "20")]).is_empty());
+        assert_eq!(sprite_problems(&set, vec![prop("xb2", "string", "20")]), ["this sprite only has 1 extra bytes"]);
+        assert_eq!(sprite_problems(&set, vec![prop("colour", "string", "red")]), ["isn't a property of this sprite"]);
+
+        let bad = sprites_with(vec![prop("sprite", "string", "400")]);
+        assert_eq!(sprite_problems(&bad, vec![]), ["sprite tile 5 has a bad \"sprite\" property: \"400\" isn't a sprite number"]);
+    }
+
+    #[test]
+    fn exit_targets_can_name_a_level_group() {
+        let siblings = [("Castle", 0x105), ("Tower", 0x1cb)];
+        assert_eq!(exit_target("106#m0", 0x105, &siblings), EntranceId::from_name("106#m0"));
+        assert_eq!(exit_target("#s01", 0x105, &siblings), EntranceId::from_num_and_fragment(0x105, "s01"));
+        assert_eq!(exit_target("Tower#m1", 0x105, &siblings), EntranceId::from_num_and_fragment(0x1cb, "m1"));
+        assert_eq!(exit_target("Dungeon#m0", 0x105, &siblings), None);
+        assert_eq!(exit_target("m0", 0x105, &siblings), None);
+    }
+
+    /// A level group with empty Level and BG layers, and then `rest`.
+    fn level_group(name: &str, number: &str, rest: &str) -> String {
+        let zeros = vec!["0"; 512 * 512].join(",");
+        format!(r#"<group name="{0}"><properties><property name="level-number" value="{1}"/></properties>
+<layer name="Level" width="512" height="512"><data encoding="csv">{2}</data></layer>
+<layer name="BG" width="512" height="512"><data encoding="csv">{2}</data></layer>
+{3}</group>"#, name, number, zeros, rest)
+    }
+
+    fn map_of_groups(groups: &[String]) -> String {
+        format!(r#"<map><tileset firstgid="1" name="fg" tilecount="65536"/>
+<tileset firstgid="70000" name="sprites" tilecount="1024"/>
+<tileset firstgid="100" name="entrances" tilecount="8"/>
+{}</map>"#, groups.concat())
+    }
+
+    #[test]
+    fn each_level_group_is_a_level() {
+        let exit = r##"<objectgroup name="Exits"><object id="1" x="0" y="0"><properties><property name="target" value="Tower#m0"/></properties></object></objectgroup>"##;
+        let tmx = map_of_groups(&[level_group("Castle", "105", exit), level_group("Tower", "1cb", "")]);
+        let levels = read_levels(&mut tmx.as_bytes(), ".").unwrap();
+        assert_eq!(levels.iter().map(|&(n, _)| n).collect::<Vec<_>>(), [0x105, 0x1cb]);
+
+        let one = read_level(&mut tmx.as_bytes(), ".", 0x1cb);
+        assert!(one.is_ok());
+        assert!(read_level(&mut tmx.as_bytes(), ".", 0x106).is_err());
+    }
+
+    #[test]
+    fn level_groups_need_different_numbers() {
+        let tmx = map_of_groups(&[level_group("Castle", "105", ""), level_group("Tower", "105", "")]);
+        assert_eq!(problems_in(&tmx), ["another level group is level 105 too"]);
+        let tmx = map_of_groups(&[level_group("Castle", "zzz", "")]);
+        assert_eq!(problems_in(&tmx), ["\"zzz\" isn't a level number"]);
+    }
+
+    fn exit_rect(x: f64, y: f64, width: f64, height: f64) -> String {
+        map_with(&format!(r##"<objectgroup id="3" name="Exits"><object id="10" x="{}" y="{}" width="{}" height="{}"><properties><property name="target" value="106#m0"/></properties></object></objectgroup>"##,
+            x, y, width, height))
+    }
+
+    #[test]
+    fn exits_outside_the_level() {
+        let outside = ["an exit isn't inside the level"];
+        assert!(problems_in(&exit_rect(0.0, 0.0, 256.0, 256.0)).is_empty());
+        assert!(problems_in(&exit_rect(8191.0, 8191.0, 0.0, 0.0)).is_empty());
+        assert_eq!(problems_in(&exit_rect(-256.0, 0.0, 256.0, 32.0)), outside);
+        assert_eq!(problems_in(&exit_rect(-0.5, 0.0, 0.0, 0.0)), outside);
+        assert_eq!(problems_in(&exit_rect(0.0, -1000.0, 32.0, 32.0)), outside);
+        assert_eq!(problems_in(&exit_rect(0.0, 8100.0, 32.0, 100.0)), outside);
+    }
+
+    fn scroll_rect(x: f64, y: f64, width: f64, height: f64) -> String {
+        map_with(&format!(r#"<objectgroup id="3" name="Scroll"><object id="10" x="{}" y="{}" width="{}" height="{}"/></objectgroup>"#,
+            x, y, width, height))
+    }
+
+    #[test]
+    fn scroll_rectangles_outside_the_level() {
+        let outside = ["a scroll filter rectangle isn't inside the level"];
+        assert!(problems_in(&scroll_rect(0.0, 0.0, 8192.0, 8192.0)).is_empty());
+        assert!(problems_in(&scroll_rect(8000.0, 300.0, 0.0, 0.0)).is_empty());
+        assert_eq!(problems_in(&scroll_rect(-16.0, 0.0, 32.0, 32.0)), outside);
+        assert_eq!(problems_in(&scroll_rect(0.0, -300.0, 32.0, 100.0)), outside);
+        assert_eq!(problems_in(&scroll_rect(-300.0, 0.0, 0.0, 0.0)), outside);
+        assert_eq!(problems_in(&scroll_rect(8000.0, 0.0, 200.0, 32.0)), outside);
+        assert_eq!(problems_in(&scroll_rect(0.0, 8192.0, 0.0, 0.0)), outside);
+    }
+
+    fn scroll_filter(auto: bool) -> Vec<bool> {
+        let mut scroll = vec!["0"; 512 * 512];
+        // a tile on screen (1, 0)
+        scroll[3 * 512 + 20] = "1";
+        let tmx = format!(r#"<map><properties><property name="scroll-filter-auto" type="bool" value="{}"/></properties>
+<layer name="Scroll" width="512" height="512"><data encoding="csv">{}</data></layer>
+<objectgroup name="Scroll"><object id="1" x="600" y="10" width="300" height="20"/></objectgroup>
+</map>"#, auto, scroll.join(","));
+        let map = xml::read_map(&mut tmx.as_bytes(), Path::new(".")).unwrap();
+
+        let mut fg_tiles = vec![0x0025; 512 * 512];
+        // screen (0, 0) isn't empty
+        fg_tiles[0] = 0x0130;
+        let mut problems = Problems::default();
+        let filt = read_scroll_filter(&map.whole(), &fg_tiles, &mut problems);
+        assert_eq!(problems.len(), 0);
+        filt
+    }
+
+    #[test]
+    fn scroll_filter_from_tiles_rectangles_and_empty_screens() {
+        let filt = scroll_filter(false);
+        let marked: Vec<_> = (0 .. 1024).filter(|&i| filt[i]).collect();
+        assert_eq!(marked, [1, 2, 3]);
+
+        let filt = scroll_filter(true);
+        assert!(!filt[0]);
+        assert!(filt[1 ..].iter().all(|&f| f));
+    }
+}
@@ -0,0 +1,112 @@
+//! Object templates (.tx, or .tj in JSON).
+//!
+//! A template is an object that objects in the map can be made from.
+//! The map only has what each of those objects changes from its template,
+//! so their template is what they start as before that gets read over it.
+//!
+//! A template's tile object refers to its tile by the template's own
+//! `firstgid` for the tileset, which usually isn't the map's,
+//! so its gid is moved over to the map's tileset with the same name.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use super::TmxError;
+use super::map::{Gid, Object};
+use super::tileset::{self, Tileset};
+use super::{tmj, xml};
+
+/// The templates a map uses, each loaded the first time it's needed.
+pub struct Templates {
+    dir: PathBuf,
+    loaded: HashMap<PathBuf, Object>,
+}
+
+impl Templates {
+    /// Templates are found relative to `dir`, the map's directory.
+    pub fn new(dir: &Path) -> Templates {
+        Templates { dir: dir.to_path_buf(), loaded: HashMap::new() }
+    }
+
+    /// The object the template at `source` describes, with its gid
+    /// changed to refer to the same tile in `tilesets`.
+    pub fn get(&mut self, source: &str, tilesets: &[Tileset]) -> Result<Object, TmxError> {
+        let path = self.dir.join(source);
+        if let Some(obj) = self.loaded.get(&path) {
+            return Ok(obj.clone());
+        }
+        let obj = read(&path, tilesets)
+            .map_err(|e| format!("in template {}: {}", path.to_string_lossy(), e))?;
+        self.loaded.insert(path, obj.clone());
+        Ok(obj)
+    }
+}
+
+fn read(path: &Path, tilesets: &[Tileset]) -> Result<Object, TmxError> {
+    let buf = {
+        let mut f = File::open(path).map_err(|e| format!("couldn't open template: {}", e))?;
+        let mut buf = String::new();
+        f.read_to_string(&mut buf).map_err(|e| format!("couldn't read template: {}", e))?;
+        buf
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let (set, mut obj) = match path.extension().and_then(|e| e.to_str()) {
+        Some("tj") | Some("json") => tmj::read_template(&buf, dir)?,
+        _ => xml::read_template(&buf, dir)?,
+    };
+
+    if let Some(raw) = obj.gid {
+        let set = set.ok_or("the template is a tile, but has no tileset")?;
+        let gid = Gid::from_raw(raw);
+        if !set.contains(gid.id) {
+            return Err(format!("the template's tile isn't from tileset '{}'", set.name).into());
+        }
+        let map_set = tileset::find(tilesets, &set.name)?;
+        // keep the flip flags, which are whatever's left over from the ID
+        obj.gid = Some(raw - gid.id + map_set.firstgid + (gid.id - set.firstgid));
+    }
+    Ok(obj)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn objects_start_as_their_template() {
+        let dir = env::temp_dir().join(format!("exlev-tx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sprites.tsx"), r#"<tileset name="sprites" tilecount="1024"/>"#).unwrap();
+        fs::write(dir.join("koopa.tx"), r#"<?xml version="1.0" encoding="UTF-8"?>
+<template><tileset firstgid="1" source="sprites.tsx"/>
+<object name="koopa" gid="2147483653" width="32" height="32"><properties>
+<property name="xb1" value="01"/><property name="xb2" value="02"/>
+</properties></object></template>"#).unwrap();
+
+        let tmx = r#"<map><tileset firstgid="1" name="fg"/><tileset firstgid="70000" source="sprites.tsx"/>
+<objectgroup name="Sprites">
+<object id="7" template="koopa.tx" x="64" y="96"><properties><property name="xb2" value="ff"/></properties></object>
+<object id="8" template="koopa.tx" name="shell" gid="70001" x="0" y="16"/>
+</objectgroup></map>"#;
+        let map = xml::read_map(&mut tmx.as_bytes(), &dir);
+        let missing = xml::read_map(&mut r#"<map><objectgroup name="Sprites">
+<object id="1" template="nope.tx" x="0" y="0"/></objectgroup></map>"#.as_bytes(), &dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let map = map.unwrap();
+        assert!(missing.is_err());
+
+        let objs: Vec<_> = map.whole().objects("Sprites").collect();
+        // tile 4 of the template's tileset, still flipped, is tile 4 of the map's
+        assert_eq!(objs[0].gid, Some(0x8000_0000 | 70004));
+        assert_eq!((objs[0].id, &objs[0].name[..], objs[0].x, objs[0].y, objs[0].width), (7, "koopa", 64.0, 96.0, 32.0));
+        assert_eq!(objs[0].properties.get("xb1").unwrap().value, "01");
+        assert_eq!(objs[0].properties.get("xb2").unwrap().value, "ff");
+
+        assert_eq!((objs[1].gid, &objs[1].name[..]), (Some(70001), "shell"));
+        assert_eq!(objs[1].properties.get("xb2").unwrap().value, "02");
+    }
+}
@@ -0,0 +1,132 @@
+//! Tilesets, whether they're embedded in the map or in external files.
+//!
+//! An external tileset only has its `firstgid` and `source` in the map;
+//! everything else, including its name, is in the .tsx (or .tsj) file,
+//! which is found relative to the map.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use super::TmxError;
+use super::map::Properties;
+use super::{tmj, xml};
+
+pub struct Tileset {
+    pub firstgid: u32,
+    pub name: String,
+    pub tilecount: Option<u32>,
+    /// The custom properties of individual tiles, by their ID within the tileset.
+    pub tile_props: BTreeMap<u32, Properties>,
+}
+
+impl Tileset {
+    /// Whether `gid` is one of this tileset's tiles.
+    /// Without a tilecount, every gid from `firstgid` up counts.
+    pub fn contains(&self, gid: u32) -> bool {
+        gid >= self.firstgid && self.tilecount.is_none_or(|n| gid - self.firstgid < n)
+    }
+
+    pub fn props(&self, local_id: u32) -> Option<&Properties> {
+        self.tile_props.get(&local_id)
+    }
+}
+
+/// Finds the tileset named `name`, which the map has to have exactly one of.
+pub fn find<'a>(sets: &'a [Tileset], name: &str) -> Result<&'a Tileset, TmxError> {
+    let mut found = sets.iter().filter(|s| s.name == name);
+    match (found.next(), found.next()) {
+        (Some(s), None) => Ok(s),
+        (None, _) => Err(format!("couldn't find tileset '{}'; it may be missing", name).into()),
+        (Some(_), Some(_)) => Err(format!("there's more than one tileset named '{}'", name).into()),
+    }
+}
+
+/// Loads an external tileset, in whichever format its extension says.
+/// Either kind of map can use either kind of tileset.
+pub fn read_external(path: &Path, firstgid: u32) -> Result<Tileset, TmxError> {
+    let buf = {
+        let mut f = File::open(path)
+            .map_err(|e| format!("couldn't open tileset {}: {}", path.to_string_lossy(), e))?;
+        let mut buf = String::new();
+        f.read_to_string(&mut buf)
+            .map_err(|e| format!("couldn't read tileset {}: {}", path.to_string_lossy(), e))?;
+        buf
+    };
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("tsj") | Some("json") => tmj::read_tileset(&buf, firstgid),
+        _ => xml::read_tileset(&buf, firstgid),
+    }.map_err(|e| format!("in tileset {}: {}", path.to_string_lossy(), e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn tilesets_in(tmx: &str, dir: &Path) -> Result<Vec<Tileset>, TmxError> {
+        xml::read_map(&mut tmx.as_bytes(), dir).map(|map| map.tilesets)
+    }
+
+    fn prop<'a>(set: &'a Tileset, id: u32, name: &str) -> &'a str {
+        &set.props(id).unwrap().get(name).unwrap().value
+    }
+
+    #[test]
+    fn embedded_tileset_with_tile_properties() {
+        let sets = tilesets_in(r#"<map>
+<tileset firstgid="1" name="fg" tilecount="4">
+<tile id="2"><properties><property name="solid" value="true"/><property name="note">long
+text</property></properties></tile>
+<tile id="3"/>
+</tileset>
+<tileset firstgid="5" name="sprites"/>
+</map>"#, Path::new(".")).unwrap();
+
+        let fg = find(&sets, "fg").unwrap();
+        assert_eq!(prop(fg, 2, "solid"), "true");
+        assert_eq!(prop(fg, 2, "note"), "long\ntext");
+        assert!(fg.props(3).is_none());
+        assert!(fg.contains(1) && fg.contains(4));
+        assert!(!fg.contains(0) && !fg.contains(5));
+
+        let sprites = find(&sets, "sprites").unwrap();
+        assert!(sprites.contains(5) && sprites.contains(100_000));
+    }
+
+    #[test]
+    fn external_tilesets_are_found_next_to_the_map() {
+        let dir = env::temp_dir().join(format!("exlev-tsx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("fg.tsx"), r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset name="fg" tilecount="8"><tile id="1"><properties><property name="a" value="b"/></properties></tile></tileset>"#).unwrap();
+        fs::write(dir.join("sprites.tsj"), r#"{"name": "sprites", "tilecount": 16,
+"tiles": [{"id": 3, "properties": [{"name": "c", "type": "string", "value": "d"}]}]}"#).unwrap();
+
+        let sets = tilesets_in(r#"<map><tileset firstgid="10" source="fg.tsx"/><tileset firstgid="20" source="sprites.tsj"/></map>"#, &dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let sets = sets.unwrap();
+
+        let fg = find(&sets, "fg").unwrap();
+        assert_eq!(fg.firstgid, 10);
+        assert!(fg.contains(17) && !fg.contains(18));
+        assert_eq!(prop(fg, 1, "a"), "b");
+
+        let sprites = find(&sets, "sprites").unwrap();
+        assert_eq!(sprites.firstgid, 20);
+        assert!(sprites.contains(35) && !sprites.contains(36));
+        assert_eq!(prop(sprites, 3, "c"), "d");
+    }
+
+    #[test]
+    fn missing_and_duplicate_tilesets() {
+        let sets = tilesets_in(r#"<map><tileset firstgid="1" name="fg"/><tileset firstgid="9" name="fg"/></map>"#,
+            Path::new(".")).unwrap();
+        assert!(find(&sets, "fg").is_err());
+        assert!(find(&sets, "sprites").is_err());
+        assert!(tilesets_in(r#"<map><tileset firstgid="1" source="nope.tsx"/></map>"#, Path::new(".")).is_err());
+        assert!(tilesets_in(r#"<map><tileset name="fg"/></map>"#, Path::new(".")).is_err());
+    }
+}
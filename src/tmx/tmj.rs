@@ -0,0 +1,247 @@
+//! Reads Tiled's JSON maps (.tmj) and tilesets (.tsj) into a `Map`.
+
+use std::io;
+use std::path::Path;
+
+use super::json::{self, Json};
+use super::TmxError;
+use super::map::{self, Map, Layer, Group, TileLayer, ObjectGroup, Object, Property, Properties};
+use super::tileset::{self, Tileset};
+use super::template::Templates;
+
+pub fn read_map<R: io::Read>(source: &mut R, dir: &Path) -> Result<Map, TmxError> {
+    let mut buf = String::new();
+    source.read_to_string(&mut buf).map_err(|e| format!("couldn't read JSON map: {}", e))?;
+    let root = json::parse(&buf)?;
+
+    let properties = read_properties(&root)?;
+
+    let mut tilesets = Vec::new();
+    for ts in array(&root, "tilesets")? {
+        let firstgid = u32_field(ts, "firstgid").ok_or("a tileset has no firstgid")?;
+        tilesets.push(if let Some(source) = ts.get("source").and_then(Json::as_str) {
+            tileset::read_external(&dir.join(source), firstgid)?
+        } else {
+            read_tileset_object(ts, firstgid)?
+        });
+    }
+
+    let mut layers = Vec::new();
+    let mut templates = Templates::new(dir);
+    for layer in array(&root, "layers")? {
+        if let Some(layer) = read_layer(layer, &mut templates, &tilesets)? {
+            layers.push(layer);
+        }
+    }
+
+    Ok(Map { properties, tilesets, layers })
+}
+
+/// Reads any kind of layer, or nothing if it's a kind exlev doesn't use.
+fn read_layer(layer: &Json, templates: &mut Templates, tilesets: &[Tileset]) -> Result<Option<Layer>, TmxError> {
+    Ok(Some(match str_field(layer, "type") {
+        "tilelayer" => Layer::Tiles(read_tile_layer(layer)?),
+        "objectgroup" => Layer::Objects(read_object_group(layer, templates, tilesets)?),
+        "group" => {
+            let mut layers = Vec::new();
+            for child in array(layer, "layers")? {
+                if let Some(l) = read_layer(child, templates, tilesets)? {
+                    layers.push(l);
+                }
+            }
+            Layer::Group(Group {
+                name: str_field(layer, "name").to_string(),
+                properties: read_properties(layer)?,
+                layers,
+            })
+        },
+        _ => return Ok(None),
+    }))
+}
+
+/// Reads a whole .tsj file.
+pub fn read_tileset(buf: &str, firstgid: u32) -> Result<Tileset, TmxError> {
+    let root = json::parse(buf)?;
+    read_tileset_object(&root, firstgid)
+}
+
+/// Reads a whole .tj file, into its tileset (if it's a tile) and its object.
+pub fn read_template(buf: &str, dir: &Path) -> Result<(Option<Tileset>, Object), TmxError> {
+    let root = json::parse(buf)?;
+    let set = match root.get("tileset") {
+        Some(ts) => {
+            let firstgid = u32_field(ts, "firstgid").ok_or("a tileset has no firstgid")?;
+            let source = ts.get("source").and_then(Json::as_str).ok_or("a tileset has no source")?;
+            Some(tileset::read_external(&dir.join(source), firstgid)?)
+        },
+        None => None,
+    };
+    let obj = root.get("object").ok_or("a template has no object")?;
+    Ok((set, read_object(obj, Object::default())?))
+}
+
+/// An array field, where a missing one is the same as an empty one.
+fn array<'j>(v: &'j Json, key: &str) -> Result<&'j [Json], TmxError> {
+    match v.get(key) {
+        None => Ok(&[]),
+        Some(a) => a.as_array().ok_or_else(|| format!("\"{}\" should be an array", key).into()),
+    }
+}
+
+fn str_field<'j>(v: &'j Json, key: &str) -> &'j str {
+    v.get(key).and_then(Json::as_str).unwrap_or("")
+}
+
+fn u32_field(v: &Json, key: &str) -> Option<u32> {
+    v.get(key).and_then(Json::as_f64).map(|f| f as u32)
+}
+
+/// Turns a property's value back into the text it would have in a TMX file,
+/// so that both formats go through the same parsing afterward.
+fn value_text(v: &Json) -> String {
+    match *v {
+        Json::String(ref s) => s.clone(),
+        Json::Bool(b) => b.to_string(),
+        Json::Number(n) if n.fract() == 0.0 => (n as i64).to_string(),
+        Json::Number(n) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn read_properties(v: &Json) -> Result<Properties, TmxError> {
+    let mut props = Vec::new();
+    for p in array(v, "properties")? {
+        let name = p.get("name").and_then(Json::as_str)
+            .ok_or("a property has no name")?
+            .to_string();
+        let ty = p.get("type").and_then(Json::as_str).unwrap_or("string").to_string();
+        // a class's members are read as if they were set by themselves
+        if ty == "class" {
+            if let Some(members) = p.get("value") {
+                class_members(members, &mut props);
+            }
+            continue;
+        }
+        let value = p.get("value").map(value_text).unwrap_or_default();
+        props.push(Property { name, ty, value });
+    }
+    Ok(Properties(props))
+}
+
+/// Reads the members of a class property's value.
+/// Unlike other properties, these don't say what type they are,
+/// so it's guessed from the JSON.
+fn class_members(value: &Json, props: &mut Vec<Property>) {
+    let members = match *value {
+        Json::Object(ref kvs) => kvs,
+        _ => return,
+    };
+    for (name, v) in members {
+        let ty = match *v {
+            Json::Object(_) => {
+                class_members(v, props);
+                continue;
+            },
+            Json::Bool(_) => "bool",
+            Json::Number(n) if n.fract() == 0.0 => "int",
+            Json::Number(_) => "float",
+            _ => "string",
+        };
+        props.push(Property { name: name.clone(), ty: ty.to_string(), value: value_text(v) });
+    }
+}
+
+fn read_tileset_object(ts: &Json, firstgid: u32) -> Result<Tileset, TmxError> {
+    let name = ts.get("name").and_then(Json::as_str)
+        .ok_or("a tileset has no name")?
+        .to_string();
+
+    let mut tile_props = ::std::collections::BTreeMap::new();
+    for tile in array(ts, "tiles")? {
+        let id = u32_field(tile, "id")
+            .ok_or_else(|| format!("a tile in tileset '{}' has no id", name))?;
+        let props = read_properties(tile)?;
+        if !props.0.is_empty() {
+            tile_props.insert(id, props);
+        }
+    }
+
+    Ok(Tileset {
+        firstgid,
+        tilecount: u32_field(ts, "tilecount"),
+        name,
+        tile_props,
+    })
+}
+
+fn read_tile_layer(layer: &Json) -> Result<TileLayer, TmxError> {
+    let name = str_field(layer, "name").to_string();
+    let width = u32_field(layer, "width").ok_or("a layer has no width")?;
+    let height = u32_field(layer, "height").ok_or("a layer has no height")?;
+
+    let gids = match layer.get("data") {
+        Some(Json::Array(tiles)) => tiles.iter().enumerate().map(|(i, t)| {
+            t.as_f64().map(|f| f as u32).ok_or_else(|| map::bad_tile_id(&name, width, i))
+        }).collect::<Result<Vec<u32>, TmxError>>()?,
+        Some(Json::String(text)) => {
+            if str_field(layer, "encoding") != "base64" {
+                return Err(format!("layer {} has string data that isn't base64", name).into());
+            }
+            map::gids_from_base64(text, layer.get("compression").and_then(Json::as_str))?
+        },
+        // infinite maps keep their tiles in "chunks" instead
+        _ => return Err(format!("layer {} has no tile data", name).into()),
+    };
+
+    if gids.len() < (width * height) as usize {
+        Err("a layer has too few tiles for its dimensions".into())
+    } else if gids.len() > (width * height) as usize {
+        Err("a layer has too many tiles for its dimensions".into())
+    } else {
+        Ok(TileLayer { name, width, height, gids })
+    }
+}
+
+fn read_object_group(
+    group: &Json,
+    templates: &mut Templates,
+    tilesets: &[Tileset],
+) -> Result<ObjectGroup, TmxError> {
+    let name = str_field(group, "name").to_string();
+    let mut objects = Vec::new();
+    for obj in array(group, "objects")? {
+        let id = u32_field(obj, "id").unwrap_or(0);
+        for key in &["x", "y"] {
+            if obj.get(key).and_then(Json::as_f64).is_none() {
+                return Err(format!("object {} in group {} has an invalid {} pos",
+                    id, name, key.to_uppercase()).into());
+            }
+        }
+        let base = match obj.get("template").and_then(Json::as_str) {
+            Some(source) => templates.get(source, tilesets)?,
+            None => Object::default(),
+        };
+        objects.push(read_object(obj, base)?);
+    }
+    Ok(ObjectGroup { name, objects })
+}
+
+/// Reads an object over `base`, which is its template if it has one.
+fn read_object(obj: &Json, base: Object) -> Result<Object, TmxError> {
+    let num = |key: &str| obj.get(key).and_then(Json::as_f64);
+    Ok(Object {
+        id: u32_field(obj, "id").unwrap_or(base.id),
+        name: obj.get("name").and_then(Json::as_str).map_or(base.name, String::from),
+        // Tiled writes a gid of 0 for objects that aren't tiles, or none at all
+        gid: match u32_field(obj, "gid") {
+            Some(0) => None,
+            Some(g) => Some(g),
+            None => base.gid,
+        },
+        x: num("x").unwrap_or(base.x),
+        y: num("y").unwrap_or(base.y),
+        width: num("width").unwrap_or(base.width),
+        height: num("height").unwrap_or(base.height),
+        properties: read_properties(obj)?.over(base.properties),
+    })
+}
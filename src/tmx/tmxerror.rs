@@ -1,5 +1,6 @@
 
 use sxd_xpath;
+use super::json;
 use std::error;
 use std::fmt;
 
@@ -8,16 +9,118 @@ use std::convert::From;
 #[derive(Debug)]
 pub enum TmxError {
     Xml(sxd_xpath::Error),
+    Json(json::JsonError),
     // the TMX is intended to be a temporary setup until we get our own GUI,
     // so the errors in TMX parsing are not rigorous.
     Structure(String),
+    /// The map could be read, but what's in it doesn't make a valid level.
+    /// These are every problem found, not just the first.
+    Invalid(Vec<Problem>),
+}
+
+/// Something wrong with a map, and where it is.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub at: Location,
+    pub msg: String,
+}
+
+/// Where in a map a `Problem` is.
+/// Whatever doesn't apply is left out.
+#[derive(Debug, Clone, Default)]
+pub struct Location {
+    /// The level group, in a map with more than one level.
+    pub group: Option<String>,
+    /// The tile layer or object group.
+    pub layer: Option<String>,
+    /// The object's id and name.
+    pub object: Option<(u32, String)>,
+    /// The position in tiles, on a tile layer.
+    pub tile: Option<(u32, u32)>,
+    /// The position in pixels, of an object.
+    pub pixel: Option<(f64, f64)>,
+    pub property: Option<String>,
+}
+
+impl Location {
+    pub fn map() -> Location {
+        Location::default()
+    }
+
+    pub fn layer(name: &str) -> Location {
+        Location { layer: Some(name.into()), ..Location::default() }
+    }
+
+    pub fn tile(layer: &str, x: u32, y: u32) -> Location {
+        Location { tile: Some((x, y)), ..Location::layer(layer) }
+    }
+
+    pub fn object(group: &str, id: u32, name: &str, x: f64, y: f64) -> Location {
+        Location {
+            object: Some((id, name.into())),
+            pixel: Some((x, y)),
+            ..Location::layer(group)
+        }
+    }
+
+    pub fn property(self, name: &str) -> Location {
+        Location { property: Some(name.into()), ..self }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut parts = Vec::new();
+        if let Some(ref g) = self.group {
+            parts.push(format!("level group {}", g));
+        }
+        if let Some(ref l) = self.layer {
+            let kind = if self.object.is_some() { "object group" } else { "layer" };
+            parts.push(format!("{} {}", kind, l));
+        }
+        if let Some((id, ref name)) = self.object {
+            if name.is_empty() {
+                parts.push(format!("object {}", id));
+            } else {
+                parts.push(format!("object {} \"{}\"", id, name));
+            }
+        }
+        if let Some((x, y)) = self.tile {
+            parts.push(format!("tile ({}, {})", x, y));
+        }
+        if let Some((x, y)) = self.pixel {
+            parts.push(format!("at ({}, {})", x, y));
+        }
+        if let Some(ref p) = self.property {
+            parts.push(format!("property {}", p));
+        }
+        if parts.is_empty() {
+            write!(f, "map")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}: {}", self.at, self.msg)
+    }
 }
 
 impl fmt::Display for TmxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
             TmxError::Xml(ref xe) => write!(f, "error processing xml: {}", xe),
+            TmxError::Json(ref je) => write!(f, "error processing json: {}", je),
             TmxError::Structure(ref se) => write!(f, "{}", se),
+            TmxError::Invalid(ref ps) => {
+                write!(f, "{} problem(s) in the map:", ps.len())?;
+                for p in ps {
+                    write!(f, "\n  {}", p)?;
+                }
+                Ok(())
+            },
         }
     }
 }
@@ -29,6 +132,7 @@ impl error::Error for TmxError {
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             TmxError::Xml(ref e) => Some(e),
+            TmxError::Json(ref e) => Some(e),
             _ => None,
         }
     }
@@ -40,6 +144,12 @@ impl From<sxd_xpath::Error> for TmxError {
     }
 }
 
+impl From<json::JsonError> for TmxError {
+    fn from(e: json::JsonError) -> TmxError {
+        TmxError::Json(e)
+    }
+}
+
 impl From<sxd_xpath::ParserError> for TmxError {
     fn from(e: sxd_xpath::ParserError) -> TmxError {
         TmxError::Xml(e.into())
@@ -0,0 +1,264 @@
+//! Reads TMX (and TSX) files into a `Map`.
+
+use std::io;
+use std::path::Path;
+use sxd_document::parser::parse;
+use sxd_document::dom::Element;
+use sxd_xpath::Context;
+use sxd_xpath::nodeset::Node;
+use why_sxd::{make_xpath_static, xpath_nodes, xpath_nodes_str, attr_u32, node_element_attr};
+
+use super::TmxError;
+use super::map::{self, Map, Layer, Group, TileLayer, ObjectGroup, Object, Property, Properties};
+use super::tileset::{self, Tileset};
+use super::template::Templates;
+
+pub fn read_map<R: io::Read>(source: &mut R, dir: &Path) -> Result<Map, TmxError> {
+    let pkg = {
+        let mut buf = String::new();
+        source.read_to_string(&mut buf).map_err(|e| format!("couldn't read TMX file: {}", e))?;
+        parse(&buf).map_err(|_| "bad TMX file")?
+    };
+    let doc = pkg.as_document();
+    let ctx = Context::new();
+
+    let map = xpath_nodes_str(&ctx, doc.root(), "/map")?
+        .document_order_first()
+        .ok_or("bad TMX file: no <map>")?;
+
+    let properties = read_properties(&ctx, map)?;
+
+    let mut tilesets = Vec::new();
+    let mut layers = Vec::new();
+    let mut templates = Templates::new(dir);
+    for node in xpath_nodes_str(&ctx, map, "*")?.document_order() {
+        match element_name(&node) {
+            "tileset" => {
+                let firstgid = attr_u32(&node, "firstgid")
+                    .ok_or("a <tileset> has no firstgid")?;
+                tilesets.push(if let Some(source) = node_element_attr(&node, "source") {
+                    tileset::read_external(&dir.join(source), firstgid)?
+                } else {
+                    read_tileset_element(&ctx, node, firstgid)?
+                });
+            },
+            _ => if let Some(layer) = read_layer(&ctx, node, &mut templates, &tilesets)? {
+                layers.push(layer);
+            },
+        }
+    }
+
+    Ok(Map { properties, tilesets, layers })
+}
+
+/// Reads any kind of layer, or nothing if `node` isn't one.
+fn read_layer(
+    context: &Context,
+    node: Node,
+    templates: &mut Templates,
+    tilesets: &[Tileset],
+) -> Result<Option<Layer>, TmxError> {
+    Ok(Some(match element_name(&node) {
+        "layer" => Layer::Tiles(read_tile_layer(context, node)?),
+        "objectgroup" => Layer::Objects(read_object_group(context, node, templates, tilesets)?),
+        "group" => {
+            let mut layers = Vec::new();
+            for child in xpath_nodes_str(context, node, "*")?.document_order() {
+                if let Some(layer) = read_layer(context, child, templates, tilesets)? {
+                    layers.push(layer);
+                }
+            }
+            Layer::Group(Group {
+                name: node_element_attr(&node, "name").unwrap_or("").to_string(),
+                properties: read_properties(context, node)?,
+                layers,
+            })
+        },
+        _ => return Ok(None),
+    }))
+}
+
+/// Reads a whole .tsx file.
+pub fn read_tileset(buf: &str, firstgid: u32) -> Result<Tileset, TmxError> {
+    let pkg = parse(buf).map_err(|_| "bad TSX file")?;
+    let doc = pkg.as_document();
+    let ctx = Context::new();
+
+    let node = xpath_nodes_str(&ctx, doc.root(), "/tileset")?
+        .document_order_first()
+        .ok_or("bad TSX file: no <tileset>")?;
+    read_tileset_element(&ctx, node, firstgid)
+}
+
+/// Reads a whole .tx file, into its tileset (if it's a tile) and its object.
+pub fn read_template(buf: &str, dir: &Path) -> Result<(Option<Tileset>, Object), TmxError> {
+    let pkg = parse(buf).map_err(|_| "bad TX file")?;
+    let doc = pkg.as_document();
+    let ctx = Context::new();
+
+    let template = xpath_nodes_str(&ctx, doc.root(), "/template")?
+        .document_order_first()
+        .ok_or("bad TX file: no <template>")?;
+
+    let set = match xpath_nodes_str(&ctx, template, "tileset")?.document_order_first() {
+        Some(node) => {
+            let firstgid = attr_u32(&node, "firstgid").ok_or("a <tileset> has no firstgid")?;
+            let source = node_element_attr(&node, "source").ok_or("a <tileset> has no source")?;
+            Some(tileset::read_external(&dir.join(source), firstgid)?)
+        },
+        None => None,
+    };
+    let obj = xpath_nodes_str(&ctx, template, "object")?
+        .document_order_first()
+        .ok_or("bad TX file: no <object>")?;
+    Ok((set, read_object(&ctx, obj, Object::default())?))
+}
+
+fn element_name<'d>(node: &Node<'d>) -> &'d str {
+    node.element().map_or("", |e| e.name().local_part())
+}
+
+fn read_properties(context: &Context, node: Node) -> Result<Properties, TmxError> {
+    let path = make_xpath_static("properties/property");
+    let mut props = Vec::new();
+    for prop in xpath_nodes(context, node, &path)?.document_order() {
+        let name = node_element_attr(&prop, "name")
+            .ok_or("a property has no name")?
+            .to_string();
+        let ty = node_element_attr(&prop, "type").unwrap_or("string").to_string();
+        // a class's members are read as if they were set by themselves
+        if ty == "class" {
+            props.extend(read_properties(context, prop)?.0);
+            continue;
+        }
+        // multi-line string properties keep their value in the element's text
+        let value = node_element_attr(&prop, "value")
+            .map(String::from)
+            .unwrap_or_else(|| prop.string_value());
+        props.push(Property { name, ty, value });
+    }
+    Ok(Properties(props))
+}
+
+fn read_tileset_element(context: &Context, node: Node, firstgid: u32) -> Result<Tileset, TmxError> {
+    let name = node_element_attr(&node, "name")
+        .ok_or("a <tileset> has no name")?
+        .to_string();
+
+    let mut tile_props = ::std::collections::BTreeMap::new();
+    for tile in xpath_nodes_str(context, node, "tile")?.document_order() {
+        let id = attr_u32(&tile, "id")
+            .ok_or_else(|| format!("a <tile> in tileset '{}' has no id", name))?;
+        let props = read_properties(context, tile)?;
+        if !props.0.is_empty() {
+            tile_props.insert(id, props);
+        }
+    }
+
+    Ok(Tileset {
+        firstgid,
+        tilecount: attr_u32(&node, "tilecount"),
+        name,
+        tile_props,
+    })
+}
+
+fn read_tile_layer(context: &Context, layer: Node) -> Result<TileLayer, TmxError> {
+    let name = node_element_attr(&layer, "name").unwrap_or("").to_string();
+    let width = attr_u32(&layer, "width").ok_or("a <layer> has no width")?;
+    let height = attr_u32(&layer, "height").ok_or("a <layer> has no height")?;
+
+    let data = xpath_nodes_str(context, layer, "data")?
+        .document_order_first()
+        .and_then(|n| n.element())
+        .ok_or("a <layer> is badly formatted")?;
+
+    let encoding = data.attribute("encoding").map(|a| a.value()).unwrap_or(
+        "xml",
+    );
+
+    let gids = match encoding {
+        "xml" => read_tiles_xml(context, data, &name, width)?,
+        "csv" => read_tiles_csv(&layer, &name, width)?,
+        "base64" => map::gids_from_base64(
+            &element_text(&data),
+            data.attribute("compression").map(|a| a.value()),
+        )?,
+        s => {
+            return Err(
+                format!("a <layer> has an unsupported encoding (encoding=\"{}\")", s).into(),
+            )
+        }
+    };
+
+    if gids.len() < (width * height) as usize {
+        Err("a <layer> has too few tiles for its dimensions".into())
+    } else if gids.len() > (width * height) as usize {
+        Err("a <layer> has too many tiles for its dimensions".into())
+    } else {
+        Ok(TileLayer { name, width, height, gids })
+    }
+}
+
+fn element_text(el: &Element) -> String {
+    el.children().iter().filter_map(|c| c.text()).map(|t| t.text()).collect()
+}
+
+fn read_tiles_csv(layer: &Node, name: &str, width: u32) -> Result<Vec<u32>, TmxError> {
+    let s = layer.string_value();
+    s.split(',').enumerate().map(|(i, chunk)| {
+        chunk.trim().parse::<u32>().map_err(|_| map::bad_tile_id(name, width, i))
+    }).collect()
+}
+
+fn read_tiles_xml(context: &Context, data: Element, name: &str, width: u32) -> Result<Vec<u32>, TmxError> {
+    xpath_nodes_str(context, data, "tile")?.document_order().iter().enumerate().map(|(i, tile)| {
+        // Tiled leaves out the gid of empty tiles
+        match node_element_attr(tile, "gid") {
+            Some(v) => v.parse::<u32>().map_err(|_| map::bad_tile_id(name, width, i)),
+            None => Ok(0),
+        }
+    }).collect()
+}
+
+fn read_object_group(
+    context: &Context,
+    group: Node,
+    templates: &mut Templates,
+    tilesets: &[Tileset],
+) -> Result<ObjectGroup, TmxError> {
+    let name = node_element_attr(&group, "name").unwrap_or("").to_string();
+    let mut objects = Vec::new();
+    for node in xpath_nodes_str(context, group, "object")?.document_order() {
+        let id = attr_u32(&node, "id").unwrap_or(0);
+        for attr in &["x", "y"] {
+            if node_element_attr(&node, attr).and_then(|v| v.parse::<f64>().ok()).is_none() {
+                return Err(format!("object {} in group {} has an invalid {} pos",
+                    id, name, attr.to_uppercase()).into());
+            }
+        }
+        let base = match node_element_attr(&node, "template") {
+            Some(source) => templates.get(source, tilesets)?,
+            None => Object::default(),
+        };
+        objects.push(read_object(context, node, base)?);
+    }
+    Ok(ObjectGroup { name, objects })
+}
+
+/// Reads an object over `base`, which is its template if it has one.
+fn read_object(context: &Context, node: Node, base: Object) -> Result<Object, TmxError> {
+    let num = |attr: &str| {
+        node_element_attr(&node, attr).and_then(|v| v.parse::<f64>().ok())
+    };
+    Ok(Object {
+        id: attr_u32(&node, "id").unwrap_or(base.id),
+        name: node_element_attr(&node, "name").map_or(base.name, String::from),
+        gid: attr_u32(&node, "gid").or(base.gid),
+        x: num("x").unwrap_or(base.x),
+        y: num("y").unwrap_or(base.y),
+        width: num("width").unwrap_or(base.width),
+        height: num("height").unwrap_or(base.height),
+        properties: read_properties(context, node)?.over(base.properties),
+    })
+}